@@ -0,0 +1,672 @@
+//! # Capping: to enforce a power budget on CPU sockets
+//!
+//! [PowerCapper] writes the RAPL power limits (`constraint_N_power_limit_uw`)
+//! of the CPU sockets powercap zones, according to a declarative [CappingPolicy],
+//! periodically, from a thread of its own (see [PowerCapper::apply_periodically]),
+//! whatever the pace of the exporter. Original limits are written back by
+//! [PowerCapper::rollback] when scaphandre exits, panics included (see
+//! [CappingGuard] and [PowerCapper::rollback_on_panic]), and on SIGINT and
+//! SIGTERM (see [block_exit_signals] and [PowerCapper::rollback_on_exit_signals]).
+use crate::sensors::powercap_rapl::{discover_rapl_zones, PowercapZone, RaplZoneKind};
+use crate::sensors::{Sensor, Topology};
+use chrono::{Local, NaiveTime};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex, TryLockError};
+use std::time::Duration;
+use std::{fs, mem, panic, process, ptr, thread};
+
+/// Default path of the powercap folder whose zones are capped.
+pub const DEFAULT_POWERCAP_PATH: &str = "/sys/class/powercap";
+/// Default number of seconds between two applications of the policy.
+pub const DEFAULT_CAPPING_INTERVAL_SECS: &str = "5";
+
+/// Power budget to enforce, as a list of rules. The cap of each socket is the
+/// lowest watts value of the rules applying to it. Sockets no rule applies to
+/// get their original power limit back.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CappingPolicy {
+    /// Name of the RAPL constraint to write (long_term, short_term...)
+    #[serde(default = "default_constraint")]
+    pub constraint: String,
+    /// Lowest cap that can be computed by a target_host rule, per socket, in watts
+    #[serde(default = "default_min_socket_watts")]
+    pub min_socket_watts: f64,
+    pub rules: Vec<CappingRule>,
+}
+
+fn default_constraint() -> String {
+    String::from("long_term")
+}
+
+fn default_min_socket_watts() -> f64 {
+    10.0
+}
+
+/// Rule of a CappingPolicy, giving a cap in watts per socket. Rules apply to
+/// all sockets if *sockets* is not set.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CappingRule {
+    /// Fixed cap
+    Static {
+        watts: f64,
+        sockets: Option<Vec<u16>>,
+    },
+    /// Fixed cap applied between *from* and *to*, as HH:MM local times
+    Schedule {
+        from: String,
+        to: String,
+        watts: f64,
+        sockets: Option<Vec<u16>>,
+    },
+    /// Caps computed so that the host power consumption stays below *watts*:
+    /// the power consumed outside of the sockets is substracted from the
+    /// target and the rest is evenly split between the sockets
+    TargetHost { watts: f64 },
+}
+
+impl CappingPolicy {
+    /// Reads a policy from the JSON file at *path*.
+    pub fn from_file(path: &str) -> Result<CappingPolicy, Box<dyn Error>> {
+        let policy: CappingPolicy = serde_json::from_str(&fs::read_to_string(path)?)?;
+        for rule in &policy.rules {
+            if let CappingRule::Schedule { from, to, .. } = rule {
+                parse_time(from)?;
+                parse_time(to)?;
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Returns the cap of each socket of *sockets_ids*, in microwatts, at the
+    /// local time *now*. *host_uw* and *sockets_uw* are the last host and
+    /// sockets power measurements, if any.
+    pub fn caps_uw(
+        &self,
+        sockets_ids: &[u16],
+        now: NaiveTime,
+        host_uw: Option<u64>,
+        sockets_uw: &BTreeMap<u16, u64>,
+    ) -> BTreeMap<u16, u64> {
+        let mut caps: BTreeMap<u16, u64> = BTreeMap::new();
+        let mut apply = |socket_id: u16, watts: f64| {
+            let cap = (watts.max(0.0) * 1_000_000.0) as u64;
+            let entry = caps.entry(socket_id).or_insert(cap);
+            *entry = (*entry).min(cap);
+        };
+        for rule in &self.rules {
+            match rule {
+                CappingRule::Static { watts, sockets } => {
+                    for id in selected_sockets(sockets_ids, sockets) {
+                        apply(id, *watts);
+                    }
+                }
+                CappingRule::Schedule {
+                    from,
+                    to,
+                    watts,
+                    sockets,
+                } => {
+                    if let (Ok(from), Ok(to)) = (parse_time(from), parse_time(to)) {
+                        let active = if from <= to {
+                            from <= now && now < to
+                        } else {
+                            // schedule going past midnight
+                            now >= from || now < to
+                        };
+                        if active {
+                            for id in selected_sockets(sockets_ids, sockets) {
+                                apply(id, *watts);
+                            }
+                        }
+                    }
+                }
+                CappingRule::TargetHost { watts } => {
+                    if let Some(host_uw) = host_uw {
+                        if sockets_ids.is_empty() {
+                            continue;
+                        }
+                        let sockets_total_uw: u64 = sockets_uw.values().sum();
+                        let others_watts =
+                            host_uw.saturating_sub(sockets_total_uw) as f64 / 1_000_000.0;
+                        let per_socket = (watts - others_watts) / sockets_ids.len() as f64;
+                        for id in sockets_ids {
+                            apply(*id, per_socket.max(self.min_socket_watts));
+                        }
+                    }
+                }
+            }
+        }
+        caps
+    }
+}
+
+/// Parses a HH:MM local time.
+fn parse_time(time: &str) -> Result<NaiveTime, Box<dyn Error>> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|e| From::from(format!("Invalid time {} (expected HH:MM): {}", time, e)))
+}
+
+/// Returns the ids of *sockets_ids* selected by *sockets*, all of them if None.
+fn selected_sockets(sockets_ids: &[u16], sockets: &Option<Vec<u16>>) -> Vec<u16> {
+    match sockets {
+        Some(selected) => sockets_ids
+            .iter()
+            .filter(|id| selected.contains(id))
+            .cloned()
+            .collect(),
+        None => sockets_ids.to_vec(),
+    }
+}
+
+/// Powercap zone of a CPU socket, with the values found before capping it.
+#[derive(Debug, Clone)]
+struct CappedZone {
+    zone: PowercapZone,
+    /// N in constraint_N_power_limit_uw
+    constraint_id: u16,
+    original_limit_uw: u64,
+    original_enabled: Option<bool>,
+    /// Cap currently requested, in microwatts
+    cap_uw: Option<u64>,
+}
+
+#[derive(Debug)]
+struct CapperState {
+    zones: BTreeMap<u16, CappedZone>,
+    /// Set once the original limits are written back, for the thread applying
+    /// the policy not to cap the sockets again while scaphandre exits
+    rolled_back: bool,
+}
+
+/// Writes the power limits of the CPU sockets powercap zones found in
+/// *powercap_path* (package-N zones, N being the socket id) according
+/// to a CappingPolicy. In dry run mode, the caps are computed and logged,
+/// but nothing is written.
+#[derive(Debug, Clone)]
+pub struct PowerCapper {
+    pub policy: CappingPolicy,
+    pub powercap_path: String,
+    pub dry_run: bool,
+    state: Arc<Mutex<CapperState>>,
+}
+
+impl PowerCapper {
+    /// Instantiates PowerCapper, reading the original power limits of the
+    /// sockets zones, and returns the instance.
+    pub fn new(
+        policy: CappingPolicy,
+        powercap_path: &str,
+        dry_run: bool,
+    ) -> Result<PowerCapper, Box<dyn Error>> {
+        let mut zones = BTreeMap::new();
+        for rapl_zone in discover_rapl_zones(powercap_path)? {
+            if let RaplZoneKind::Socket(socket_id) = rapl_zone.kind {
+                let zone = PowercapZone::new(&rapl_zone.path);
+                let constraint = zone
+                    .constraints
+                    .iter()
+                    .find(|c| c.name == policy.constraint);
+                match constraint {
+                    Some(c) => {
+                        let original_limit_uw = c.power_limit_uw.ok_or_else(|| {
+                            format!("Can't read the power limit of {}", zone.path)
+                        })?;
+                        zones.insert(
+                            socket_id,
+                            CappedZone {
+                                constraint_id: c.id,
+                                original_limit_uw,
+                                original_enabled: zone.enabled,
+                                cap_uw: None,
+                                zone,
+                            },
+                        );
+                    }
+                    None => warn!(
+                        "No {} constraint in {}, socket {} won't be capped.",
+                        policy.constraint, zone.path, socket_id
+                    ),
+                }
+            }
+        }
+        if zones.is_empty() {
+            return Err(From::from(format!(
+                "No socket zone with a {} constraint found in {}",
+                policy.constraint, powercap_path
+            )));
+        }
+        Ok(PowerCapper {
+            policy,
+            powercap_path: String::from(powercap_path),
+            dry_run,
+            state: Arc::new(Mutex::new(CapperState {
+                zones,
+                rolled_back: false,
+            })),
+        })
+    }
+
+    /// Computes the caps from the last measurements of *topology* and writes them.
+    pub fn apply(&self, topology: &Topology) {
+        let host_uw = topology
+            .get_records_diff_power_microwatts()
+            .and_then(|r| r.value.parse::<u64>().ok());
+        let mut sockets_uw = BTreeMap::new();
+        for socket in topology.get_sockets_passive() {
+            if let Some(record) = socket.get_records_diff_power_microwatts() {
+                if let Ok(value) = record.value.parse::<u64>() {
+                    sockets_uw.insert(socket.id, value);
+                }
+            }
+        }
+        self.apply_at(Local::now().time(), host_uw, &sockets_uw);
+    }
+
+    /// Spawns a thread applying the policy every *interval*, from the power
+    /// measured on *topology*, which is refreshed by the thread only. Caps
+    /// are then applied on time even if the exporter refreshes its own
+    /// topology rarely (the prometheus exporter only does on scrapes).
+    pub fn apply_periodically(
+        &self,
+        mut topology: Topology,
+        interval: Duration,
+    ) -> thread::JoinHandle<()> {
+        let capper = self.clone();
+        thread::spawn(move || loop {
+            topology.refresh_records();
+            capper.apply(&topology);
+            thread::sleep(interval);
+        })
+    }
+
+    /// Computes the caps at the local time *now* from the given power
+    /// measurements, in microwatts, and writes them. Does nothing once the
+    /// original limits are written back.
+    pub fn apply_at(&self, now: NaiveTime, host_uw: Option<u64>, sockets_uw: &BTreeMap<u16, u64>) {
+        let mut state = self.state.lock().unwrap();
+        if state.rolled_back {
+            return;
+        }
+        let sockets_ids: Vec<u16> = state.zones.keys().cloned().collect();
+        let caps = self.policy.caps_uw(&sockets_ids, now, host_uw, sockets_uw);
+        for (socket_id, capped) in state.zones.iter_mut() {
+            let cap_uw = caps.get(socket_id).cloned();
+            if cap_uw == capped.cap_uw {
+                continue;
+            }
+            let result = match cap_uw {
+                Some(cap_uw) => {
+                    // a cap can't be higher than the maximum allowed by the firmware
+                    let max_uw =
+                        capped.zone.constraints[capped.constraint_id as usize].max_power_uw;
+                    let cap_uw = max_uw.map_or(cap_uw, |max| cap_uw.min(max));
+                    info!("Capping socket {} to {} uW.", socket_id, cap_uw);
+                    self.write_zone(capped, cap_uw, Some(true))
+                }
+                None => {
+                    info!("Removing cap of socket {}.", socket_id);
+                    self.write_zone(capped, capped.original_limit_uw, capped.original_enabled)
+                }
+            };
+            match result {
+                Ok(()) => capped.cap_uw = cap_uw,
+                Err(e) => warn!("Couldn't write power limit of socket {}: {}", socket_id, e),
+            }
+        }
+    }
+
+    /// Writes the power limit and enabled state of a zone (logs them in dry run mode).
+    fn write_zone(
+        &self,
+        capped: &CappedZone,
+        limit_uw: u64,
+        enabled: Option<bool>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut writes = vec![(
+            format!(
+                "{}/constraint_{}_power_limit_uw",
+                capped.zone.path, capped.constraint_id
+            ),
+            limit_uw.to_string(),
+        )];
+        if let Some(enabled) = enabled {
+            writes.push((
+                format!("{}/enabled", capped.zone.path),
+                (enabled as u8).to_string(),
+            ));
+        }
+        for (path, value) in writes {
+            if self.dry_run {
+                info!("Dry run: would write {} to {}", value, path);
+            } else {
+                fs::write(&path, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the cap currently requested for each socket, in microwatts.
+    pub fn get_caps_uw(&self) -> BTreeMap<u16, u64> {
+        let state = self.state.lock().unwrap();
+        state
+            .zones
+            .iter()
+            .filter_map(|(id, capped)| capped.cap_uw.map(|cap| (*id, cap)))
+            .collect()
+    }
+
+    /// Writes back the original power limits of the capped sockets. The policy
+    /// isn't applied anymore afterwards.
+    pub fn rollback(&self) {
+        // a panic while caps were written must not prevent restoring them
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.rollback_zones(&mut state);
+    }
+
+    fn rollback_zones(&self, state: &mut CapperState) {
+        state.rolled_back = true;
+        for (socket_id, capped) in state.zones.iter_mut() {
+            if capped.cap_uw.is_some() {
+                info!("Restoring original power limit of socket {}.", socket_id);
+                match self.write_zone(capped, capped.original_limit_uw, capped.original_enabled) {
+                    Ok(()) => capped.cap_uw = None,
+                    Err(e) => warn!(
+                        "Couldn't restore power limit of socket {}: {}",
+                        socket_id, e
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Makes SIGINT and SIGTERM roll the power limits back before exiting.
+    /// The signals are waited for synchronously by a dedicated thread, so
+    /// that the rollback doesn't run in a signal handler: [block_exit_signals]
+    /// has to be called first, before any other thread exists.
+    pub fn rollback_on_exit_signals(&self) {
+        let capper = self.clone();
+        let set = block_exit_signals();
+        thread::spawn(move || {
+            let mut signal = 0;
+            unsafe {
+                libc::sigwait(&set, &mut signal);
+            }
+            capper.rollback();
+            process::exit(128 + signal);
+        });
+    }
+
+    /// Makes any panic, in any thread, roll the power limits back before the
+    /// previous panic hook runs.
+    pub fn rollback_on_panic(&self) {
+        let capper = self.clone();
+        let previous_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            // the panicking thread may hold the state, waiting for it would
+            // deadlock: the CappingGuard rolls back once it is released
+            match capper.state.try_lock() {
+                Ok(mut state) => capper.rollback_zones(&mut state),
+                Err(TryLockError::Poisoned(e)) => capper.rollback_zones(&mut e.into_inner()),
+                Err(TryLockError::WouldBlock) => {}
+            }
+            previous_hook(info);
+        }));
+    }
+}
+
+/// Blocks SIGINT and SIGTERM in the calling thread and returns their set.
+/// Threads inherit the signal mask of the thread spawning them, but a thread
+/// spawned before keeps the default disposition, and the kernel may deliver
+/// the signals to it, which kills scaphandre without rolling the power limits
+/// back. This has then to be called by the main thread before any other
+/// thread exists (sensors, exporters and the taskstats listener spawn some),
+/// for the signals to be left to [PowerCapper::rollback_on_exit_signals].
+pub fn block_exit_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+        set
+    }
+}
+
+/// Owns a PowerCapper and rolls its power limits back when dropped, so that
+/// they are restored when the exporter returns or the thread owning the guard
+/// unwinds from a panic.
+pub struct CappingGuard {
+    capper: PowerCapper,
+}
+
+impl CappingGuard {
+    /// Instantiates and returns an instance of CappingGuard owning *capper*.
+    pub fn new(capper: PowerCapper) -> CappingGuard {
+        CappingGuard { capper }
+    }
+}
+
+impl Drop for CappingGuard {
+    fn drop(&mut self) {
+        self.capper.rollback();
+    }
+}
+
+/// Sensor wrapper attaching a PowerCapper to the topology of the wrapped
+/// sensor, so that the caps it applies are reported by exporters.
+pub struct CappingSensor {
+    sensor: Box<dyn Sensor>,
+    capper: PowerCapper,
+}
+
+impl CappingSensor {
+    /// Instantiates and returns an instance of CappingSensor, applying
+    /// the policy of *capper* on the topology of *sensor*.
+    pub fn new(sensor: Box<dyn Sensor>, capper: PowerCapper) -> CappingSensor {
+        CappingSensor { sensor, capper }
+    }
+}
+
+impl Sensor for CappingSensor {
+    /// Creates a Topology instance from the wrapped sensor, with the power capper attached.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut topo = self.sensor.generate_topology()?;
+        topo.power_capper = Some(self.capper.clone());
+        Ok(topo)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let mut topology = *self.sensor.get_topology();
+        if let Some(topo) = topology.as_mut() {
+            topo.power_capper = Some(self.capper.clone());
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::test_utils::TempDir;
+
+    fn fake_powercap(root: &std::path::Path) {
+        // two sockets and a psys zone, which isn't capped
+        for (number, name) in &[(0, "package-0"), (1, "package-1"), (2, "psys")] {
+            let zone = root.join(format!("intel-rapl:{}", number));
+            fs::create_dir_all(&zone).unwrap();
+            fs::write(zone.join("name"), name).unwrap();
+            fs::write(zone.join("energy_uj"), "0").unwrap();
+            fs::write(zone.join("enabled"), "0").unwrap();
+            fs::write(zone.join("constraint_0_name"), "long_term").unwrap();
+            fs::write(zone.join("constraint_0_power_limit_uw"), "150000000").unwrap();
+            fs::write(zone.join("constraint_1_name"), "short_term").unwrap();
+            fs::write(zone.join("constraint_1_power_limit_uw"), "180000000").unwrap();
+        }
+    }
+
+    fn read(root: &std::path::Path, file: &str) -> String {
+        fs::read_to_string(root.join(file)).unwrap()
+    }
+
+    #[test]
+    fn policy_combines_rules() {
+        let policy: CappingPolicy = serde_json::from_str(
+            r#"{"rules": [
+                {"type": "static", "watts": 100, "sockets": [1]},
+                {"type": "schedule", "from": "22:00", "to": "06:00", "watts": 60},
+                {"type": "target_host", "watts": 250}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(policy.constraint, "long_term");
+        let noon = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let night = NaiveTime::from_hms_opt(23, 0, 0).unwrap();
+        let no_power = BTreeMap::new();
+        let caps = policy.caps_uw(&[0, 1], noon, None, &no_power);
+        assert_eq!(caps, vec![(1, 100_000_000)].into_iter().collect());
+        let caps = policy.caps_uw(&[0, 1], night, None, &no_power);
+        assert_eq!(
+            caps,
+            vec![(0, 60_000_000), (1, 60_000_000)].into_iter().collect()
+        );
+        // 300W for the host, 200W for the sockets: 100W are consumed by the
+        // rest of the host, 150W are left to the sockets
+        let sockets_uw = vec![(0, 100_000_000), (1, 100_000_000)]
+            .into_iter()
+            .collect();
+        let caps = policy.caps_uw(&[0, 1], noon, Some(300_000_000), &sockets_uw);
+        assert_eq!(
+            caps,
+            vec![(0, 75_000_000), (1, 75_000_000)].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn capper_writes_and_rolls_back_limits() {
+        let root = TempDir::new("capping");
+        fake_powercap(&root);
+        let policy: CappingPolicy =
+            serde_json::from_str(r#"{"rules": [{"type": "static", "watts": 90, "sockets": [0]}]}"#)
+                .unwrap();
+        let now = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+
+        let capper = PowerCapper::new(policy.clone(), root.to_str().unwrap(), true).unwrap();
+        capper.apply_at(now, None, &BTreeMap::new());
+        assert_eq!(
+            read(&root, "intel-rapl:0/constraint_0_power_limit_uw"),
+            "150000000"
+        );
+        assert_eq!(capper.get_caps_uw().get(&0), Some(&90_000_000));
+
+        let capper = PowerCapper::new(policy, root.to_str().unwrap(), false).unwrap();
+        capper.apply_at(now, None, &BTreeMap::new());
+        assert_eq!(
+            read(&root, "intel-rapl:0/constraint_0_power_limit_uw"),
+            "90000000"
+        );
+        assert_eq!(read(&root, "intel-rapl:0/enabled"), "1");
+        assert_eq!(
+            read(&root, "intel-rapl:0/constraint_1_power_limit_uw"),
+            "180000000"
+        );
+        assert_eq!(
+            read(&root, "intel-rapl:1/constraint_0_power_limit_uw"),
+            "150000000"
+        );
+        capper.rollback();
+        assert_eq!(
+            read(&root, "intel-rapl:0/constraint_0_power_limit_uw"),
+            "150000000"
+        );
+        assert_eq!(read(&root, "intel-rapl:0/enabled"), "0");
+        assert!(capper.get_caps_uw().is_empty());
+        // the sockets are not capped again once rolled back
+        capper.apply_at(now, None, &BTreeMap::new());
+        assert!(capper.get_caps_uw().is_empty());
+    }
+
+    #[test]
+    fn capper_applies_policy_on_its_own() {
+        let root = TempDir::new("capping-thread");
+        fake_powercap(&root);
+        let policy: CappingPolicy =
+            serde_json::from_str(r#"{"rules": [{"type": "static", "watts": 90}]}"#).unwrap();
+        let capper = PowerCapper::new(policy, root.to_str().unwrap(), false).unwrap();
+        // nothing refreshes this topology but the capper thread
+        capper.apply_periodically(Topology::new(), Duration::from_millis(10));
+        for _ in 0..500 {
+            if capper.get_caps_uw().len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            read(&root, "intel-rapl:1/constraint_0_power_limit_uw"),
+            "90000000"
+        );
+        capper.rollback();
+        assert_eq!(
+            read(&root, "intel-rapl:1/constraint_0_power_limit_uw"),
+            "150000000"
+        );
+    }
+
+    #[test]
+    fn guard_rolls_back_limits_on_panic() {
+        let root = TempDir::new("capping-guard");
+        fake_powercap(&root);
+        let policy: CappingPolicy =
+            serde_json::from_str(r#"{"rules": [{"type": "static", "watts": 90}]}"#).unwrap();
+        let capper = PowerCapper::new(policy, root.to_str().unwrap(), false).unwrap();
+        let result = panic::catch_unwind(|| {
+            let _guard = CappingGuard::new(capper.clone());
+            capper.apply_at(
+                NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+                None,
+                &BTreeMap::new(),
+            );
+            assert_eq!(
+                read(&root, "intel-rapl:1/constraint_0_power_limit_uw"),
+                "90000000"
+            );
+            assert_eq!(
+                capper.get_caps_uw().keys().cloned().collect::<Vec<u16>>(),
+                vec![0, 1]
+            );
+            assert_eq!(
+                read(&root, "intel-rapl:2/constraint_0_power_limit_uw"),
+                "150000000"
+            );
+            panic!("exporter failure");
+        });
+        assert!(result.is_err());
+        for socket in 0..2 {
+            assert_eq!(
+                read(
+                    &root,
+                    &format!("intel-rapl:{}/constraint_0_power_limit_uw", socket)
+                ),
+                "150000000"
+            );
+        }
+        assert!(capper.get_caps_uw().is_empty());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -9,7 +9,11 @@ pub mod riemann;
 pub mod stdout;
 pub mod utils;
 pub mod warpten;
-use crate::sensors::{utils::current_system_time_since_epoch, RecordGenerator, Topology};
+use crate::sensors::{
+    powercap_rapl::PowercapZone,
+    utils::{current_system_time_since_epoch, ProcessTracker},
+    Record, RecordGenerator, Topology,
+};
 use chrono::Utc;
 use clap::ArgMatches;
 use docker_sync::{container::Container, Docker};
@@ -408,6 +412,38 @@ impl MetricGenerator {
                     metric_value: MetricValueType::Text(power.value),
                 });
             }
+
+            if let Some(usage) = self.topology.get_usage() {
+                if let Some(idle_power) = usage.idle_power_microwatts {
+                    let timestamp = record.timestamp;
+                    let dynamic_power = usage.power_microwatts - idle_power;
+                    for (name, value, description) in [
+                        (
+                            "scaph_host_idle_power_microwatts",
+                            idle_power,
+                            "Estimated idle (static) power of the host, in microwatts",
+                        ),
+                        (
+                            "scaph_host_dynamic_power_microwatts",
+                            dynamic_power,
+                            "Dynamic power of the host (power measured minus idle power), in microwatts",
+                        ),
+                    ] {
+                        self.data.push(Metric {
+                            name: String::from(name),
+                            metric_type: String::from("gauge"),
+                            ttl: 60.0,
+                            timestamp,
+                            hostname: self.hostname.clone(),
+                            state: String::from("ok"),
+                            tags: vec!["scaphandre".to_string()],
+                            attributes: HashMap::new(),
+                            description: String::from(description),
+                            metric_value: MetricValueType::Text((value as u64).to_string()),
+                        });
+                    }
+                }
+            }
         }
     }
 
@@ -505,6 +541,299 @@ impl MetricGenerator {
         }
     }
 
+    /// Generate metrics for the power limits (RAPL constraints) of sockets and domains.
+    fn gen_power_limit_metrics(&mut self) {
+        let mut metrics = vec![];
+        for socket in self.topology.get_sockets_passive() {
+            let mut attributes = HashMap::new();
+            attributes.insert("socket_id".to_string(), socket.id.to_string());
+            if let Some(zone) = &socket.powercap_zone {
+                metrics.append(&mut self.powercap_zone_metrics("socket", zone, &attributes));
+            }
+            for domain in socket.get_domains_passive() {
+                if let Some(zone) = &domain.powercap_zone {
+                    let mut attributes = attributes.clone();
+                    attributes.insert("domain_name".to_string(), domain.name.clone());
+                    attributes.insert("domain_id".to_string(), domain.id.to_string());
+                    metrics.append(&mut self.powercap_zone_metrics("domain", zone, &attributes));
+                }
+            }
+        }
+        self.data.append(&mut metrics);
+    }
+
+    /// Returns the metrics describing the power limits of *zone*, named after *kind*
+    /// (socket or domain) and labeled with *attributes*.
+    fn powercap_zone_metrics(
+        &self,
+        kind: &str,
+        zone: &PowercapZone,
+        attributes: &HashMap<String, String>,
+    ) -> Vec<Metric> {
+        let timestamp = current_system_time_since_epoch();
+        let metric = |name: String, description: String, attributes, value| Metric {
+            name,
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp,
+            hostname: self.hostname.clone(),
+            state: String::from("ok"),
+            tags: vec!["scaphandre".to_string()],
+            attributes,
+            description,
+            metric_value: MetricValueType::IntUnsigned(value),
+        };
+        let mut metrics = vec![];
+        if let Some(enabled) = zone.enabled {
+            metrics.push(metric(
+                format!("scaph_{}_power_limits_enabled", kind),
+                format!(
+                    "1 if the power limits of the {} are enforced, 0 otherwise.",
+                    kind
+                ),
+                attributes.clone(),
+                enabled as u64,
+            ));
+        }
+        for constraint in &zone.constraints {
+            let mut attributes = attributes.clone();
+            attributes.insert("constraint_name".to_string(), constraint.name.clone());
+            attributes.insert("constraint_id".to_string(), constraint.id.to_string());
+            if let Some(value) = constraint.power_limit_uw {
+                metrics.push(metric(
+                    format!("scaph_{}_power_limit_microwatts", kind),
+                    format!(
+                        "Power limit (RAPL constraint) of the {}, in microwatts.",
+                        kind
+                    ),
+                    attributes.clone(),
+                    value,
+                ));
+            }
+            if let Some(value) = constraint.time_window_us {
+                metrics.push(metric(
+                    format!("scaph_{}_power_limit_time_window_microseconds", kind),
+                    format!(
+                        "Time window the power of the {} is averaged over for a power limit, in microseconds.",
+                        kind
+                    ),
+                    attributes.clone(),
+                    value,
+                ));
+            }
+            if let Some(value) = constraint.max_power_uw {
+                metrics.push(metric(
+                    format!("scaph_{}_power_limit_max_microwatts", kind),
+                    format!(
+                        "Maximum value allowed for a power limit of the {}, in microwatts.",
+                        kind
+                    ),
+                    attributes,
+                    value,
+                ));
+            }
+        }
+        metrics
+    }
+
+    /// Generate metrics for the power caps applied to the CPU sockets, if power capping is enabled.
+    fn gen_power_cap_metrics(&mut self) {
+        if let Some(capper) = &self.topology.power_capper {
+            let timestamp = current_system_time_since_epoch();
+            for (socket_id, cap_uw) in capper.get_caps_uw() {
+                let mut attributes = HashMap::new();
+                attributes.insert("socket_id".to_string(), socket_id.to_string());
+                attributes.insert(
+                    "constraint_name".to_string(),
+                    capper.policy.constraint.clone(),
+                );
+                attributes.insert("dry_run".to_string(), capper.dry_run.to_string());
+                self.data.push(Metric {
+                    name: String::from("scaph_socket_power_cap_microwatts"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Power cap applied by scaphandre to the socket, in microwatts.",
+                    ),
+                    metric_value: MetricValueType::IntUnsigned(cap_uw),
+                });
+            }
+        }
+    }
+
+    /// Generate frequency and idle states (C-states) metrics for each CPU core.
+    fn gen_core_metrics(&mut self) {
+        let mut metrics = vec![];
+        for socket in self.topology.get_sockets_passive() {
+            for core in socket.get_cores_passive() {
+                let state = match core.get_last_state() {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let mut attributes = HashMap::new();
+                attributes.insert("socket_id".to_string(), socket.id.to_string());
+                attributes.insert("die_id".to_string(), core.die_id.to_string());
+                attributes.insert("core_id".to_string(), core.id.to_string());
+                let metric =
+                    |name: &str, metric_type: &str, description: &str, attributes, value| Metric {
+                        name: String::from(name),
+                        metric_type: String::from(metric_type),
+                        ttl: 60.0,
+                        timestamp: state.timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes,
+                        description: String::from(description),
+                        metric_value: value,
+                    };
+
+                if let Some(frequency) = state.frequency_khz {
+                    let mut attributes = attributes.clone();
+                    if let Some(governor) = &state.governor {
+                        attributes.insert("governor".to_string(), governor.clone());
+                    }
+                    metrics.push(metric(
+                        "scaph_core_frequency_khz",
+                        "gauge",
+                        "Current frequency of the CPU core, in kHz.",
+                        attributes,
+                        MetricValueType::IntUnsigned(frequency),
+                    ));
+                }
+                for idle_state in &state.idle_states {
+                    let mut attributes = attributes.clone();
+                    attributes.insert("state_name".to_string(), idle_state.name.clone());
+                    metrics.push(metric(
+                        "scaph_core_idle_state_time_microseconds",
+                        "counter",
+                        "Total time spent by the CPU core in this idle state (C-state), in microseconds.",
+                        attributes.clone(),
+                        MetricValueType::IntUnsigned(idle_state.time_us),
+                    ));
+                    metrics.push(metric(
+                        "scaph_core_idle_state_usage",
+                        "counter",
+                        "Number of times the CPU core entered this idle state (C-state).",
+                        attributes,
+                        MetricValueType::IntUnsigned(idle_state.usage),
+                    ));
+                }
+                if let Some(residency) = core.get_idle_states_residency() {
+                    for (name, ratio) in residency {
+                        let mut attributes = attributes.clone();
+                        attributes.insert("state_name".to_string(), name);
+                        metrics.push(metric(
+                            "scaph_core_idle_state_residency_ratio",
+                            "gauge",
+                            "Part of the time (between 0 and 1) the CPU core spent in this idle state (C-state) since the previous measurement.",
+                            attributes,
+                            MetricValueType::FloatDouble(ratio),
+                        ));
+                    }
+                }
+            }
+        }
+        self.data.append(&mut metrics);
+    }
+
+    /// Generate metrics for components that are not part of a CPU socket.
+    fn gen_component_metrics(&mut self) {
+        for component in self.topology.get_components_passive() {
+            let records = component.get_records_passive();
+            if !records.is_empty() {
+                let metric = records.last().unwrap();
+
+                let mut attributes = HashMap::new();
+                attributes.insert("component_name".to_string(), component.name.clone());
+                attributes.insert("component_id".to_string(), component.id.to_string());
+
+                self.data.push(Metric {
+                    name: String::from("scaph_component_energy_microjoules"),
+                    metric_type: String::from("counter"),
+                    ttl: 60.0,
+                    timestamp: metric.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: attributes.clone(),
+                    description: String::from(
+                        "Energy measurement relative to a component of the host, in microjoules.",
+                    ),
+                    metric_value: MetricValueType::Text(metric.value.clone()),
+                });
+
+                if let Some(power) = component.get_records_diff_power_microwatts() {
+                    self.data.push(Metric {
+                        name: String::from("scaph_component_power_microwatts"),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp: power.timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes,
+                        description: String::from(
+                            "Power measurement relative to a component of the host, in microwatts",
+                        ),
+                        metric_value: MetricValueType::Text(power.value),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Generate metrics for platform (psys) power domains, measuring the whole SoC/platform.
+    fn gen_platform_metrics(&mut self) {
+        for platform in self.topology.get_platforms_passive() {
+            let records = platform.get_records_passive();
+            if !records.is_empty() {
+                let metric = records.last().unwrap();
+
+                let mut attributes = HashMap::new();
+                attributes.insert("platform_name".to_string(), platform.name.clone());
+                attributes.insert("platform_id".to_string(), platform.id.to_string());
+
+                self.data.push(Metric {
+                    name: String::from("scaph_platform_energy_microjoules"),
+                    metric_type: String::from("counter"),
+                    ttl: 60.0,
+                    timestamp: metric.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: attributes.clone(),
+                    description: String::from(
+                        "Energy measurement of the whole platform (RAPL psys domain), in microjoules.",
+                    ),
+                    metric_value: MetricValueType::Text(metric.value.clone()),
+                });
+
+                if let Some(power) = platform.get_records_diff_power_microwatts() {
+                    self.data.push(Metric {
+                        name: String::from("scaph_platform_power_microwatts"),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp: power.timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes,
+                        description: String::from(
+                            "Power measurement of the whole platform (RAPL psys domain), in microwatts",
+                        ),
+                        metric_value: MetricValueType::Text(power.value),
+                    });
+                }
+            }
+        }
+    }
+
     /// Generate system metrics.
     fn gen_system_metrics(&mut self) {
         let default_timestamp = current_system_time_since_epoch();
@@ -569,6 +898,26 @@ impl MetricGenerator {
         }
     }
 
+    /// Generate cgroup metrics, when power is attributed to cgroups.
+    fn gen_cgroup_metrics(&mut self) {
+        for (path, power) in self.topology.get_cgroups_power_consumption_microwatts() {
+            let mut attributes = HashMap::new();
+            attributes.insert("cgroup".to_string(), path);
+            self.data.push(Metric {
+                name: String::from("scaph_cgroup_power_consumption_microwatts"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: power.timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from("Power consumption due to the processes of the cgroup and its descendants, in microwatts"),
+                metric_value: MetricValueType::Text(power.value),
+            });
+        }
+    }
+
     /// If *self.watch_docker* is true and *self.docker_client* is Some
     /// gets the list of docker containers running on the machine, thanks
     /// to *self.docker_client*. Stores the resulting vector as *self.containers*.
@@ -662,6 +1011,7 @@ impl MetricGenerator {
             }
         }
 
+        let processes_power = self.topology.get_processes_power_consumption_microwatts();
         for pid in self.topology.proc_tracker.get_alive_pids() {
             let exe = self.topology.proc_tracker.get_process_name(pid);
             let cmdline = self.topology.proc_tracker.get_process_cmdline(pid);
@@ -701,22 +1051,180 @@ impl MetricGenerator {
                 }
             }
 
+            let power = match processes_power.get(&pid) {
+                Some(power) => power,
+                None => continue,
+            };
+
+            if self.topology.track_threads {
+                for (thread, power) in self
+                    .topology
+                    .get_threads_power_consumption_microwatts(pid, power)
+                {
+                    let mut thread_attributes = HashMap::new();
+                    thread_attributes.insert("pid".to_string(), pid.to_string());
+                    thread_attributes.insert("exe".to_string(), exe.clone());
+                    thread_attributes.insert("tid".to_string(), thread.tid.to_string());
+                    thread_attributes.insert("thread_name".to_string(), thread.comm);
+                    self.data.push(Metric {
+                        name: String::from("scaph_thread_power_consumption_microwatts"),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp: power.timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes: thread_attributes,
+                        description: String::from(
+                            "Power consumption due to a thread of the process, in microwatts",
+                        ),
+                        metric_value: MetricValueType::Text(power.value),
+                    });
+                }
+            }
+
             let metric_name = String::from("scaph_process_power_consumption_microwatts");
-            if let Some(power) = self.topology.get_process_power_consumption_microwatts(pid) {
-                self.data.push(Metric {
-                    name: metric_name,
-                    metric_type: String::from("gauge"),
-                    ttl: 60.0,
-                    timestamp: power.timestamp,
-                    hostname: self.hostname.clone(),
-                    state: String::from("ok"),
-                    tags: vec!["scaphandre".to_string()],
-                    attributes,
-                    description: String::from("Power consumption due to the process, measured on at the topology level, in microwatts"),
-                    metric_value: MetricValueType::Text(power.value),
-                });
+            self.data.push(Metric {
+                name: metric_name,
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: power.timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from("Power consumption due to the process, measured on at the topology level, in microwatts"),
+                metric_value: MetricValueType::Text(power.value.clone()),
+            });
+        }
+
+        self.gen_process_tree_metrics(&processes_power);
+        self.gen_systemd_metrics(&processes_power);
+        self.gen_user_metrics(&processes_power);
+    }
+
+    /// Generate the power metrics of users, from the power of each process in
+    /// *processes_power*, when processes are mapped to users.
+    fn gen_user_metrics(&mut self, processes_power: &HashMap<i32, Record>) {
+        for (uid, power) in self
+            .topology
+            .get_users_power_consumption_microwatts(processes_power)
+        {
+            let mut attributes = HashMap::new();
+            attributes.insert("uid".to_string(), uid.to_string());
+            attributes.insert(
+                "username".to_string(),
+                self.topology.proc_tracker.get_username(uid),
+            );
+            self.data.push(Metric {
+                name: String::from("scaph_user_power_consumption_microwatts"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: power.timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(
+                    "Power consumption due to the processes of the user, in microwatts",
+                ),
+                metric_value: MetricValueType::Text(power.value),
+            });
+        }
+    }
+
+    /// Generate the power metrics of systemd units and slices, from the power of
+    /// each process in *processes_power*, when processes are mapped to units.
+    fn gen_systemd_metrics(&mut self, processes_power: &HashMap<i32, Record>) {
+        let units = self
+            .topology
+            .get_systemd_units_power_consumption_microwatts(processes_power);
+        let mut slices: HashMap<&String, (Duration, u64)> = HashMap::new();
+        for (unit, power) in &units {
+            let microwatts = power.value.parse::<u64>().unwrap_or_default();
+            for slice in &unit.slices {
+                let slice = slices.entry(slice).or_default();
+                slice.0 = slice.0.max(power.timestamp);
+                slice.1 += microwatts;
             }
         }
+        let mut metrics = vec![];
+        for (unit, power) in &units {
+            let mut attributes = HashMap::new();
+            attributes.insert("unit".to_string(), unit.name.clone());
+            attributes.insert("slice".to_string(), unit.slice.clone());
+            metrics.push((
+                "scaph_systemd_unit_power_microwatts",
+                "Power consumption due to the processes of the systemd unit, in microwatts",
+                attributes,
+                power.timestamp,
+                power.value.clone(),
+            ));
+        }
+        for (slice, (timestamp, microwatts)) in slices {
+            let mut attributes = HashMap::new();
+            attributes.insert("slice".to_string(), slice.clone());
+            metrics.push((
+                "scaph_systemd_slice_power_microwatts",
+                "Power consumption due to the processes of the systemd slice, in microwatts",
+                attributes,
+                timestamp,
+                microwatts.to_string(),
+            ));
+        }
+        for (name, description, attributes, timestamp, value) in metrics {
+            self.data.push(Metric {
+                name: String::from(name),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(description),
+                metric_value: MetricValueType::Text(value),
+            });
+        }
+    }
+
+    /// Generate the power metrics of process trees, from the power of each
+    /// process in *processes_power*, when process trees are enabled.
+    fn gen_process_tree_metrics(&mut self, processes_power: &HashMap<i32, Record>) {
+        for (pid, power) in self
+            .topology
+            .get_trees_power_consumption_microwatts(processes_power)
+        {
+            let mut attributes = HashMap::new();
+            attributes.insert("pid".to_string(), pid.to_string());
+            attributes.insert(
+                "exe".to_string(),
+                self.topology.proc_tracker.get_process_name(pid),
+            );
+            self.data.push(Metric {
+                name: String::from("scaph_process_tree_power_consumption_microwatts"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: power.timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(
+                    "Power consumption due to the process and its descendants, in microwatts",
+                ),
+                metric_value: MetricValueType::Text(power.value),
+            });
+        }
+    }
+
+    /// Generate the energy counters of processes, process trees, containers, pods,
+    /// systemd units and slices and users. The final
+    /// value of the counters of the ones that are gone is generated once.
+    fn gen_energy_metrics(&mut self) {
+        let metrics = get_energy_counters_metrics(&self.topology.proc_tracker, &self.hostname);
+        self.data.extend(metrics);
+        self.topology.proc_tracker.drop_exported_energy_counters();
     }
 
     /// Generate all metrics provided by Scaphandre agent.
@@ -736,16 +1244,51 @@ impl MetricGenerator {
             Utc::now().format("%Y-%m-%dT%H:%M:%S")
         );
         self.gen_socket_metrics();
+        info!(
+            "{}: Get power limit metrics",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S")
+        );
+        self.gen_power_limit_metrics();
+        info!(
+            "{}: Get core metrics",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S")
+        );
+        self.gen_core_metrics();
+        info!(
+            "{}: Get power cap metrics",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S")
+        );
+        self.gen_power_cap_metrics();
+        info!(
+            "{}: Get component metrics",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S")
+        );
+        self.gen_component_metrics();
+        info!(
+            "{}: Get platform metrics",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S")
+        );
+        self.gen_platform_metrics();
         info!(
             "{}: Get system metrics",
             Utc::now().format("%Y-%m-%dT%H:%M:%S")
         );
         self.gen_system_metrics();
+        info!(
+            "{}: Get cgroup metrics",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S")
+        );
+        self.gen_cgroup_metrics();
         info!(
             "{}: Get process metrics",
             Utc::now().format("%Y-%m-%dT%H:%M:%S")
         );
         self.gen_process_metrics();
+        info!(
+            "{}: Get energy metrics",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S")
+        );
+        self.gen_energy_metrics();
         debug!("self_metrics: {:#?}", self.data);
     }
 
@@ -758,6 +1301,117 @@ impl MetricGenerator {
     }
 }
 
+/// Returns the metrics of the energy counters of processes (if enabled), process
+/// trees, containers, pods, systemd units and slices and users of *tracker*, the
+/// counters of the ones that are gone included. Exporters not using
+/// MetricGenerator::gen_all_metrics call it, then drop the exported counters.
+fn get_energy_counters_metrics(tracker: &ProcessTracker, hostname: &str) -> Vec<Metric> {
+    let timestamp = current_system_time_since_epoch();
+    let mut counters = vec![];
+    let processes_energy = tracker
+        .processes_energy
+        .iter()
+        .filter(|_| tracker.processes_energy_metrics);
+    for ((pid, start_time), process) in processes_energy {
+        let mut attributes = HashMap::new();
+        attributes.insert("pid".to_string(), pid.to_string());
+        attributes.insert("start_time".to_string(), start_time.to_string());
+        attributes.insert("exe".to_string(), process.exe.clone());
+        if let Some(container_id) = &process.container_id {
+            attributes.insert("container_id".to_string(), container_id.clone());
+        }
+        if let Some(pod_uid) = &process.pod_uid {
+            attributes.insert("pod_uid".to_string(), pod_uid.clone());
+        }
+        counters.push((
+            "scaph_process_energy_microjoules",
+            "Energy consumed by the process since scaphandre tracks it, in microjoules",
+            attributes,
+            process.energy.microjoules,
+        ));
+    }
+    for (container_id, counter) in &tracker.containers_energy {
+        let mut attributes = HashMap::new();
+        attributes.insert("container_id".to_string(), container_id.clone());
+        counters.push((
+            "scaph_container_energy_microjoules",
+            "Energy consumed by the processes of the container since scaphandre tracks them, in microjoules",
+            attributes,
+            counter.microjoules,
+        ));
+    }
+    for ((pid, start_time), tree) in &tracker.trees_energy {
+        let mut attributes = HashMap::new();
+        attributes.insert("pid".to_string(), pid.to_string());
+        attributes.insert("start_time".to_string(), start_time.to_string());
+        attributes.insert("exe".to_string(), tree.exe.clone());
+        counters.push((
+            "scaph_process_tree_energy_microjoules",
+            "Energy consumed by the process and its descendants since scaphandre tracks them, in microjoules",
+            attributes,
+            tree.energy.microjoules,
+        ));
+    }
+    for (unit, counter) in &tracker.units_energy {
+        let mut attributes = HashMap::new();
+        attributes.insert("unit".to_string(), unit.name.clone());
+        attributes.insert("slice".to_string(), unit.slice.clone());
+        counters.push((
+            "scaph_systemd_unit_energy_microjoules",
+            "Energy consumed by the processes of the systemd unit since scaphandre tracks them, in microjoules",
+            attributes,
+            counter.microjoules,
+        ));
+    }
+    for (slice, counter) in &tracker.slices_energy {
+        let mut attributes = HashMap::new();
+        attributes.insert("slice".to_string(), slice.clone());
+        counters.push((
+            "scaph_systemd_slice_energy_microjoules",
+            "Energy consumed by the processes of the systemd slice since scaphandre tracks them, in microjoules",
+            attributes,
+            counter.microjoules,
+        ));
+    }
+    for (uid, counter) in &tracker.users_energy {
+        let mut attributes = HashMap::new();
+        attributes.insert("uid".to_string(), uid.to_string());
+        attributes.insert("username".to_string(), tracker.get_username(*uid));
+        counters.push((
+            "scaph_user_energy_microjoules",
+            "Energy consumed by the processes of the user since scaphandre tracks them, in microjoules",
+            attributes,
+            counter.microjoules,
+        ));
+    }
+    for (pod_uid, counter) in &tracker.pods_energy {
+        let mut attributes = HashMap::new();
+        attributes.insert("pod_uid".to_string(), pod_uid.clone());
+        counters.push((
+            "scaph_pod_energy_microjoules",
+            "Energy consumed by the processes of the kubernetes pod since scaphandre tracks them, in microjoules",
+            attributes,
+            counter.microjoules,
+        ));
+    }
+    let mut metrics = vec![];
+    for (name, description, attributes, microjoules) in counters {
+        metrics.push(Metric {
+            name: String::from(name),
+            metric_type: String::from("counter"),
+            ttl: 60.0,
+            timestamp,
+            hostname: String::from(hostname),
+            state: String::from("ok"),
+            tags: vec!["scaphandre".to_string()],
+            attributes,
+            description: String::from(description),
+            metric_value: MetricValueType::Text((microjoules as u64).to_string()),
+        });
+    }
+    metrics
+}
+
 //  Copyright 2020 The scaphandre authors.
 //
 //  Licensed under the Apache License, Version 2.0 (the "License");
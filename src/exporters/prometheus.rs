@@ -2,7 +2,7 @@
 //!
 //! `PrometheusExporter` implementation, expose metrics to
 //! a [Prometheus](https://prometheus.io/) server.
-use super::utils::get_hostname;
+use super::utils::{get_hostname, get_tree_roots};
 use crate::current_system_time_since_epoch;
 use crate::exporters::{Exporter, MetricGenerator, MetricValueType};
 use crate::sensors::{Sensor, Topology};
@@ -47,8 +47,14 @@ impl Exporter for PrometheusExporter {
         );
         println!("Press CTRL-C to stop scaphandre");
 
+        let mut topology = (*self.sensor.get_topology()).unwrap();
+        topology.track_threads = parameters.is_present("threads");
+        topology.proc_tracker.processes_energy_metrics = parameters.is_present("process_energy");
+        topology.proc_tracker.tree_roots = get_tree_roots(&parameters);
+        topology.proc_tracker.systemd_units = parameters.is_present("systemd_units");
+        topology.proc_tracker.users = parameters.is_present("users");
         runner(
-            (*self.sensor.get_topology()).unwrap(),
+            topology,
             parameters.value_of("address").unwrap().to_string(),
             parameters.value_of("port").unwrap().to_string(),
             parameters.value_of("suffix").unwrap().to_string(),
@@ -102,6 +108,44 @@ impl Exporter for PrometheusExporter {
             .takes_value(false);
         options.push(arg);
 
+        let arg = Arg::with_name("threads")
+            .help("Expose the power consumption of each thread of each process (scaph_thread_power_consumption_microwatts). Increases the number of series a lot on hosts running many threads.")
+            .long("threads")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("process_energy")
+            .help("Expose the energy consumed by each process (scaph_process_energy_microjoules). Creates a series for each process, short-lived ones included.")
+            .long("process-energy")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("process_tree")
+            .help("Expose the power and energy of each process tree (scaph_process_tree_power_consumption_microwatts), rolling up the processes to the leader of their process group (like a shell job), or to the given roots (PIDs or executable names, separated by commas).")
+            .long("process-tree")
+            .value_name("roots")
+            .required(false)
+            .takes_value(true)
+            .min_values(0)
+            .use_delimiter(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("systemd_units")
+            .help("Expose the power and energy of each systemd unit and slice, mapping processes to the unit found in /proc/PID/cgroup.")
+            .long("systemd-units")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("users")
+            .help("Expose the power and energy of each user, mapping processes to their real UID and UIDs to names found in /etc/passwd.")
+            .long("users")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
         let arg = Arg::with_name("kubernetes_host")
             .help("FQDN of the kubernetes API server")
             .long("kubernetes-host")
@@ -52,47 +52,41 @@ impl QemuExporter {
     pub fn iteration(&mut self, path: String) {
         trace!("path: {}", path);
         self.topology.refresh();
-        let topo_uj_diff = self.topology.get_records_diff();
-        let topo_stat_diff = self.topology.get_stats_diff();
-        if let Some(topo_rec_uj) = topo_uj_diff {
-            debug!("Got topo uj diff: {:?}", topo_rec_uj);
-            let proc_tracker = self.topology.get_proc_tracker();
-            let processes = proc_tracker.get_alive_processes();
-            let qemu_processes = QemuExporter::filter_qemu_vm_processes(&processes);
-            debug!(
-                "Number of filtered qemu processes: {}",
-                qemu_processes.len()
-            );
-            for qp in qemu_processes {
-                info!("Working on {:?}", qp);
-                if qp.len() > 2 {
-                    let last = qp.first().unwrap();
-                    let previous = qp.get(1).unwrap();
-                    let vm_name =
-                        QemuExporter::get_vm_name_from_cmdline(&last.process.cmdline().unwrap());
-                    let time_pdiff = last.total_time_jiffies() - previous.total_time_jiffies();
-                    if let Some(time_tdiff) = &topo_stat_diff {
-                        let first_domain_path = format!("{}/{}/intel-rapl:0:0", path, vm_name);
-                        if fs::read_dir(&first_domain_path).is_err() {
-                            match fs::create_dir_all(&first_domain_path) {
-                                Ok(_) => info!("Created {} folder.", &path),
-                                Err(error) => panic!("Couldn't create {}. Got: {}", &path, error),
-                            }
-                        }
-                        let tdiff = time_tdiff.total_time_jiffies();
-                        trace!("Time_pdiff={} time_tdiff={}", time_pdiff.to_string(), tdiff);
-                        let ratio = time_pdiff / tdiff;
-                        trace!("Ratio is {}", ratio.to_string());
-                        let uj_to_add = ratio * topo_rec_uj.value.parse::<u64>().unwrap();
-                        trace!("Adding {} uJ", uj_to_add);
-                        let complete_path = format!("{}/{}/intel-rapl:0", path, vm_name);
-                        if let Ok(result) =
-                            QemuExporter::add_or_create(&complete_path, uj_to_add as u64)
-                        {
-                            trace!("{:?}", result);
-                            debug!("Updated {}", complete_path);
+        let processes_power = self.topology.get_processes_power_consumption_microwatts();
+        let proc_tracker = self.topology.get_proc_tracker();
+        let processes = proc_tracker.get_alive_processes();
+        let qemu_processes = QemuExporter::filter_qemu_vm_processes(&processes);
+        debug!(
+            "Number of filtered qemu processes: {}",
+            qemu_processes.len()
+        );
+        for qp in qemu_processes {
+            info!("Working on {:?}", qp);
+            if qp.len() > 2 {
+                let last = qp.first().unwrap();
+                let previous = qp.get(1).unwrap();
+                let vm_name =
+                    QemuExporter::get_vm_name_from_cmdline(&last.process.cmdline().unwrap());
+                if let Some(power) = processes_power.get(&last.process.pid) {
+                    let first_domain_path = format!("{}/{}/intel-rapl:0:0", path, vm_name);
+                    if fs::read_dir(&first_domain_path).is_err() {
+                        match fs::create_dir_all(&first_domain_path) {
+                            Ok(_) => info!("Created {} folder.", &path),
+                            Err(error) => panic!("Couldn't create {}. Got: {}", &path, error),
                         }
                     }
+                    // the energy of the vm is the power attributed to its process
+                    // over the time elapsed since the previous measurement
+                    let time_diff = last.timestamp.as_secs_f64() - previous.timestamp.as_secs_f64();
+                    let uj_to_add = power.value.parse::<f64>().unwrap() * time_diff;
+                    trace!("Adding {} uJ", uj_to_add);
+                    let complete_path = format!("{}/{}/intel-rapl:0", path, vm_name);
+                    if let Ok(result) =
+                        QemuExporter::add_or_create(&complete_path, uj_to_add as u64)
+                    {
+                        trace!("{:?}", result);
+                        debug!("Updated {}", complete_path);
+                    }
                 }
             }
         }
@@ -15,6 +15,25 @@ use std::convert::TryFrom;
 use std::thread;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+/// Returns the name of *metric* followed by its attributes, like
+/// `name{exe="bash",pid="42"}`, the values being escaped so that the services
+/// of different consumers never collide.
+fn get_service_name(metric: &Metric) -> String {
+    let mut attributes: Vec<String> = metric
+        .attributes
+        .iter()
+        .map(|(key, value)| {
+            format!(
+                "{}=\"{}\"",
+                key,
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect();
+    attributes.sort();
+    format!("{}{{{}}}", metric.name, attributes.join(","))
+}
+
 /// Riemann server default ipv4/ipv6 address
 const DEFAULT_IP_ADDRESS: &str = "localhost";
 
@@ -138,10 +157,11 @@ impl Exporter for RiemannExporter {
         println!("Press CTRL-C to stop scaphandre");
         println!("Measurement step is: {}s", dispatch_duration);
 
-        let topology = self.sensor.get_topology().unwrap();
+        let mut topology = self.sensor.get_topology().unwrap();
+        topology.proc_tracker.processes_energy_metrics = parameters.is_present("process_energy");
         let mut metric_generator = MetricGenerator::new(
             topology,
-            hostname,
+            hostname.clone(),
             parameters.is_present("qemu"),
             parameters.is_present("containers"),
         );
@@ -169,8 +189,17 @@ impl Exporter for RiemannExporter {
             metric_generator.gen_self_metrics();
             metric_generator.gen_host_metrics();
             metric_generator.gen_socket_metrics();
+            metric_generator.gen_power_limit_metrics();
+            metric_generator.gen_core_metrics();
+            metric_generator.gen_power_cap_metrics();
+            metric_generator.gen_component_metrics();
+            metric_generator.gen_platform_metrics();
+            metric_generator.gen_cgroup_metrics();
 
             let mut data = vec![];
+            let processes_power = metric_generator
+                .topology
+                .get_processes_power_consumption_microwatts();
             let processes_tracker = &metric_generator.topology.proc_tracker;
 
             for pid in processes_tracker.get_alive_pids() {
@@ -198,10 +227,7 @@ impl Exporter for RiemannExporter {
                     "{}_{}_{}",
                     "scaph_process_power_consumption_microwatts", pid, exe
                 );
-                if let Some(power) = metric_generator
-                    .topology
-                    .get_process_power_consumption_microwatts(pid)
-                {
+                if let Some(power) = processes_power.get(&pid) {
                     data.push(Metric {
                         name: metric_name,
                         metric_type: String::from("gauge"),
@@ -212,10 +238,21 @@ impl Exporter for RiemannExporter {
                         tags: vec!["scaphandre".to_string()],
                         attributes,
                         description: String::from("Power consumption due to the process, measured on at the topology level, in microwatts"),
-                        metric_value: MetricValueType::Text(power.value),
+                        metric_value: MetricValueType::Text(power.value.clone()),
                     });
                 }
             }
+            // As for processes power, each energy counter needs its own service
+            for mut metric in
+                get_energy_counters_metrics(&metric_generator.topology.proc_tracker, &hostname)
+            {
+                metric.name = get_service_name(&metric);
+                data.push(metric);
+            }
+            metric_generator
+                .topology
+                .proc_tracker
+                .drop_exported_energy_counters();
             // Send all data
             info!("{}: Send data", Utc::now().format("%Y-%m-%dT%H:%M:%S"));
             for metric in metric_generator.pop_metrics() {
@@ -267,6 +304,13 @@ impl Exporter for RiemannExporter {
             .takes_value(false);
         options.push(arg);
 
+        let arg = Arg::with_name("process_energy")
+            .help("Expose the energy consumed by each process (scaph_process_energy_microjoules). Creates a series for each process, short-lived ones included.")
+            .long("process-energy")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
         let arg = Arg::with_name("mtls")
             .help("Connect to a Riemann server using mTLS. Parameters address, ca, cert and key must be defined.")
             .long("mtls")
@@ -306,6 +350,38 @@ impl Exporter for RiemannExporter {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_names_dont_collide() {
+        let metric = |attributes: &[(&str, &str)]| Metric {
+            name: String::from("scaph_container_energy_microjoules"),
+            metric_type: String::from("counter"),
+            ttl: 60.0,
+            hostname: String::from("host"),
+            state: String::from("ok"),
+            tags: vec![],
+            attributes: attributes
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            description: String::new(),
+            metric_value: MetricValueType::Text(String::from("1")),
+            timestamp: Duration::default(),
+        };
+        assert_eq!(
+            get_service_name(&metric(&[("pod_uid", "p"), ("container_id", "a\"b")])),
+            r#"scaph_container_energy_microjoules{container_id="a\"b",pod_uid="p"}"#
+        );
+        assert_ne!(
+            get_service_name(&metric(&[("container_id", "a_b")])),
+            get_service_name(&metric(&[("container_id", "a"), ("pod_uid", "b")]))
+        );
+    }
+}
+
 //  Copyright 2020 The scaphandre authors.
 //
 //  Licensed under the Apache License, Version 2.0 (the "License");
@@ -1,12 +1,16 @@
 use clap::Arg;
 
 use crate::exporters::*;
+use crate::sensors::utils::ProcessTracker;
 use crate::sensors::Sensor;
 use colored::*;
 use regex::Regex;
 use std::thread;
 use std::time::{Duration, Instant};
 
+/// Maximum depth of the process trees displayed
+const MAX_TREE_DEPTH: usize = 32;
+
 /// An Exporter that displays power consumption data of the host
 /// and its processes on the standard output of the terminal.
 pub struct StdoutExporter {
@@ -57,6 +61,30 @@ impl Exporter for StdoutExporter {
             .takes_value(true);
         options.push(arg);
 
+        let arg = Arg::with_name("pid")
+            .help("Display the power consumption of each thread of the process with this PID.")
+            .long("pid")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("process_tree")
+            .help("Display process trees instead of processes, rolling up each process to the leader of its process group (like a shell job), or to the given roots (PIDs or executable names, separated by commas).")
+            .long("process-tree")
+            .value_name("roots")
+            .required(false)
+            .takes_value(true)
+            .min_values(0)
+            .use_delimiter(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("users")
+            .help("Display users instead of processes, mapping processes to their real UID and UIDs to names found in /etc/passwd.")
+            .long("users")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
         let arg = Arg::with_name("qemu")
             .help("Apply labels to metrics of processes looking like a Qemu/KVM virtual machine")
             .long("qemu")
@@ -118,7 +146,14 @@ impl StdoutExporter {
             eprintln!("{}", warning.bright_yellow());
         }
 
-        let topology = self.sensor.get_topology().unwrap();
+        let threads_pid: Option<i32> = parameters
+            .value_of("pid")
+            .map(|pid| pid.parse().expect("Wrong pid value, should be a number"));
+
+        let mut topology = self.sensor.get_topology().unwrap();
+        topology.track_threads = threads_pid.is_some();
+        topology.proc_tracker.tree_roots = utils::get_tree_roots(&parameters);
+        topology.proc_tracker.users = parameters.is_present("users");
         let mut metric_generator = MetricGenerator::new(
             topology,
             utils::get_hostname(),
@@ -129,14 +164,24 @@ impl StdoutExporter {
         println!("Measurement step is: {}s", step_duration);
         if timeout_secs == 0 {
             loop {
-                self.iterate(&regex_filter, process_number, &mut metric_generator);
+                self.iterate(
+                    &regex_filter,
+                    process_number,
+                    threads_pid,
+                    &mut metric_generator,
+                );
                 thread::sleep(Duration::new(step_duration, 0));
             }
         } else {
             let now = Instant::now();
 
             while now.elapsed().as_secs() <= timeout_secs {
-                self.iterate(&regex_filter, process_number, &mut metric_generator);
+                self.iterate(
+                    &regex_filter,
+                    process_number,
+                    threads_pid,
+                    &mut metric_generator,
+                );
                 thread::sleep(Duration::new(step_duration, 0));
             }
         }
@@ -146,6 +191,7 @@ impl StdoutExporter {
         &mut self,
         regex_filter: &Option<Regex>,
         process_number: u16,
+        threads_pid: Option<i32>,
         metric_generator: &mut MetricGenerator,
     ) {
         metric_generator
@@ -153,13 +199,14 @@ impl StdoutExporter {
             .proc_tracker
             .clean_terminated_process_records_vectors();
         metric_generator.topology.refresh();
-        self.show_metrics(regex_filter, process_number, metric_generator);
+        self.show_metrics(regex_filter, process_number, threads_pid, metric_generator);
     }
 
     fn show_metrics(
         &self,
         regex_filter: &Option<Regex>,
         process_number: u16,
+        threads_pid: Option<i32>,
         metric_generator: &mut MetricGenerator,
     ) {
         metric_generator.gen_all_metrics();
@@ -171,13 +218,30 @@ impl StdoutExporter {
             None => MetricValueType::Text("0".to_string()),
         };
 
-        let domain_names = metric_generator.topology.domains_names.as_ref().unwrap();
+        let no_domain_names = vec![];
+        let domain_names = metric_generator
+            .topology
+            .domains_names
+            .as_ref()
+            .unwrap_or(&no_domain_names);
         info!("domain_name: {:?}", domain_names);
 
         println!(
             "Host:\t{} W",
             (format!("{}", host_power).parse::<f64>().unwrap() / 1000000.0)
         );
+        let power_of = |name: &str| {
+            metrics
+                .iter()
+                .find(|x| x.name == name)
+                .map(|m| format!("{}", m.metric_value).parse::<f64>().unwrap() / 1000000.0)
+        };
+        if let (Some(idle), Some(dynamic)) = (
+            power_of("scaph_host_idle_power_microwatts"),
+            power_of("scaph_host_dynamic_power_microwatts"),
+        ) {
+            println!("\tidle {} W\tdynamic {} W", idle, dynamic);
+        }
         println!("\tpackage \t{}", domain_names.join("\t\t"));
 
         for s in metrics
@@ -228,6 +292,46 @@ impl StdoutExporter {
             println!("{}\n", to_print);
         }
 
+        for c in metrics
+            .iter()
+            .filter(|x| x.name == "scaph_component_power_microwatts")
+        {
+            let power = format!("{}", c.metric_value).parse::<f32>().unwrap() / 1000000.0;
+            println!(
+                "Component {}\t{} W",
+                c.attributes.get("component_name").unwrap(),
+                power
+            );
+        }
+
+        for p in metrics
+            .iter()
+            .filter(|x| x.name == "scaph_platform_power_microwatts")
+        {
+            let power = format!("{}", p.metric_value).parse::<f32>().unwrap() / 1000000.0;
+            println!(
+                "Platform {}\t{} W",
+                p.attributes.get("platform_name").unwrap(),
+                power
+            );
+        }
+
+        if metric_generator.topology.cgroup_tracker.is_some() {
+            // processes are not tracked when power is attributed to cgroups
+            self.show_cgroups(&metrics, regex_filter, process_number);
+            return;
+        }
+
+        if metric_generator.topology.proc_tracker.users {
+            self.show_users(&metrics, regex_filter, process_number);
+            return;
+        }
+
+        if metric_generator.topology.proc_tracker.tree_roots.is_some() {
+            self.show_trees(&metrics, metric_generator, regex_filter, process_number);
+            return;
+        }
+
         let consumers: Vec<(procfs::process::Process, u64)> =
             if let Some(regex_filter) = regex_filter {
                 println!("Processes filtered by '{}':", regex_filter.as_str());
@@ -266,8 +370,200 @@ impl StdoutExporter {
                 }
             }
         }
+        if let Some(pid) = threads_pid {
+            let pid = pid.to_string();
+            let mut threads: Vec<&Metric> = metrics
+                .iter()
+                .filter(|x| {
+                    x.name == "scaph_thread_power_consumption_microwatts"
+                        && x.attributes.get("pid") == Some(&pid)
+                })
+                .collect();
+            threads.sort_by_key(|x| x.attributes.get("tid").unwrap().parse::<i32>().unwrap());
+            println!("\nThreads of PID {}:", pid);
+            println!("Power\t\tTID\tName");
+            if threads.is_empty() {
+                println!("No threads found yet for that PID.");
+            }
+            for t in threads {
+                println!(
+                    "{} W\t{}\t{:?}",
+                    format!("{}", t.metric_value).parse::<f32>().unwrap() / 1000000.0,
+                    t.attributes.get("tid").unwrap(),
+                    t.attributes.get("thread_name").unwrap()
+                );
+            }
+        }
         println!("------------------------------------------------------------\n");
     }
+
+    /// Displays the process trees consuming the most, or the ones whose root
+    /// matches *regex_filter*, with the power of each subtree.
+    fn show_trees(
+        &self,
+        metrics: &[Metric],
+        metric_generator: &MetricGenerator,
+        regex_filter: &Option<Regex>,
+        process_number: u16,
+    ) {
+        let power_by_pid = |name: &str| -> HashMap<i32, f32> {
+            metrics
+                .iter()
+                .filter(|x| x.name == name)
+                .map(|x| {
+                    (
+                        x.attributes.get("pid").unwrap().parse::<i32>().unwrap(),
+                        format!("{}", x.metric_value).parse::<f32>().unwrap() / 1000000.0,
+                    )
+                })
+                .collect()
+        };
+        let processes = power_by_pid("scaph_process_power_consumption_microwatts");
+        let mut trees: Vec<(i32, f32)> =
+            power_by_pid("scaph_process_tree_power_consumption_microwatts")
+                .into_iter()
+                .collect();
+        trees.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        let tracker = &metric_generator.topology.proc_tracker;
+        if let Some(regex_filter) = regex_filter {
+            println!("Process trees filtered by '{}':", regex_filter.as_str());
+            trees.retain(|t| regex_filter.is_match(&tracker.get_process_name(t.0)));
+        } else {
+            println!("Top {} process trees:", process_number);
+            trees.truncate(process_number as usize);
+        }
+        println!("Power		PID	Exe");
+        if trees.is_empty() {
+            println!("No process trees found yet or filter returns no value.");
+        }
+        let children = tracker.get_children();
+        for (root, _) in trees {
+            show_subtree(root, 0, &children, &processes, tracker);
+        }
+        println!("------------------------------------------------------------\n");
+    }
+
+    /// Displays the users consuming the most, with their energy since scaphandre
+    /// started, or the ones whose name matches *regex_filter*.
+    fn show_users(&self, metrics: &[Metric], regex_filter: &Option<Regex>, process_number: u16) {
+        let value_of = |metric: &Metric| format!("{}", metric.metric_value).parse::<f64>().unwrap();
+        let energy: HashMap<&String, f64> = metrics
+            .iter()
+            .filter(|x| x.name == "scaph_user_energy_microjoules")
+            .map(|x| (x.attributes.get("uid").unwrap(), value_of(x) / 1000000.0))
+            .collect();
+        let mut users: Vec<(&String, &String, f64)> = metrics
+            .iter()
+            .filter(|x| x.name == "scaph_user_power_consumption_microwatts")
+            .map(|x| {
+                (
+                    x.attributes.get("uid").unwrap(),
+                    x.attributes.get("username").unwrap(),
+                    value_of(x) / 1000000.0,
+                )
+            })
+            .collect();
+        users.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
+        if let Some(regex_filter) = regex_filter {
+            println!("Users filtered by '{}':", regex_filter.as_str());
+            users.retain(|u| regex_filter.is_match(u.1));
+        } else {
+            println!("Top {} users:", process_number);
+            users.truncate(process_number as usize);
+        }
+        println!("Power\t\tEnergy\t\tUID\tUser");
+        if users.is_empty() {
+            println!("No users found yet or filter returns no value.");
+        }
+        for (uid, username, power) in users {
+            println!(
+                "{} W\t{} J\t{}\t{}",
+                power,
+                energy.get(uid).cloned().unwrap_or_default(),
+                uid,
+                username
+            );
+        }
+        println!("------------------------------------------------------------\n");
+    }
+
+    /// Displays the cgroups consuming the most, or the ones matching *regex_filter*.
+    fn show_cgroups(&self, metrics: &[Metric], regex_filter: &Option<Regex>, process_number: u16) {
+        let mut cgroups: Vec<(&String, f32)> = metrics
+            .iter()
+            .filter(|x| x.name == "scaph_cgroup_power_consumption_microwatts")
+            .map(|x| {
+                (
+                    x.attributes.get("cgroup").unwrap(),
+                    format!("{}", x.metric_value).parse::<f32>().unwrap() / 1000000.0,
+                )
+            })
+            .collect();
+        cgroups.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        if let Some(regex_filter) = regex_filter {
+            println!("Cgroups filtered by '{}':", regex_filter.as_str());
+            cgroups.retain(|c| regex_filter.is_match(c.0));
+        } else {
+            println!("Top {} cgroups:", process_number);
+            cgroups.truncate(process_number as usize);
+        }
+        println!("Power\t\tCgroup");
+        if cgroups.is_empty() {
+            println!("No cgroups found yet or filter returns no value.");
+        }
+        for (path, power) in cgroups {
+            println!("{} W\t{}", power, path);
+        }
+        println!("------------------------------------------------------------\n");
+    }
+}
+
+/// Returns the power of the process *pid* and its descendants, in watts.
+fn subtree_power(
+    pid: i32,
+    depth: usize,
+    children: &HashMap<i32, Vec<i32>>,
+    processes: &HashMap<i32, f32>,
+) -> f32 {
+    let mut power = processes.get(&pid).cloned().unwrap_or_default();
+    if depth < MAX_TREE_DEPTH {
+        for child in children.get(&pid).into_iter().flatten() {
+            power += subtree_power(*child, depth + 1, children, processes);
+        }
+    }
+    power
+}
+
+/// Displays the process *pid* and the subtrees of its children consuming power,
+/// indented by *depth*.
+fn show_subtree(
+    pid: i32,
+    depth: usize,
+    children: &HashMap<i32, Vec<i32>>,
+    processes: &HashMap<i32, f32>,
+    tracker: &ProcessTracker,
+) {
+    let power = subtree_power(pid, depth, children, processes);
+    if depth > 0 && power <= 0.0 {
+        return;
+    }
+    let indent = if depth > 0 {
+        format!("{}└ ", "  ".repeat(depth - 1))
+    } else {
+        String::new()
+    };
+    println!(
+        "{} W\t{}\t{}{:?}",
+        power,
+        pid,
+        indent,
+        tracker.get_process_name(pid)
+    );
+    if depth < MAX_TREE_DEPTH {
+        for child in children.get(&pid).into_iter().flatten() {
+            show_subtree(*child, depth + 1, children, processes, tracker);
+        }
+    }
 }
 
 #[cfg(test)]
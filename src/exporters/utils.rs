@@ -1,7 +1,8 @@
 //! # utils
 //!
 //! The utils module provides common functions used by the exporters.
-use clap::crate_version;
+use crate::sensors::utils::TreeRoots;
+use clap::{crate_version, ArgMatches};
 use docker_sync::Docker;
 use k8s_sync::{errors::KubernetesError, kubernetes::Kubernetes};
 
@@ -24,6 +25,19 @@ pub fn filter_qemu_cmdline(cmdline: &str) -> Option<String> {
     None
 }
 
+/// Returns the roots of the process trees given by the process_tree option
+/// of an exporter, None if the option is not set.
+pub fn get_tree_roots(parameters: &ArgMatches) -> Option<TreeRoots> {
+    if !parameters.is_present("process_tree") {
+        return None;
+    }
+    let values: Vec<&str> = parameters
+        .values_of("process_tree")
+        .map(|values| values.collect())
+        .unwrap_or_default();
+    Some(TreeRoots::new(&values))
+}
+
 /// Returns scaphandre version.
 pub fn get_scaphandre_version() -> String {
     let mut version_parts = crate_version!().split('.');
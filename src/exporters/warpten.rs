@@ -32,6 +32,8 @@ impl Exporter for Warp10Exporter {
         //let read_token = parameters.value_of("read-token");
         let step = parameters.value_of("step").unwrap();
         let qemu = parameters.is_present("qemu");
+        self.topology.proc_tracker.processes_energy_metrics =
+            parameters.is_present("process_energy");
 
         loop {
             match self.iteration(
@@ -96,6 +98,13 @@ impl Exporter for Warp10Exporter {
             .takes_value(true);
         options.push(arg);
 
+        let arg = Arg::with_name("process_energy")
+            .help("Expose the energy consumed by each process (scaph_process_energy_microjoules). Creates a series for each process, short-lived ones included.")
+            .long("process-energy")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
         let arg = Arg::with_name("qemu")
             .help("Tells scaphandre it is running on a Qemu hypervisor.")
             .long("qemu")
@@ -326,6 +335,7 @@ impl Warp10Exporter {
             warp10::Value::Double(scaphandre_version.parse::<f64>().unwrap()),
         )];
 
+        let processes_power = self.topology.get_processes_power_consumption_microwatts();
         let processes_tracker = &self.topology.proc_tracker;
         for pid in processes_tracker.get_alive_pids() {
             let exe = processes_tracker.get_process_name(pid);
@@ -349,7 +359,7 @@ impl Warp10Exporter {
                 "{}_{}_{}",
                 "scaph_process_power_consumption_microwats", pid, exe
             );
-            if let Some(power) = self.topology.get_process_power_consumption_microwatts(pid) {
+            if let Some(power) = processes_power.get(&pid) {
                 process_data.push(warp10::Data::new(
                     time::OffsetDateTime::now_utc(),
                     None,
@@ -359,7 +369,23 @@ impl Warp10Exporter {
                 ));
             }
         }
+        let hostname = utils::get_hostname();
+        for metric in get_energy_counters_metrics(processes_tracker, &hostname) {
+            let mut elabels = labels.clone();
+            for (key, value) in &metric.attributes {
+                elabels.push(warp10::Label::new(key, value));
+            }
+            process_data.push(warp10::Data::new(
+                time::OffsetDateTime::now_utc(),
+                None,
+                metric.name,
+                elabels,
+                warp10::Value::Long(metric.metric_value.to_string().parse::<i64>().unwrap()),
+            ));
+        }
         let process_res = writer.post_sync(process_data)?;
+        // counters that couldn't be sent are retried on the next iteration
+        self.topology.proc_tracker.drop_exported_energy_counters();
 
         //if let Some(token) = read_token {
         //let reader = client.get_reader(token.to_owned());
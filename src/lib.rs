@@ -5,16 +5,34 @@
 //! Final monitoring data is sent to or exposed for monitoring tools thanks to *exporters*.
 #[macro_use]
 extern crate log;
+pub mod capping;
 pub mod exporters;
 pub mod sensors;
+use capping::{CappingGuard, CappingPolicy, CappingSensor, PowerCapper};
 use clap::ArgMatches;
 use colored::*;
 use exporters::{
     json::JSONExporter, prometheus::PrometheusExporter, qemu::QemuExporter,
     riemann::RiemannExporter, stdout::StdoutExporter, warpten::Warp10Exporter, Exporter,
 };
-use sensors::{powercap_rapl::PowercapRAPLSensor, Sensor};
+use sensors::{
+    amd_rapl::{self, AmdRAPLSensor},
+    attribution::{get_attribution_model, AttributionMode, AttributionSensor, IdleRule},
+    cgroup,
+    composite::CompositeSensor,
+    hwmon::{self, HwmonSensor},
+    ipmi::IpmiSensor,
+    msr_rapl::{self, MsrRAPLSensor},
+    power_supply::{self, PowerSupplySensor},
+    powercap_rapl::PowercapRAPLSensor,
+    redfish::RedfishSensor,
+    replay::{RecordSensor, ReplaySensor},
+    synthetic::{self, LoadModel, SyntheticSensor},
+    taskstats::{TaskstatsListener, TaskstatsSensor},
+    Sensor, DEFAULT_CPU_SYSFS_PATH,
+};
 use std::collections::HashMap;
+use std::env;
 use std::time::{Duration, SystemTime};
 
 /// Helper function to get an argument from ArgMatches
@@ -25,29 +43,128 @@ fn get_argument(matches: &ArgMatches, arg: &'static str) -> String {
     panic!("Couldn't get argument {}", arg);
 }
 
-/// Helper function to get a Sensor instance from ArgMatches
+/// Helper function to get a Sensor instance from ArgMatches.
+/// When several sensors are requested, they are combined in a CompositeSensor,
+/// by order of precedence.
 fn get_sensor(matches: &ArgMatches) -> Box<dyn Sensor> {
-    let sensor = match &get_argument(matches, "sensor")[..] {
-        "powercap_rapl" => PowercapRAPLSensor::new(
-            get_argument(matches, "sensor-buffer-per-socket-max-kB")
-                .parse()
-                .unwrap(),
-            get_argument(matches, "sensor-buffer-per-domain-max-kB")
-                .parse()
-                .unwrap(),
-            matches.is_present("vm"),
-        ),
-        _ => PowercapRAPLSensor::new(
-            get_argument(matches, "sensor-buffer-per-socket-max-kB")
-                .parse()
-                .unwrap(),
-            get_argument(matches, "sensor-buffer-per-domain-max-kB")
-                .parse()
-                .unwrap(),
+    let names: Vec<&str> = match matches.values_of("sensor") {
+        Some(values) => values.collect(),
+        None => panic!("Couldn't get argument sensor"),
+    };
+    if names.len() == 1 {
+        return get_named_sensor(names[0], matches);
+    }
+    let buffer_per_domain_max_kbytes = get_argument(matches, "sensor-buffer-per-domain-max-kB")
+        .parse()
+        .unwrap();
+    let sensors = names
+        .iter()
+        .map(|name| (String::from(*name), get_named_sensor(name, matches)))
+        .collect();
+    Box::new(CompositeSensor::new(sensors, buffer_per_domain_max_kbytes))
+}
+
+/// Helper function to get an instance of the Sensor called *name* from ArgMatches
+fn get_named_sensor(name: &str, matches: &ArgMatches) -> Box<dyn Sensor> {
+    let buffer_per_socket_max_kbytes = get_argument(matches, "sensor-buffer-per-socket-max-kB")
+        .parse()
+        .unwrap();
+    let buffer_per_domain_max_kbytes = get_argument(matches, "sensor-buffer-per-domain-max-kB")
+        .parse()
+        .unwrap();
+    match name {
+        "powercap_rapl" => Box::new(PowercapRAPLSensor::new(
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
             matches.is_present("vm"),
-        ),
+        )),
+        "amd_rapl" => Box::new(AmdRAPLSensor::new(
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
+            amd_rapl::DEFAULT_HWMON_PATH,
+            amd_rapl::DEFAULT_POWERCAP_PATH,
+            DEFAULT_CPU_SYSFS_PATH,
+        )),
+        "hwmon" => Box::new(HwmonSensor::new(
+            buffer_per_domain_max_kbytes,
+            hwmon::DEFAULT_HWMON_PATH,
+            matches
+                .value_of("sensor-hwmon-host-channel")
+                .map(String::from),
+        )),
+        "ipmi" => Box::new(IpmiSensor::new(
+            buffer_per_domain_max_kbytes,
+            &get_argument(matches, "sensor-ipmi-command"),
+        )),
+        "msr_rapl" => Box::new(MsrRAPLSensor::new(
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
+            msr_rapl::DEFAULT_MSR_PATH,
+            DEFAULT_CPU_SYSFS_PATH,
+            "/proc",
+        )),
+        "power_supply" => Box::new(PowerSupplySensor::new(
+            power_supply::DEFAULT_POWER_SUPPLY_PATH,
+            Some(Box::new(PowercapRAPLSensor::new(
+                buffer_per_socket_max_kbytes,
+                buffer_per_domain_max_kbytes,
+                matches.is_present("vm"),
+            ))),
+        )),
+        "redfish" => {
+            let password = match matches.value_of("sensor-redfish-password") {
+                Some(password) => String::from(password),
+                None => env::var("SCAPHANDRE_REDFISH_PASSWORD").unwrap_or_default(),
+            };
+            Box::new(RedfishSensor::new(
+                buffer_per_domain_max_kbytes,
+                &get_argument(matches, "sensor-redfish-url"),
+                matches.value_of("sensor-redfish-username").unwrap_or(""),
+                &password,
+                matches.is_present("sensor-redfish-session"),
+                matches.is_present("sensor-redfish-insecure"),
+            ))
+        }
+        "replay" => Box::new(ReplaySensor::new(
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
+            &get_argument(matches, "sensor-replay-path"),
+        )),
+        "synthetic" => Box::new(SyntheticSensor::new(
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
+            LoadModel::new(
+                get_argument(matches, "sensor-synthetic-idle-watts")
+                    .parse()
+                    .unwrap(),
+                get_argument(matches, "sensor-synthetic-max-watts")
+                    .parse()
+                    .unwrap(),
+                get_argument(matches, "sensor-synthetic-curve-exponent")
+                    .parse()
+                    .unwrap(),
+            ),
+            synthetic::DEFAULT_PROCFS_PATH,
+        )),
+        _ => panic!("Unknown sensor: {}", name),
+    }
+}
+
+/// Helper function to get a PowerCapper applying the policy found at *policy_path*,
+/// with the other capping options from ArgMatches
+fn get_power_capper(policy_path: &str, matches: &ArgMatches) -> PowerCapper {
+    let policy = match CappingPolicy::from_file(policy_path) {
+        Ok(policy) => policy,
+        Err(e) => panic!("Couldn't read power capping policy {}: {}", policy_path, e),
     };
-    Box::new(sensor)
+    match PowerCapper::new(
+        policy,
+        &get_argument(matches, "power-cap-powercap-path"),
+        matches.is_present("power-cap-dry-run"),
+    ) {
+        Ok(capper) => capper,
+        Err(e) => panic!("Couldn't initialize power capping: {}", e),
+    }
 }
 
 /// Matches the sensor and exporter name and options requested from the command line and
@@ -56,8 +173,78 @@ fn get_sensor(matches: &ArgMatches) -> Box<dyn Sensor> {
 /// This function should be updated to take new exporters into account.
 pub fn run(matches: ArgMatches) {
     loggerv::init_with_verbosity(matches.occurrences_of("v")).unwrap();
+    if matches.is_present("power-cap-policy") {
+        // before any thread is spawned, sensors included, for all of them to
+        // leave SIGINT and SIGTERM to the thread rolling the power limits back
+        capping::block_exit_signals();
+    }
 
-    let sensor_boxed = get_sensor(&matches);
+    let mut sensor_boxed = get_sensor(&matches);
+    if let Some(path) = matches.value_of("record") {
+        sensor_boxed = Box::new(RecordSensor::new(sensor_boxed, path));
+    }
+    let attribution_mode: AttributionMode = matches
+        .value_of("attribution-mode")
+        .unwrap_or("host")
+        .parse()
+        .unwrap();
+    let attribution_model = matches.value_of("attribution-model").unwrap_or("cpu-time");
+    let idle_rule: IdleRule = matches
+        .value_of("idle-power-rule")
+        .unwrap_or("none")
+        .parse()
+        .unwrap();
+    let idle_power_microwatts = matches.value_of("idle-power").map(|watts| {
+        watts
+            .parse::<f64>()
+            .expect("Wrong idle-power value, should be a number of watts")
+            * 1000000.0
+    });
+    if attribution_mode != AttributionMode::Host
+        || attribution_model != "cpu-time"
+        || idle_power_microwatts.is_some()
+    {
+        sensor_boxed = Box::new(AttributionSensor::new(
+            sensor_boxed,
+            attribution_mode,
+            get_attribution_model(attribution_model, idle_rule).unwrap(),
+            matches
+                .value_of("cgroup-root")
+                .unwrap_or(cgroup::DEFAULT_CGROUP_ROOT),
+            idle_power_microwatts,
+        ));
+    }
+    let power_capper = matches
+        .value_of("power-cap-policy")
+        .map(|path| get_power_capper(path, &matches));
+    // restores the original power limits when run returns or panics
+    let _capping_guard = power_capper.clone().map(CappingGuard::new);
+    if let Some(capper) = &power_capper {
+        capper.rollback_on_exit_signals();
+        capper.rollback_on_panic();
+        // the caps are applied by a thread of their own, from a topology of
+        // their own, not on the refreshes of the exporter
+        let interval = get_argument(&matches, "power-cap-interval")
+            .parse()
+            .expect("Wrong power-cap-interval value, should be a number of seconds");
+        match sensor_boxed.generate_topology() {
+            Ok(topology) => {
+                capper.apply_periodically(topology, Duration::from_secs(interval));
+            }
+            Err(e) => panic!("Couldn't get the topology to apply power caps on: {}", e),
+        }
+        sensor_boxed = Box::new(CappingSensor::new(sensor_boxed, capper.clone()));
+    }
+    if matches.is_present("taskstats") {
+        // started after the exit signals are blocked, for its thread to inherit it
+        match TaskstatsListener::start() {
+            Ok(listener) => sensor_boxed = Box::new(TaskstatsSensor::new(sensor_boxed, listener)),
+            Err(e) => warn!(
+                "Couldn't listen to exits of processes from taskstats: {}",
+                e
+            ),
+        }
+    }
     let exporter_parameters;
 
     let mut header = true;
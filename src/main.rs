@@ -1,8 +1,18 @@
 //! Generic sensor and transmission agent for energy consumption related metrics.
 use clap::{crate_authors, crate_version, App, AppSettings, Arg, SubCommand};
-use scaphandre::{get_exporters_options, run};
+use scaphandre::{capping, get_exporters_options, run};
 fn main() {
-    let sensors = ["powercap_rapl"];
+    let sensors = [
+        "powercap_rapl",
+        "amd_rapl",
+        "hwmon",
+        "msr_rapl",
+        "power_supply",
+        "ipmi",
+        "redfish",
+        "replay",
+        "synthetic",
+    ];
     let exporters_options = get_exporters_options();
     let exporters = exporters_options.keys();
     let exporters: Vec<&str> = exporters.into_iter().map(|x| x.as_str()).collect();
@@ -29,9 +39,11 @@ fn main() {
         .arg(
             Arg::with_name("sensor")
                 .value_name("sensor")
-                .help("Sensor module to apply on the host to get energy consumption metrics.")
+                .help("Sensor module to apply on the host to get energy consumption metrics. Several sensors may be combined, as a comma separated list or by repeating the option, by order of precedence: CPU sockets come from the first sensor providing some, the host consumption from the first one measuring the whole host (the sum of the sockets otherwise), platform domains from the first one providing some, and components from all of them.")
                 .required(false)
                 .takes_value(true)
+                .multiple(true)
+                .require_delimiter(true)
                 .default_value("powercap_rapl")
                 .possible_values(&sensors)
                 .short("s")
@@ -50,6 +62,173 @@ fn main() {
                 .required(false)
                 .takes_value(true)
                 .default_value("1")
+        ).arg(
+            Arg::with_name("sensor-hwmon-host-channel")
+                .value_name("sensor-hwmon-host-channel")
+                .help("hwmon channel measuring the whole host power, as <device name>/<label> or hwmonX/powerY (hwmon sensor only). Defaults to the acpi_power_meter channel.")
+                .long("sensor-hwmon-host-channel")
+                .required(false)
+                .takes_value(true)
+        ).arg(
+            Arg::with_name("sensor-ipmi-command")
+                .value_name("sensor-ipmi-command")
+                .help("Command printing the DCMI power reading of the host, in the ipmitool format (ipmi sensor only).")
+                .long("sensor-ipmi-command")
+                .required(false)
+                .takes_value(true)
+                .default_value("ipmitool dcmi power reading")
+        ).arg(
+            Arg::with_name("sensor-redfish-url")
+                .value_name("sensor-redfish-url")
+                .help("Scheme and host of the BMC to query, like https://bmc.example.org (redfish sensor only).")
+                .long("sensor-redfish-url")
+                .required(false)
+                .takes_value(true)
+        ).arg(
+            Arg::with_name("sensor-redfish-username")
+                .value_name("sensor-redfish-username")
+                .help("Username used to authenticate on the BMC (redfish sensor only).")
+                .long("sensor-redfish-username")
+                .required(false)
+                .takes_value(true)
+        ).arg(
+            Arg::with_name("sensor-redfish-password")
+                .value_name("sensor-redfish-password")
+                .help("Password used to authenticate on the BMC (redfish sensor only). Read from SCAPHANDRE_REDFISH_PASSWORD environment variable if not set.")
+                .long("sensor-redfish-password")
+                .required(false)
+                .takes_value(true)
+        ).arg(
+            Arg::with_name("sensor-redfish-session")
+                .value_name("sensor-redfish-session")
+                .help("Authenticate with a Redfish session token instead of basic auth (redfish sensor only).")
+                .long("sensor-redfish-session")
+                .required(false)
+                .takes_value(false)
+        ).arg(
+            Arg::with_name("sensor-redfish-insecure")
+                .value_name("sensor-redfish-insecure")
+                .help("Accept invalid TLS certificates from the BMC (redfish sensor only).")
+                .long("sensor-redfish-insecure")
+                .required(false)
+                .takes_value(false)
+        ).arg(
+            Arg::with_name("sensor-replay-path")
+                .value_name("sensor-replay-path")
+                .help("Trace file to replay, recorded with --record (replay sensor only).")
+                .long("sensor-replay-path")
+                .required(false)
+                .takes_value(true)
+        ).arg(
+            Arg::with_name("sensor-synthetic-idle-watts")
+                .value_name("sensor-synthetic-idle-watts")
+                .help("Power consumed by the CPUs when idle, in watts (synthetic sensor only).")
+                .long("sensor-synthetic-idle-watts")
+                .required(false)
+                .takes_value(true)
+                .default_value("20")
+        ).arg(
+            Arg::with_name("sensor-synthetic-max-watts")
+                .value_name("sensor-synthetic-max-watts")
+                .help("Power consumed by the CPUs when fully used, in watts (synthetic sensor only).")
+                .long("sensor-synthetic-max-watts")
+                .required(false)
+                .takes_value(true)
+                .default_value("120")
+        ).arg(
+            Arg::with_name("sensor-synthetic-curve-exponent")
+                .value_name("sensor-synthetic-curve-exponent")
+                .help("Exponent applied to the CPU utilization between idle and max power, 1 being linear (synthetic sensor only).")
+                .long("sensor-synthetic-curve-exponent")
+                .required(false)
+                .takes_value(true)
+                .default_value("1")
+        ).arg(
+            Arg::with_name("record")
+                .value_name("record")
+                .help("Records the measurements made by the sensor to a trace file, that can be replayed with the replay sensor.")
+                .long("record")
+                .required(false)
+                .takes_value(true)
+        ).arg(
+            Arg::with_name("power-cap-policy")
+                .value_name("power-cap-policy")
+                .help("Path to a JSON power capping policy. The power limits of the CPU sockets are written according to this policy, and restored on exit.")
+                .long("power-cap-policy")
+                .required(false)
+                .takes_value(true)
+        ).arg(
+            Arg::with_name("power-cap-dry-run")
+                .value_name("power-cap-dry-run")
+                .help("Computes and logs the power caps without writing them.")
+                .long("power-cap-dry-run")
+                .required(false)
+                .takes_value(false)
+        ).arg(
+            Arg::with_name("power-cap-powercap-path")
+                .value_name("power-cap-powercap-path")
+                .help("Path of the powercap folder whose zones are capped.")
+                .long("power-cap-powercap-path")
+                .required(false)
+                .takes_value(true)
+                .default_value(capping::DEFAULT_POWERCAP_PATH)
+        ).arg(
+            Arg::with_name("power-cap-interval")
+                .value_name("power-cap-interval")
+                .help("Number of seconds between two applications of the power capping policy, whatever the pace of the exporter.")
+                .long("power-cap-interval")
+                .required(false)
+                .takes_value(true)
+                .default_value(capping::DEFAULT_CAPPING_INTERVAL_SECS)
+        ).arg(
+            Arg::with_name("attribution-mode")
+                .value_name("attribution-mode")
+                .help("How the power is attributed to processes: from the host power and CPU time (host), or socket by socket, from the CPUs their threads ran on (socket), or to cgroups instead of processes, from their cpu.stat file (cgroup, needs cgroup v2).")
+                .long("attribution-mode")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["host", "socket", "cgroup"])
+                .default_value("host")
+        ).arg(
+            Arg::with_name("attribution-model")
+                .value_name("attribution-model")
+                .help("How the power is split between processes or cgroups: by their share of the busy CPU time (cpu-time), the idle part of the power being split evenly (idle-even), weighting the CPU time by the frequency of the cores (frequency), or splitting only the dynamic part of the power by CPU time, the idle part being split by --idle-power-rule (dynamic).")
+                .long("attribution-model")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&scaphandre::sensors::attribution::ATTRIBUTION_MODELS)
+                .default_value("cpu-time")
+        ).arg(
+            Arg::with_name("idle-power-rule")
+                .value_name("idle-power-rule")
+                .help("With --attribution-model dynamic, how the idle power is split: not at all (none), evenly (even), or by the number of CPUs each process or cgroup may run on (reserved-cpus).")
+                .long("idle-power-rule")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["none", "even", "reserved-cpus"])
+                .default_value("none")
+        ).arg(
+            Arg::with_name("idle-power")
+                .value_name("idle-power")
+                .help("Idle power of the host, in watts. Learned from the power measured while the host is barely used if not set.")
+                .long("idle-power")
+                .required(false)
+                .takes_value(true)
+        ).arg(
+            Arg::with_name("cgroup-root")
+                .value_name("cgroup-root")
+                .help("Path of the cgroup v2 hierarchy, read with --attribution-mode cgroup.")
+                .long("cgroup-root")
+                .required(false)
+                .takes_value(true)
+                .default_value(scaphandre::sensors::cgroup::DEFAULT_CGROUP_ROOT)
+        ).arg(
+            Arg::with_name("taskstats")
+                .value_name("taskstats")
+                .help("Account the CPU time of processes that exit between two measurements, received from the taskstats netlink interface of the kernel (needs the CAP_NET_ADMIN capability).")
+                .long("taskstats")
+                .required(false)
+                .takes_value(false)
         ).arg(
             Arg::with_name("vm")
                 .value_name("vm")
@@ -0,0 +1,289 @@
+use crate::sensors::counters::{EnergyCounter, FileEnergyCounter, SumEnergyCounter};
+use crate::sensors::powercap_rapl::add_rapl_zones;
+use crate::sensors::{read_cpu_socket_id, Sensor, Topology};
+use procfs::modules;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+
+/// Default folder containing hwmon devices, among which the one exposed by amd_energy.
+pub const DEFAULT_HWMON_PATH: &str = "/sys/class/hwmon";
+/// Default folder containing powercap zones (AMD RAPL is exposed there since kernel 5.8).
+pub const DEFAULT_POWERCAP_PATH: &str = "/sys/class/powercap";
+
+/// This is a Sensor type that relies on the amd_energy hwmon driver, or on the
+/// AMD support of the powercap rapl modules, to collect energy consumption
+/// from AMD CPU sockets and their cores.
+pub struct AmdRAPLSensor {
+    hwmon_path: String,
+    powercap_path: String,
+    cpu_sysfs_path: String,
+    buffer_per_socket_max_kbytes: u16,
+    buffer_per_domain_max_kbytes: u16,
+}
+
+impl AmdRAPLSensor {
+    /// Instantiates and returns an instance of AmdRAPLSensor.
+    /// *cpu_sysfs_path* is the folder containing the cpuN folders, used to
+    /// find the socket of each core.
+    pub fn new(
+        buffer_per_socket_max_kbytes: u16,
+        buffer_per_domain_max_kbytes: u16,
+        hwmon_path: &str,
+        powercap_path: &str,
+        cpu_sysfs_path: &str,
+    ) -> AmdRAPLSensor {
+        AmdRAPLSensor {
+            hwmon_path: String::from(hwmon_path),
+            powercap_path: String::from(powercap_path),
+            cpu_sysfs_path: String::from(cpu_sysfs_path),
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
+        }
+    }
+
+    /// Checks if amd_energy or intel_rapl_common modules are present and activated.
+    pub fn check_module() -> Result<String, String> {
+        if let Ok(modules) = modules() {
+            if modules
+                .values()
+                .any(|m| m.name == "amd_energy" || m.name == "intel_rapl_common")
+            {
+                return Ok(String::from(
+                    "amd_energy or intel_rapl_common module found.",
+                ));
+            }
+        }
+        Err(String::from(
+            "None of amd_energy or intel_rapl_common kernel modules found.",
+        ))
+    }
+
+    /// Returns the path of the hwmon device managed by the amd_energy driver, if any.
+    fn find_amd_energy_device(&self) -> Option<String> {
+        if let Ok(devices) = fs::read_dir(&self.hwmon_path) {
+            for device in devices.flatten() {
+                let path = device.path();
+                if let Ok(name) = fs::read_to_string(path.join("name")) {
+                    if name.trim() == "amd_energy" {
+                        return Some(String::from(path.to_str().unwrap()));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Adds sockets to *topo* from the Esocket counters of the amd_energy device,
+    /// with a "core" domain summing the Ecore counters of each socket.
+    fn add_amd_energy_counters(
+        &self,
+        topo: &mut Topology,
+        device: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let re_label = Regex::new(r"^E(socket|core)(\d+)$").unwrap();
+        let mut sockets: BTreeMap<u16, String> = BTreeMap::new();
+        let mut cores: Vec<(u16, String)> = vec![];
+        for entry in fs::read_dir(device)? {
+            let path = entry?.path();
+            let file_name = String::from(path.file_name().unwrap().to_str().unwrap());
+            if let Some(channel) = file_name.strip_suffix("_label") {
+                let label = fs::read_to_string(&path)?;
+                if let Some(captures) = re_label.captures(label.trim()) {
+                    let id = captures[2].parse::<u16>()?;
+                    let input = format!("{}/{}_input", device, channel);
+                    if &captures[1] == "socket" {
+                        sockets.insert(id, input);
+                    } else {
+                        cores.push((id, input));
+                    }
+                }
+            }
+        }
+        if sockets.is_empty() {
+            return Err(From::from(format!(
+                "No socket energy counter found in {}",
+                device
+            )));
+        }
+
+        let nb_sockets = sockets.len();
+        let nb_cores = cores.len();
+        let mut cores_counters: HashMap<u16, Vec<Box<dyn EnergyCounter>>> = HashMap::new();
+        for (core_id, input) in cores {
+            // fall back on an even split of cores between sockets if sysfs doesn't tell
+            let socket_id = read_cpu_socket_id(&self.cpu_sysfs_path, core_id)
+                .unwrap_or((core_id as usize * nb_sockets / nb_cores) as u16);
+            cores_counters
+                .entry(socket_id)
+                .or_default()
+                .push(Box::new(FileEnergyCounter::new(&input)));
+        }
+
+        for (socket_id, input) in sockets {
+            topo.safe_add_socket(
+                socket_id,
+                vec![],
+                vec![],
+                input,
+                self.buffer_per_socket_max_kbytes,
+            );
+            if let Some(counters) = cores_counters.remove(&socket_id) {
+                topo.safe_add_domain_to_socket_with_counter(
+                    socket_id,
+                    0,
+                    "core",
+                    device,
+                    Box::new(SumEnergyCounter::new(counters)),
+                    self.buffer_per_domain_max_kbytes,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds sockets and domains to *topo* from the RAPL powercap zones, as
+    /// exposed for AMD CPUs.
+    fn add_powercap_counters(&self, topo: &mut Topology) -> Result<(), Box<dyn Error>> {
+        add_rapl_zones(
+            topo,
+            &self.powercap_path,
+            self.buffer_per_socket_max_kbytes,
+            self.buffer_per_domain_max_kbytes,
+        )?;
+        if topo.sockets.is_empty() {
+            return Err(From::from(format!(
+                "No AMD RAPL zone found in {}",
+                self.powercap_path
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Sensor for AmdRAPLSensor {
+    /// Creates a Topology instance, from amd_energy counters if the driver is
+    /// loaded, from powercap zones otherwise.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        if AmdRAPLSensor::check_module().is_err() {
+            warn!("Couldn't find amd_energy or intel_rapl_common modules.");
+        }
+        let mut topo = Topology::new();
+        if let Some(device) = self.find_amd_energy_device() {
+            debug!("Using amd_energy counters from {}", device);
+            self.add_amd_energy_counters(&mut topo, &device)?;
+        } else {
+            debug!("amd_energy not found, using powercap zones.");
+            self.add_powercap_counters(&mut topo)?;
+        }
+        topo.add_cpu_cores_from(&self.cpu_sysfs_path, "/proc");
+        Ok(topo)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::test_utils::TempDir;
+    use crate::sensors::RecordGenerator;
+
+    #[test]
+    fn amd_energy_counters_build_sockets_and_core_domain() {
+        let root = TempDir::new("amd");
+        let device = root.join("hwmon3");
+        fs::create_dir_all(&device).unwrap();
+        fs::write(device.join("name"), "amd_energy\n").unwrap();
+        let channels = [
+            ("Ecore000", "1000"),
+            ("Ecore001", "2000"),
+            ("Esocket0", "9000"),
+            ("Esocket1", "7000"),
+        ];
+        // both cores are on the second socket
+        for cpu in 0..2 {
+            let topology = root.join(format!("cpu/cpu{}/topology", cpu));
+            fs::create_dir_all(&topology).unwrap();
+            fs::write(topology.join("physical_package_id"), "1\n").unwrap();
+        }
+        for (i, (label, value)) in channels.iter().enumerate() {
+            fs::write(device.join(format!("energy{}_label", i + 1)), label).unwrap();
+            fs::write(device.join(format!("energy{}_input", i + 1)), value).unwrap();
+        }
+        let hwmon_path = root.to_str().unwrap();
+        let cpu_sysfs_path = root.join("cpu");
+        let sensor = AmdRAPLSensor::new(
+            1,
+            1,
+            hwmon_path,
+            hwmon_path,
+            cpu_sysfs_path.to_str().unwrap(),
+        );
+        let mut topo = sensor.generate_topology().unwrap();
+        assert_eq!(topo.sockets.len(), 2);
+        assert_eq!(topo.domains_names, Some(vec![String::from("core")]));
+        assert!(topo.get_sockets()[0].get_domains_passive().is_empty());
+        let socket = &mut topo.get_sockets()[1];
+        socket.refresh_record();
+        assert_eq!(socket.record_buffer[0].value, "7000");
+        let domain = &mut socket.get_domains()[0];
+        domain.refresh_record();
+        assert_eq!(domain.record_buffer[0].value, "3000");
+        assert_eq!(socket.get_cores_passive().len(), 2);
+    }
+
+    #[test]
+    fn powercap_zones_are_used_without_amd_energy() {
+        let root = TempDir::new("amd-powercap");
+        let hwmon = root.join("hwmon");
+        fs::create_dir_all(hwmon.join("hwmon0")).unwrap();
+        fs::write(hwmon.join("hwmon0/name"), "k10temp\n").unwrap();
+        let zone = root.join("powercap/intel-rapl:0");
+        fs::create_dir_all(&zone).unwrap();
+        fs::write(zone.join("name"), "package-0\n").unwrap();
+        fs::write(zone.join("energy_uj"), "5000\n").unwrap();
+        // the second socket isn't exposed by powercap, its core is left out
+        for cpu in 0..2 {
+            let topology = root.join(format!("cpu/cpu{}/topology", cpu));
+            fs::create_dir_all(&topology).unwrap();
+            fs::write(topology.join("physical_package_id"), format!("{}\n", cpu)).unwrap();
+        }
+        let sensor = AmdRAPLSensor::new(
+            1,
+            1,
+            hwmon.to_str().unwrap(),
+            root.join("powercap").to_str().unwrap(),
+            root.join("cpu").to_str().unwrap(),
+        );
+        let mut topo = sensor.generate_topology().unwrap();
+        assert_eq!(topo.sockets.len(), 1);
+        let socket = &mut topo.get_sockets()[0];
+        socket.refresh_record();
+        assert_eq!(socket.record_buffer[0].value, "5000");
+        let cores: Vec<u16> = socket.get_cores_passive().iter().map(|c| c.id).collect();
+        assert_eq!(cores, vec![0]);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,722 @@
+//! # Attribution: how the measured power is attributed to processes
+//!
+//! With the [AttributionMode::Host] mode, the power of the host is split
+//! between processes according to their share of the host CPU time. With
+//! the [AttributionMode::Socket] mode, the power of each CPU socket is split
+//! according to the share of the CPU time of that socket, the CPU a thread
+//! ran on being found in /proc/PID/task/TID/stat, and what the sockets
+//! don't account for of the host power is split according to the share of
+//! the host CPU time. With the
+//! [AttributionMode::Cgroup] mode, the power of the host is split between
+//! cgroups according to their share of the host CPU time, as found in their
+//! cpu.stat file, instead of between processes.
+//!
+//! In every mode, the share of the power given to each consumer is computed
+//! by an [AttributionModel], from the power and CPU time of the host (or of a
+//! socket) and the CPU time of each consumer between two measurements.
+use crate::sensors::cgroup::CgroupTracker;
+use crate::sensors::utils::ThreadRecord;
+use crate::sensors::{Sensor, Topology};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Rule used to attribute the measured power to processes.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AttributionMode {
+    /// Host power split by the share of the host CPU time used by each process
+    #[default]
+    Host,
+    /// Power of each socket split by the share of the socket CPU time used
+    /// by each process, the power of a process being the sum over sockets
+    Socket,
+    /// Host power split by the share of the host CPU time used by each cgroup,
+    /// processes being not tracked individually
+    Cgroup,
+}
+
+impl FromStr for AttributionMode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<AttributionMode, String> {
+        match name {
+            "host" => Ok(AttributionMode::Host),
+            "socket" => Ok(AttributionMode::Socket),
+            "cgroup" => Ok(AttributionMode::Cgroup),
+            _ => Err(format!("Unknown attribution mode: {}", name)),
+        }
+    }
+}
+
+/// Power and CPU time of the host, or of a socket, between two measurements.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostUsage {
+    /// Power measured, in microwatts
+    pub power_microwatts: f64,
+    /// CPU time spent running tasks, in jiffies
+    pub busy_jiffies: u64,
+    /// CPU time spent idle (or waiting for I/O), in jiffies
+    pub idle_jiffies: u64,
+    /// CPU time spent running tasks by each CPU core, in jiffies, by core id
+    pub cores_busy_jiffies: HashMap<u16, u64>,
+    /// Last frequency of each CPU core, in kHz, by core id
+    pub cores_frequency_khz: HashMap<u16, u64>,
+    /// Estimated idle (static) part of the power, in microwatts, when known
+    pub idle_power_microwatts: Option<f64>,
+}
+
+/// CPU time consumed by a consumer (process, cgroup...) between two measurements.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConsumerUsage {
+    /// CPU time consumed, in jiffies
+    pub cpu_jiffies: u64,
+    /// CPU time consumed on each CPU core, in jiffies, by core id, when known
+    pub cores_jiffies: HashMap<u16, u64>,
+    /// Number of CPUs the consumer is allowed to run on, when known
+    pub reserved_cpus: Option<u16>,
+}
+
+/// Defines how the power measured on the host (or on a socket) is attributed
+/// to the consumers that ran on it.
+pub trait AttributionModel: fmt::Debug + Send + Sync {
+    /// Returns the power attributed to each of the *consumers*, in microwatts,
+    /// in the same order, from the power and CPU time of the *host*.
+    fn attribute(&self, host: &HostUsage, consumers: &[ConsumerUsage]) -> Vec<f64>;
+
+    /// Tells if the model uses the CPU time of consumers on each CPU core,
+    /// which requires reading the threads of every process.
+    fn needs_cores_jiffies(&self) -> bool {
+        false
+    }
+
+    /// Tells if the model uses the number of CPUs reserved by each consumer,
+    /// which requires reading the CPU affinity of every process.
+    fn needs_reserved_cpus(&self) -> bool {
+        false
+    }
+}
+
+/// Attributes the whole power (idle power included) by the share of the busy
+/// CPU time used by each consumer. This is the default model.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuTimeShare;
+
+impl AttributionModel for CpuTimeShare {
+    fn attribute(&self, host: &HostUsage, consumers: &[ConsumerUsage]) -> Vec<f64> {
+        consumers
+            .iter()
+            .map(|c| share(c.cpu_jiffies as f64, host.busy_jiffies as f64) * host.power_microwatts)
+            .collect()
+    }
+}
+
+/// Splits the power between a busy part and an idle part, according to the
+/// busy and idle CPU time of the host. The busy part is attributed by the
+/// share of the busy CPU time used by each consumer, the idle part is split
+/// evenly between consumers.
+#[derive(Debug, Clone, Copy)]
+pub struct EvenIdleSplit;
+
+impl AttributionModel for EvenIdleSplit {
+    fn attribute(&self, host: &HostUsage, consumers: &[ConsumerUsage]) -> Vec<f64> {
+        let busy_ratio = share(
+            host.busy_jiffies as f64,
+            (host.busy_jiffies + host.idle_jiffies) as f64,
+        );
+        let busy_power = host.power_microwatts * busy_ratio;
+        let idle_power_each = (host.power_microwatts - busy_power) / consumers.len().max(1) as f64;
+        consumers
+            .iter()
+            .map(|c| {
+                share(c.cpu_jiffies as f64, host.busy_jiffies as f64) * busy_power + idle_power_each
+            })
+            .collect()
+    }
+}
+
+/// Attributes the whole power by the share of the busy CPU time used by each
+/// consumer, weighted by the frequency of the cores it ran on: a jiffy spent
+/// on a core running at a higher frequency costs more. The cores a process
+/// ran on are found from its threads; without frequency or cores data, this
+/// gives the same result as [CpuTimeShare].
+#[derive(Debug, Clone, Copy)]
+pub struct FrequencyWeighted;
+
+impl AttributionModel for FrequencyWeighted {
+    fn attribute(&self, host: &HostUsage, consumers: &[ConsumerUsage]) -> Vec<f64> {
+        let frequency = |cpu: &u16| host.cores_frequency_khz.get(cpu).map(|f| *f as f64);
+        // average frequency of the busy CPU time, used when the cores are unknown
+        let mut weighted_jiffies = 0.0;
+        let mut known_jiffies = 0.0;
+        for (cpu, jiffies) in &host.cores_busy_jiffies {
+            if let Some(frequency) = frequency(cpu) {
+                weighted_jiffies += *jiffies as f64 * frequency;
+                known_jiffies += *jiffies as f64;
+            }
+        }
+        if weighted_jiffies <= 0.0 {
+            return CpuTimeShare.attribute(host, consumers);
+        }
+        let average_frequency = weighted_jiffies / known_jiffies;
+        let host_weight = host.busy_jiffies as f64 * average_frequency;
+        consumers
+            .iter()
+            .map(|c| {
+                let mut weight = 0.0;
+                let mut cores_jiffies = 0;
+                for (cpu, jiffies) in &c.cores_jiffies {
+                    weight += *jiffies as f64 * frequency(cpu).unwrap_or(average_frequency);
+                    cores_jiffies += jiffies;
+                }
+                // time not found on a core (like the one of exited threads)
+                weight += c.cpu_jiffies.saturating_sub(cores_jiffies) as f64 * average_frequency;
+                share(weight, host_weight) * host.power_microwatts
+            })
+            .collect()
+    }
+
+    fn needs_cores_jiffies(&self) -> bool {
+        true
+    }
+}
+
+/// Rule used to split the idle power between consumers, with [DynamicSplit].
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IdleRule {
+    /// Idle power is not attributed
+    #[default]
+    None,
+    /// Idle power is split evenly between consumers
+    Even,
+    /// Idle power is split according to the number of CPUs each consumer
+    /// is allowed to run on (its CPU affinity, or the cpuset of a cgroup)
+    ReservedCpus,
+}
+
+impl FromStr for IdleRule {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<IdleRule, String> {
+        match name {
+            "none" => Ok(IdleRule::None),
+            "even" => Ok(IdleRule::Even),
+            "reserved-cpus" => Ok(IdleRule::ReservedCpus),
+            _ => Err(format!("Unknown idle power rule: {}", name)),
+        }
+    }
+}
+
+/// Attributes only the dynamic part of the power (the power measured minus
+/// the estimated idle power of the host) by the share of the busy CPU time
+/// used by each consumer. The idle power is split according to *idle_rule*.
+/// Without idle power estimation yet, this gives the same result as
+/// [CpuTimeShare].
+#[derive(Debug, Clone, Copy)]
+pub struct DynamicSplit {
+    pub idle_rule: IdleRule,
+}
+
+impl AttributionModel for DynamicSplit {
+    fn attribute(&self, host: &HostUsage, consumers: &[ConsumerUsage]) -> Vec<f64> {
+        let idle_power = match host.idle_power_microwatts {
+            Some(idle_power) => idle_power.min(host.power_microwatts),
+            None => return CpuTimeShare.attribute(host, consumers),
+        };
+        let dynamic_power = host.power_microwatts - idle_power;
+        // weight of each consumer in the idle power
+        let weights: Vec<f64> = consumers
+            .iter()
+            .map(|c| match self.idle_rule {
+                IdleRule::None => 0.0,
+                IdleRule::Even => 1.0,
+                IdleRule::ReservedCpus => c
+                    .reserved_cpus
+                    .map_or(host.cores_busy_jiffies.len().max(1) as f64, f64::from),
+            })
+            .collect();
+        let total_weight: f64 = weights.iter().sum();
+        consumers
+            .iter()
+            .zip(weights)
+            .map(|(c, weight)| {
+                share(c.cpu_jiffies as f64, host.busy_jiffies as f64) * dynamic_power
+                    + share(weight, total_weight) * idle_power
+            })
+            .collect()
+    }
+
+    fn needs_reserved_cpus(&self) -> bool {
+        self.idle_rule == IdleRule::ReservedCpus
+    }
+}
+
+/// Utilization of the host (between 0 and 1) under which a measurement is
+/// used to learn the idle power.
+const IDLE_MAX_UTILIZATION: f64 = 0.1;
+/// Number of low utilization measurements kept to learn the idle power.
+const IDLE_MAX_SAMPLES: usize = 300;
+/// Number of low utilization measurements needed before the idle power is known.
+const IDLE_MIN_SAMPLES: usize = 3;
+
+/// Estimates the idle (static) power of the host, that is consumed even when
+/// no task is running. The idle power is either configured, or learned as
+/// the median of the power measured while the host was barely used.
+#[derive(Debug, Clone, Default)]
+pub struct IdlePowerEstimator {
+    /// Idle power given by the user, in microwatts
+    pub configured_microwatts: Option<f64>,
+    /// Power measured while the host was barely used, in microwatts, the most recent last
+    samples: Vec<f64>,
+}
+
+impl IdlePowerEstimator {
+    /// Instantiates and returns an IdlePowerEstimator, using *configured_microwatts*
+    /// as the idle power if any, or learning it otherwise.
+    pub fn new(configured_microwatts: Option<f64>) -> IdlePowerEstimator {
+        IdlePowerEstimator {
+            configured_microwatts,
+            samples: vec![],
+        }
+    }
+
+    /// Learns from the power and CPU time of the *host* between two measurements.
+    pub fn add_sample(&mut self, host: &HostUsage) {
+        let total_jiffies = host.busy_jiffies + host.idle_jiffies;
+        if total_jiffies == 0
+            || !host.power_microwatts.is_finite()
+            || share(host.busy_jiffies as f64, total_jiffies as f64) > IDLE_MAX_UTILIZATION
+        {
+            return;
+        }
+        self.samples.push(host.power_microwatts);
+        if self.samples.len() > IDLE_MAX_SAMPLES {
+            self.samples.remove(0);
+        }
+    }
+
+    /// Returns the idle power of the host, in microwatts, if configured or learned.
+    pub fn get_idle_power_microwatts(&self) -> Option<f64> {
+        if self.configured_microwatts.is_some() {
+            return self.configured_microwatts;
+        }
+        if self.samples.len() < IDLE_MIN_SAMPLES {
+            return None;
+        }
+        let mut samples = self.samples.clone();
+        samples.sort_by(f64::total_cmp);
+        Some(samples[samples.len() / 2])
+    }
+}
+
+/// Returns the number of CPUs in a CPU list, as found in
+/// /sys/fs/cgroup/.../cpuset.cpus.effective (like "0-3,8").
+pub fn count_cpu_list(list: &str) -> Option<u16> {
+    let mut count = 0;
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.split('-');
+        let first: u16 = bounds.next()?.parse().ok()?;
+        let last: u16 = match bounds.next() {
+            Some(last) => last.parse().ok()?,
+            None => first,
+        };
+        count += last.checked_sub(first)? + 1;
+    }
+    Some(count)
+}
+
+/// Returns *part* / *total*, or 0 if *total* is 0.
+fn share(part: f64, total: f64) -> f64 {
+    if total > 0.0 {
+        part / total
+    } else {
+        0.0
+    }
+}
+
+/// Names of the built-in attribution models, as accepted by get_attribution_model.
+pub const ATTRIBUTION_MODELS: [&str; 4] = ["cpu-time", "idle-even", "frequency", "dynamic"];
+
+/// Returns the built-in attribution model called *name*. *idle_rule* is
+/// used by the dynamic model.
+pub fn get_attribution_model(
+    name: &str,
+    idle_rule: IdleRule,
+) -> Result<Arc<dyn AttributionModel>, String> {
+    match name {
+        "cpu-time" => Ok(Arc::new(CpuTimeShare)),
+        "idle-even" => Ok(Arc::new(EvenIdleSplit)),
+        "frequency" => Ok(Arc::new(FrequencyWeighted)),
+        "dynamic" => Ok(Arc::new(DynamicSplit { idle_rule })),
+        _ => Err(format!("Unknown attribution model: {}", name)),
+    }
+}
+
+/// Returns the CPU time (in jiffies) consumed by a process between two
+/// measurements, by socket id, from the *last* and *previous* records of its
+/// threads. *process_jiffies* is the CPU time consumed by the whole process
+/// and *cpus_sockets* gives the socket id of each CPU.
+/// The time of threads that terminated in between, which is only known at
+/// the process level, is attributed to the socket of the main thread
+/// (*main_tid*). Returns None if the CPU of none of the threads is known.
+pub fn jiffies_by_socket(
+    last: &[ThreadRecord],
+    previous: &[ThreadRecord],
+    process_jiffies: u64,
+    main_tid: i32,
+    cpus_sockets: &HashMap<u16, u16>,
+) -> Option<HashMap<u16, u64>> {
+    let mut result: HashMap<u16, u64> = HashMap::new();
+    let mut main_socket = None;
+    let mut threads_jiffies = 0;
+    for thread in last {
+        let socket_id = match thread.processor.and_then(|cpu| cpus_sockets.get(&cpu)) {
+            Some(socket_id) => *socket_id,
+            None => continue,
+        };
+        if thread.tid == main_tid || main_socket.is_none() {
+            main_socket = Some(socket_id);
+        }
+        let jiffies = thread_jiffies(thread, previous);
+        threads_jiffies += jiffies;
+        *result.entry(socket_id).or_insert(0) += jiffies;
+    }
+    let main_socket = main_socket?;
+    let remaining = process_jiffies.saturating_sub(threads_jiffies);
+    if remaining > 0 {
+        *result.entry(main_socket).or_insert(0) += remaining;
+    }
+    Some(result)
+}
+
+/// Returns the CPU time (in jiffies) consumed by *thread* since the
+/// *previous* records of the threads of its process.
+pub fn thread_jiffies(thread: &ThreadRecord, previous: &[ThreadRecord]) -> u64 {
+    // a thread missing from the previous records started in between
+    let previous_jiffies = previous
+        .iter()
+        .find(|t| t.tid == thread.tid)
+        .map_or(0, |t| t.total_time_jiffies());
+    thread.total_time_jiffies().saturating_sub(previous_jiffies)
+}
+
+/// Returns the power attributed by *model* to each of the *consumers*, in
+/// microwatts, in the same order, with the [AttributionMode::Socket] mode.
+/// Each consumer comes with its CPU time by socket id, when known.
+///
+/// The power of each socket in *sockets* is split between every consumer: a
+/// consumer whose sockets are unknown is deemed to have used each socket as
+/// much as the host did. What the sockets don't account for of the power of
+/// the *host* (like the memory, or the rest of the machine when the host
+/// power is measured at the wall) is then split by the host CPU time, so
+/// that every consumer gets its share of the same whole, whether its sockets
+/// are known or not.
+pub fn attribute_by_socket(
+    model: &dyn AttributionModel,
+    host: Option<&HostUsage>,
+    sockets: &[(u16, HostUsage)],
+    consumers: &[(ConsumerUsage, Option<HashMap<u16, u64>>)],
+    cpus_sockets: &HashMap<u16, u16>,
+) -> Vec<f64> {
+    let mut result = vec![0.0; consumers.len()];
+    let sockets_busy_jiffies: u64 = sockets.iter().map(|(_, s)| s.busy_jiffies).sum();
+    for (socket_id, socket) in sockets {
+        let socket_share = share(socket.busy_jiffies as f64, sockets_busy_jiffies as f64);
+        let socket_consumers: Vec<ConsumerUsage> = consumers
+            .iter()
+            .map(|(usage, by_socket)| ConsumerUsage {
+                cpu_jiffies: match by_socket {
+                    Some(jiffies) => jiffies.get(socket_id).cloned().unwrap_or_default(),
+                    None => (usage.cpu_jiffies as f64 * socket_share).round() as u64,
+                },
+                cores_jiffies: usage
+                    .cores_jiffies
+                    .iter()
+                    .filter(|(cpu, _)| cpus_sockets.get(cpu) == Some(socket_id))
+                    .map(|(cpu, jiffies)| (*cpu, *jiffies))
+                    .collect(),
+                reserved_cpus: usage.reserved_cpus,
+            })
+            .collect();
+        for (power, socket_power) in result
+            .iter_mut()
+            .zip(model.attribute(socket, &socket_consumers))
+        {
+            *power += socket_power;
+        }
+    }
+    if let Some(host) = host {
+        let sockets_power: f64 = sockets.iter().map(|(_, s)| s.power_microwatts).sum();
+        let remainder = host.power_microwatts - sockets_power;
+        if remainder > 0.0 {
+            let rest = HostUsage {
+                power_microwatts: remainder,
+                idle_power_microwatts: host
+                    .idle_power_microwatts
+                    .map(|idle| idle * remainder / host.power_microwatts),
+                ..host.clone()
+            };
+            let host_consumers: Vec<ConsumerUsage> =
+                consumers.iter().map(|(usage, _)| usage.clone()).collect();
+            for (power, rest_power) in result
+                .iter_mut()
+                .zip(model.attribute(&rest, &host_consumers))
+            {
+                *power += rest_power;
+            }
+        }
+    }
+    result
+}
+
+/// Sensor wrapper setting the attribution mode and model of the topology of
+/// the wrapped sensor.
+pub struct AttributionSensor {
+    sensor: Box<dyn Sensor>,
+    mode: AttributionMode,
+    model: Arc<dyn AttributionModel>,
+    /// Path of the cgroup v2 hierarchy, used with the cgroup mode
+    cgroup_root: String,
+    /// Idle power of the host, in microwatts, learned if None
+    idle_power_microwatts: Option<f64>,
+}
+
+impl AttributionSensor {
+    /// Instantiates and returns an instance of AttributionSensor, using
+    /// *mode* and *model* to attribute the power measured by *sensor*. The
+    /// cgroups are read from *cgroup_root* with the cgroup mode. The idle
+    /// power of the host is learned if *idle_power_microwatts* is None.
+    pub fn new(
+        sensor: Box<dyn Sensor>,
+        mode: AttributionMode,
+        model: Arc<dyn AttributionModel>,
+        cgroup_root: &str,
+        idle_power_microwatts: Option<f64>,
+    ) -> AttributionSensor {
+        AttributionSensor {
+            sensor,
+            mode,
+            model,
+            cgroup_root: String::from(cgroup_root),
+            idle_power_microwatts,
+        }
+    }
+
+    /// Sets the attribution mode and model of *topo*.
+    fn set_mode(&self, topo: &mut Topology) {
+        topo.attribution_mode = self.mode;
+        topo.attribution_model = self.model.clone();
+        topo.idle_power = IdlePowerEstimator::new(self.idle_power_microwatts);
+        if self.mode == AttributionMode::Cgroup {
+            topo.cgroup_tracker = Some(CgroupTracker::new(&self.cgroup_root, 5));
+        }
+    }
+}
+
+impl Sensor for AttributionSensor {
+    /// Creates a Topology instance from the wrapped sensor, with the attribution mode set.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut topo = self.sensor.generate_topology()?;
+        self.set_mode(&mut topo);
+        Ok(topo)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let mut topology = *self.sensor.get_topology();
+        if let Some(topo) = topology.as_mut() {
+            self.set_mode(topo);
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thread(tid: i32, processor: u16, jiffies: u64) -> ThreadRecord {
+        ThreadRecord {
+            tid,
+            comm: format!("thread-{}", tid),
+            processor: Some(processor),
+            utime: jiffies,
+            stime: 0,
+        }
+    }
+
+    #[test]
+    fn threads_time_is_split_by_socket() {
+        // cpus 0 and 1 on socket 0, cpus 2 and 3 on socket 1
+        let cpus_sockets = [(0, 0), (1, 0), (2, 1), (3, 1)].iter().cloned().collect();
+        let previous = [thread(10, 0, 100), thread(11, 2, 50), thread(12, 3, 10)];
+        // thread 12 terminated after 5 more jiffies, thread 13 started
+        let last = [thread(10, 1, 130), thread(11, 3, 70), thread(13, 2, 15)];
+        let jiffies = jiffies_by_socket(&last, &previous, 70, 10, &cpus_sockets).unwrap();
+        assert_eq!(jiffies.get(&0), Some(&35));
+        assert_eq!(jiffies.get(&1), Some(&35));
+
+        let unknown_cpus = HashMap::new();
+        assert_eq!(
+            jiffies_by_socket(&last, &previous, 70, 10, &unknown_cpus),
+            None
+        );
+        assert_eq!(
+            "socket".parse::<AttributionMode>(),
+            Ok(AttributionMode::Socket)
+        );
+    }
+
+    #[test]
+    fn models_attribute_host_power() {
+        let host = HostUsage {
+            power_microwatts: 40000000.0,
+            busy_jiffies: 100,
+            idle_jiffies: 300,
+            cores_busy_jiffies: [(0, 80), (1, 20)].iter().cloned().collect(),
+            cores_frequency_khz: [(0, 3000000), (1, 1000000)].iter().cloned().collect(),
+            idle_power_microwatts: None,
+        };
+        let consumers = [
+            ConsumerUsage {
+                cpu_jiffies: 80,
+                cores_jiffies: [(0, 80)].iter().cloned().collect(),
+                reserved_cpus: None,
+            },
+            ConsumerUsage {
+                cpu_jiffies: 20,
+                cores_jiffies: [(1, 20)].iter().cloned().collect(),
+                reserved_cpus: Some(1),
+            },
+        ];
+        let attribute = |model: &str| -> Vec<u64> {
+            get_attribution_model(model, IdleRule::None)
+                .unwrap()
+                .attribute(&host, &consumers)
+                .iter()
+                .map(|power| power.round() as u64)
+                .collect()
+        };
+        assert_eq!(attribute("cpu-time"), vec![32000000, 8000000]);
+        // 10 W of busy power by CPU time, 30 W of idle power split evenly
+        assert_eq!(attribute("idle-even"), vec![23000000, 17000000]);
+        // 80 jiffies at 3 GHz against 20 jiffies at 1 GHz
+        assert_eq!(attribute("frequency"), vec![36923077, 3076923]);
+    }
+
+    #[test]
+    fn idle_power_is_learned_and_split_apart() {
+        let mut estimator = IdlePowerEstimator::new(None);
+        let mut host = HostUsage {
+            power_microwatts: 10000000.0,
+            busy_jiffies: 5,
+            idle_jiffies: 195,
+            cores_busy_jiffies: [(0, 3), (1, 2)].iter().cloned().collect(),
+            ..Default::default()
+        };
+        for power in [12000000.0, 10000000.0, 11000000.0] {
+            host.power_microwatts = power;
+            estimator.add_sample(&host);
+        }
+        // this one is not a power
+        host.power_microwatts = f64::NAN;
+        estimator.add_sample(&host);
+        // this sample is too busy to be learned from
+        host.power_microwatts = 30000000.0;
+        host.busy_jiffies = 100;
+        host.idle_jiffies = 100;
+        estimator.add_sample(&host);
+        assert_eq!(estimator.get_idle_power_microwatts(), Some(11000000.0));
+
+        host.idle_power_microwatts = estimator.get_idle_power_microwatts();
+        let consumers = [
+            ConsumerUsage {
+                cpu_jiffies: 75,
+                reserved_cpus: Some(2),
+                ..Default::default()
+            },
+            ConsumerUsage {
+                cpu_jiffies: 25,
+                reserved_cpus: Some(6),
+                ..Default::default()
+            },
+        ];
+        let attribute = |idle_rule| -> Vec<u64> {
+            DynamicSplit { idle_rule }
+                .attribute(&host, &consumers)
+                .iter()
+                .map(|power| power.round() as u64)
+                .collect()
+        };
+        // 19 W of dynamic power split by CPU time
+        assert_eq!(attribute(IdleRule::None), vec![14250000, 4750000]);
+        assert_eq!(attribute(IdleRule::Even), vec![19750000, 10250000]);
+        assert_eq!(attribute(IdleRule::ReservedCpus), vec![17000000, 13000000]);
+        assert_eq!(count_cpu_list("0-3,8\n"), Some(5));
+    }
+
+    #[test]
+    fn sockets_and_host_rest_are_attributed_to_every_process() {
+        let cpus_sockets = [(0, 0), (1, 1)].iter().cloned().collect();
+        // 30 W on socket 0 and 10 W on socket 1, out of 60 W at the wall
+        let socket = |power_microwatts, busy_jiffies| HostUsage {
+            power_microwatts,
+            busy_jiffies,
+            idle_jiffies: 100 - busy_jiffies,
+            ..Default::default()
+        };
+        let sockets = [(0, socket(30000000.0, 60)), (1, socket(10000000.0, 20))];
+        let host = HostUsage {
+            power_microwatts: 60000000.0,
+            busy_jiffies: 80,
+            idle_jiffies: 120,
+            ..Default::default()
+        };
+        let consumers = [
+            // threads seen on socket 0 only
+            (
+                ConsumerUsage {
+                    cpu_jiffies: 40,
+                    ..Default::default()
+                },
+                Some([(0, 40)].iter().cloned().collect()),
+            ),
+            // threads not read: its time is spread as the one of the host
+            (
+                ConsumerUsage {
+                    cpu_jiffies: 40,
+                    ..Default::default()
+                },
+                None,
+            ),
+        ];
+        let powers: Vec<u64> = attribute_by_socket(
+            &CpuTimeShare,
+            Some(&host),
+            &sockets,
+            &consumers,
+            &cpus_sockets,
+        )
+        .iter()
+        .map(|power| power.round() as u64)
+        .collect();
+        // 20 W of socket 0 and 10 W of the rest, against 15 W of socket 0,
+        // 5 W of socket 1 and 10 W of the rest: the whole host power
+        assert_eq!(powers, vec![30000000, 30000000]);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
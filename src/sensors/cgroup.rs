@@ -0,0 +1,346 @@
+//! # Cgroup: CPU time accounting per control group
+//!
+//! On hosts using the unified (v2) cgroup hierarchy, the `cpu.stat` file of
+//! each cgroup gives the CPU time (`usage_usec`) consumed by the processes
+//! of that cgroup and its descendants, including the ones that already
+//! terminated. [CgroupTracker] stores those values at each refresh so the
+//! power of the host can be attributed to each cgroup (systemd slices and
+//! services, containers, pods...) without reading every /proc/PID.
+//! As the CPU time of a cgroup includes the one of its descendants, power is
+//! attributed to the CPU time of the processes of each cgroup itself, and the
+//! power of a cgroup sums the one of its subtree (see
+//! [CgroupTracker::attribute_power]).
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::sensors::attribution::{count_cpu_list, AttributionModel, ConsumerUsage, HostUsage};
+use crate::sensors::utils::current_system_time_since_epoch;
+
+/// Default path of the cgroup v2 hierarchy
+pub const DEFAULT_CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// CPU time consumed by a cgroup, as found in its cpu.stat file at a given time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CgroupRecord {
+    pub timestamp: Duration,
+    /// Total CPU time consumed by the cgroup, in microseconds
+    pub usage_usec: u64,
+}
+
+/// Stores the last CgroupRecord instances of each cgroup found under *root*,
+/// the key being the path of the cgroup relative to *root* (like
+/// "/system.slice/ssh.service").
+#[derive(Debug, Clone)]
+pub struct CgroupTracker {
+    /// Path where the cgroup v2 hierarchy is mounted
+    pub root: String,
+    /// Records of each cgroup, the last one first
+    pub cgroups: BTreeMap<String, Vec<CgroupRecord>>,
+    /// Maximum number of CgroupRecord instances kept for each cgroup
+    pub max_records_per_cgroup: u16,
+}
+
+impl CgroupTracker {
+    /// Instantiates CgroupTracker and returns the instance.
+    pub fn new(root: &str, max_records_per_cgroup: u16) -> CgroupTracker {
+        CgroupTracker {
+            root: String::from(root),
+            cgroups: BTreeMap::new(),
+            max_records_per_cgroup,
+        }
+    }
+
+    /// Reads the cpu.stat file of each cgroup and stores the values as new
+    /// records. Cgroups that don't exist anymore are forgotten.
+    pub fn refresh(&mut self) {
+        let usages = match read_cgroups_usage(&self.root) {
+            Ok(usages) => usages,
+            Err(e) => {
+                warn!("Couldn't read cgroups in {}: {}", self.root, e);
+                return;
+            }
+        };
+        let timestamp = current_system_time_since_epoch();
+        self.cgroups.retain(|path, _| usages.contains_key(path));
+        for (path, usage_usec) in usages {
+            let records = self.cgroups.entry(path).or_default();
+            records.insert(
+                0,
+                CgroupRecord {
+                    timestamp,
+                    usage_usec,
+                },
+            );
+            records.truncate(self.max_records_per_cgroup as usize);
+        }
+    }
+
+    /// Returns the paths of the cgroups found on the last refresh.
+    pub fn get_alive_cgroups(&self) -> Vec<&String> {
+        self.cgroups.keys().collect()
+    }
+
+    /// Returns the CPU time consumed by the cgroup *path* between the last
+    /// two refreshes, in microseconds, with the timestamp of the last one.
+    pub fn get_usage_diff_usec(&self, path: &str) -> Option<(Duration, u64)> {
+        match self.cgroups.get(path) {
+            Some(records) if records.len() > 1 => Some((
+                records[0].timestamp,
+                records[0].usage_usec.saturating_sub(records[1].usage_usec),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Returns the paths of the alive cgroups directly below the cgroup *path*.
+    pub fn get_children(&self, path: &str) -> Vec<&String> {
+        let prefix = format!("{}/", path);
+        self.cgroups
+            .range(prefix.clone()..)
+            .map(|(child, _)| child)
+            .take_while(|child| child.starts_with(&prefix))
+            .filter(|child| !child[prefix.len()..].contains('/'))
+            .collect()
+    }
+
+    /// Returns the CPU time consumed by the processes of the cgroup *path*
+    /// itself, the one of its children excluded, between the last two
+    /// refreshes, in microseconds, with the timestamp of the last one.
+    pub fn get_own_usage_diff_usec(&self, path: &str) -> Option<(Duration, u64)> {
+        let (timestamp, usage_usec) = self.get_usage_diff_usec(path)?;
+        let children_usec: u64 = self
+            .get_children(path)
+            .iter()
+            .filter_map(|child| self.get_usage_diff_usec(child))
+            .map(|(_, usage)| usage)
+            .sum();
+        Some((timestamp, usage_usec.saturating_sub(children_usec)))
+    }
+
+    /// Returns the power of each cgroup, in microwatts, with the timestamp of
+    /// its last record, by path. *model* splits the power of the *host* between
+    /// the cgroups running processes of their own (leaf cgroups, mostly), so
+    /// nested cgroups are not counted as peers, and the power of a cgroup is
+    /// then the sum of the one of its subtree.
+    pub fn attribute_power(
+        &self,
+        model: &dyn AttributionModel,
+        host: &HostUsage,
+        ticks_per_second: u64,
+    ) -> BTreeMap<String, (Duration, f64)> {
+        let mut result = BTreeMap::new();
+        let mut consumers_paths = vec![];
+        let mut consumers = vec![];
+        for path in self.get_alive_cgroups() {
+            if let Some((timestamp, usage_usec)) = self.get_own_usage_diff_usec(path) {
+                result.insert(path.clone(), (timestamp, 0.0));
+                // a cgroup that only groups other cgroups consumes nothing itself
+                if usage_usec == 0 && !self.get_children(path).is_empty() {
+                    continue;
+                }
+                // the host CPU time is sampled by jiffies, so the cgroup time is capped to it
+                let jiffies = usage_usec * ticks_per_second / 1000000;
+                let mut reserved_cpus = None;
+                if model.needs_reserved_cpus() {
+                    reserved_cpus = self.read_reserved_cpus(path);
+                }
+                consumers_paths.push(path);
+                consumers.push(ConsumerUsage {
+                    cpu_jiffies: jiffies.min(host.busy_jiffies),
+                    cores_jiffies: HashMap::new(),
+                    reserved_cpus,
+                });
+            }
+        }
+        let powers = model.attribute(host, &consumers);
+        for (path, power) in consumers_paths.into_iter().zip(powers) {
+            // adds the power to the cgroup and to each of its ancestors
+            let mut ancestor = path.as_str();
+            loop {
+                if let Some((_, total)) = result.get_mut(ancestor) {
+                    *total += power;
+                }
+                match ancestor.rfind('/') {
+                    Some(index) if index > 0 => ancestor = &ancestor[..index],
+                    _ => break,
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the number of CPUs the processes of the cgroup *path* may run
+    /// on, from its cpuset.cpus.effective file.
+    pub fn read_reserved_cpus(&self, path: &str) -> Option<u16> {
+        let content =
+            fs::read_to_string(format!("{}{}/cpuset.cpus.effective", self.root, path)).ok()?;
+        count_cpu_list(&content)
+    }
+}
+
+/// Returns the usage_usec value of the cpu.stat file of each cgroup found
+/// under *root* (the root cgroup excluded), by path relative to *root*.
+pub fn read_cgroups_usage(root: &str) -> Result<BTreeMap<String, u64>, io::Error> {
+    let mut usages = BTreeMap::new();
+    let mut folders = vec![];
+    push_subfolders(Path::new(root), &mut folders)?;
+    while let Some(folder) = folders.pop() {
+        // a cgroup removed while walking the hierarchy is skipped
+        if let Ok(content) = fs::read_to_string(folder.join("cpu.stat")) {
+            if let Some(usage_usec) = parse_usage_usec(&content) {
+                let path = folder.strip_prefix(root).unwrap_or(&folder);
+                usages.insert(format!("/{}", path.display()), usage_usec);
+            }
+        }
+        let _ = push_subfolders(&folder, &mut folders);
+    }
+    Ok(usages)
+}
+
+/// Pushes the folders found in *folder* to *folders*.
+fn push_subfolders(folder: &Path, folders: &mut Vec<std::path::PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(folder)?.flatten() {
+        if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            folders.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Returns the usage_usec field of the *content* of a cpu.stat file.
+pub fn parse_usage_usec(content: &str) -> Option<u64> {
+    content.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some("usage_usec"), Some(value)) => value.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::attribution::{get_attribution_model, IdleRule};
+    use crate::sensors::test_utils::TempDir;
+
+    #[test]
+    fn cgroups_usage_is_tracked() {
+        let root = TempDir::new("cgroup");
+        let write = |path: &str, usage: u64| {
+            let folder = root.join(path);
+            fs::create_dir_all(&folder).unwrap();
+            fs::write(
+                folder.join("cpu.stat"),
+                format!("usage_usec {}\nuser_usec 10\nsystem_usec 5\n", usage),
+            )
+            .unwrap();
+        };
+        write("", 1000000);
+        write("system.slice/ssh.service", 2000);
+        write("kubepods.slice/pod1", 50000);
+        let mut tracker = CgroupTracker::new(root.to_str().unwrap(), 3);
+        tracker.refresh();
+        write("system.slice/ssh.service", 2500);
+        write("kubepods.slice/pod1", 80000);
+        fs::remove_dir_all(root.join("kubepods.slice/pod1")).unwrap();
+        tracker.refresh();
+
+        assert_eq!(
+            tracker.get_alive_cgroups(),
+            vec!["/system.slice/ssh.service"]
+        );
+        assert_eq!(
+            tracker
+                .get_usage_diff_usec("/system.slice/ssh.service")
+                .map(|(_, usage)| usage),
+            Some(500)
+        );
+        assert_eq!(tracker.get_usage_diff_usec("/kubepods.slice/pod1"), None);
+    }
+
+    #[test]
+    fn power_of_nested_cgroups_is_summed() {
+        let root = TempDir::new("cgroup-nested");
+        let write = |path: &str, usage: u64| {
+            let folder = root.join(path);
+            fs::create_dir_all(&folder).unwrap();
+            fs::write(folder.join("cpu.stat"), format!("usage_usec {}\n", usage)).unwrap();
+        };
+        for (path, usage) in [
+            ("system.slice", 0),
+            ("system.slice/a.service", 0),
+            ("system.slice/b.service", 0),
+            ("user.slice", 0),
+        ] {
+            write(path, usage);
+        }
+        let mut tracker = CgroupTracker::new(root.to_str().unwrap(), 3);
+        tracker.refresh();
+        // 30 ms in a.service, 10 ms in b.service and 20 ms in user.slice,
+        // usage_usec of system.slice including the one of its children
+        for (path, usage) in [
+            ("system.slice", 40000),
+            ("system.slice/a.service", 30000),
+            ("system.slice/b.service", 10000),
+            ("user.slice", 20000),
+        ] {
+            write(path, usage);
+        }
+        tracker.refresh();
+        assert_eq!(
+            tracker.get_children("/system.slice"),
+            vec!["/system.slice/a.service", "/system.slice/b.service"]
+        );
+        assert_eq!(
+            tracker
+                .get_own_usage_diff_usec("/system.slice")
+                .map(|(_, usage)| usage),
+            Some(0)
+        );
+
+        let host = HostUsage {
+            power_microwatts: 60000000.0,
+            busy_jiffies: 6,
+            idle_jiffies: 94,
+            ..Default::default()
+        };
+        let powers = |model: &str| -> Vec<(String, u64)> {
+            let model = get_attribution_model(model, IdleRule::None).unwrap();
+            tracker
+                .attribute_power(model.as_ref(), &host, 100)
+                .into_iter()
+                .map(|(path, (_, power))| (path, power.round() as u64))
+                .collect()
+        };
+        let expected = |a: u64, b: u64, user: u64| {
+            vec![
+                (String::from("/system.slice"), a + b),
+                (String::from("/system.slice/a.service"), a),
+                (String::from("/system.slice/b.service"), b),
+                (String::from("/user.slice"), user),
+            ]
+        };
+        assert_eq!(powers("cpu-time"), expected(30000000, 10000000, 20000000));
+        // the idle power (94% of 60 W) is split between the three cgroups
+        // running processes, not between four peers
+        assert_eq!(powers("idle-even"), expected(20600000, 19400000, 20000000));
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,225 @@
+use crate::sensors::counters::{DifferenceEnergyCounter, EnergyCounter, SharedEnergyCounter};
+use crate::sensors::{Sensor, Topology};
+use std::error::Error;
+use std::time::Duration;
+
+/// Name of the component measuring the energy consumed by the host that
+/// isn't measured by its CPU sockets.
+pub const UNATTRIBUTED_COMPONENT: &str = "unattributed";
+
+/// This is a Sensor type that merges the topologies of several sensors,
+/// listed by order of precedence:
+/// - CPU sockets and their domains come from the first sensor providing sockets,
+/// - the host consumption comes from the first sensor providing a host counter
+///   (it's the sum of the sockets otherwise),
+/// - components of all sensors are kept,
+/// - platform domains are taken from the first sensor providing some.
+///
+/// When the host consumption and the sockets come from different sensors,
+/// an "unattributed" component measures the difference between them.
+pub struct CompositeSensor {
+    sensors: Vec<(String, Box<dyn Sensor>)>,
+    buffer_per_component_max_kbytes: u16,
+}
+
+impl CompositeSensor {
+    /// Instantiates and returns an instance of CompositeSensor.
+    /// *sensors* are named sensors, by order of precedence.
+    pub fn new(
+        sensors: Vec<(String, Box<dyn Sensor>)>,
+        buffer_per_component_max_kbytes: u16,
+    ) -> CompositeSensor {
+        CompositeSensor {
+            sensors,
+            buffer_per_component_max_kbytes,
+        }
+    }
+}
+
+impl Sensor for CompositeSensor {
+    /// Creates a Topology instance merging the topologies of all sensors that
+    /// could generate one.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut topologies = vec![];
+        for (name, sensor) in &self.sensors {
+            match sensor.generate_topology() {
+                Ok(topo) => topologies.push((name, topo)),
+                Err(e) => warn!("Couldn't generate topology from sensor {}: {}", name, e),
+            }
+        }
+        if topologies.is_empty() {
+            return Err(From::from("None of the sensors could generate a topology"));
+        }
+
+        let mut result = Topology::new();
+        let mut sockets_source = None;
+        let mut host_source = None;
+        for (index, (name, topo)) in topologies.iter_mut().enumerate() {
+            if sockets_source.is_none() && !topo.sockets.is_empty() {
+                info!("Using CPU sockets from sensor {}.", name);
+                result.sockets = topo.sockets.clone();
+                result.domains_names = topo.domains_names.clone();
+                sockets_source = Some(index);
+            }
+            if result.platforms.is_empty() && !topo.platforms.is_empty() {
+                info!("Using platform domains from sensor {}.", name);
+                result.platforms = topo.platforms.clone();
+            }
+            if host_source.is_none() {
+                if let Some(counter) = topo.host_counter.take() {
+                    info!("Using host consumption from sensor {}.", name);
+                    result.host_counter = Some(counter);
+                    host_source = Some(index);
+                }
+            }
+            for component in &topo.components {
+                result.safe_add_component(
+                    result.components.len() as u16,
+                    &component.name,
+                    &component.counter_uj_path,
+                    component.counter.clone(),
+                    component.buffer_max_kbytes,
+                );
+            }
+        }
+
+        if let (Some(sockets_index), Some(host_index)) = (sockets_source, host_source) {
+            if sockets_index != host_index {
+                // the host counter is read by the topology and by the unattributed
+                // component during the same refresh, it has to be read only once
+                let host_counter = SharedEnergyCounter::new(
+                    result.host_counter.take().unwrap(),
+                    Duration::from_millis(500),
+                );
+                let sockets_counters: Vec<Box<dyn EnergyCounter>> =
+                    result.sockets.iter().map(|s| s.counter.clone()).collect();
+                result.safe_add_component(
+                    result.components.len() as u16,
+                    UNATTRIBUTED_COMPONENT,
+                    "",
+                    Box::new(DifferenceEnergyCounter::new(
+                        Box::new(host_counter.clone()),
+                        sockets_counters,
+                    )),
+                    self.buffer_per_component_max_kbytes,
+                );
+                result.host_counter = Some(Box::new(host_counter));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::counters::FileEnergyCounter;
+    use crate::sensors::test_utils::TempDir;
+    use crate::sensors::RecordGenerator;
+    use std::fs;
+
+    /// Sensor returning a topology with the given sockets counters, components
+    /// counters and host counter, all read from files in *dir*.
+    struct FakeSensor {
+        dir: String,
+        sockets: Vec<&'static str>,
+        components: Vec<&'static str>,
+        host: Option<&'static str>,
+    }
+
+    impl Sensor for FakeSensor {
+        fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+            let mut topo = Topology::new();
+            for (id, file) in self.sockets.iter().enumerate() {
+                let path = format!("{}/{}", self.dir, file);
+                topo.safe_add_socket(id as u16, vec![], vec![], path, 1);
+            }
+            for (id, file) in self.components.iter().enumerate() {
+                let path = format!("{}/{}", self.dir, file);
+                let counter = Box::new(FileEnergyCounter::new(&path));
+                topo.safe_add_component(id as u16, file, &path, counter, 1);
+            }
+            if let Some(file) = self.host {
+                let path = format!("{}/{}", self.dir, file);
+                topo.host_counter = Some(Box::new(FileEnergyCounter::new(&path)));
+            }
+            Ok(topo)
+        }
+
+        fn get_topology(&mut self) -> Box<Option<Topology>> {
+            Box::new(self.generate_topology().ok())
+        }
+    }
+
+    #[test]
+    fn composite_merges_sockets_host_and_unattributed() {
+        let root = TempDir::new("composite");
+        fs::create_dir_all(&root).unwrap();
+        for (file, value) in [("socket0", "100"), ("wall", "1000"), ("psu", "10")].iter() {
+            fs::write(root.join(file), value).unwrap();
+        }
+        let dir = String::from(root.to_str().unwrap());
+        let rapl = FakeSensor {
+            dir: dir.clone(),
+            sockets: vec!["socket0"],
+            components: vec![],
+            host: None,
+        };
+        let wall = FakeSensor {
+            dir: dir.clone(),
+            sockets: vec![],
+            components: vec!["psu"],
+            host: Some("wall"),
+        };
+        let sensor = CompositeSensor::new(
+            vec![
+                (String::from("rapl"), Box::new(rapl)),
+                (String::from("wall"), Box::new(wall)),
+            ],
+            1,
+        );
+        let mut topo = sensor.generate_topology().unwrap();
+        assert_eq!(topo.sockets.len(), 1);
+        let names: Vec<&str> = topo.components.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["psu", UNATTRIBUTED_COMPONENT]);
+        topo.refresh_record();
+        assert_eq!(topo.record_buffer[0].value, "1000");
+        topo.get_components()[1].refresh_record();
+        assert_eq!(topo.components[1].record_buffer[0].value, "0");
+
+        // without a host counter, there is nothing to reconcile
+        let rapl = FakeSensor {
+            dir,
+            sockets: vec!["socket0"],
+            components: vec![],
+            host: None,
+        };
+        let sensor = CompositeSensor::new(vec![(String::from("rapl"), Box::new(rapl))], 1);
+        let topo = sensor.generate_topology().unwrap();
+        assert!(topo.host_counter.is_none());
+        assert!(topo.components.is_empty());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,503 @@
+//! # Counters
+//!
+//! Energy counters read by [CPUSocket](crate::sensors::CPUSocket) and
+//! [Domain](crate::sensors::Domain) instances each time their records are refreshed.
+//! Powercap-like sensors rely on [FileEnergyCounter], other sensors may implement
+//! [EnergyCounter] to expose their measurements the same way.
+//! Sources measuring power rather than energy implement [PowerMeter] and are
+//! wrapped in an [IntegratedPowerCounter].
+use crate::sensors::utils::current_system_time_since_epoch;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// EnergyCounter trait, implemented by anything able to provide a cumulative
+/// energy consumption value, in microjoules.
+pub trait EnergyCounter: fmt::Debug + Send {
+    /// Returns the current value of the counter, in microjoules.
+    fn read_uj(&mut self) -> Result<u64, Box<dyn Error>>;
+    /// Returns a boxed copy of the counter, so that structs owning counters stay clonable.
+    fn box_clone(&self) -> Box<dyn EnergyCounter>;
+}
+
+impl Clone for Box<dyn EnergyCounter> {
+    fn clone(&self) -> Box<dyn EnergyCounter> {
+        self.box_clone()
+    }
+}
+
+/// Counter stored in a file containing a number of microjoules, like powercap's
+/// energy_uj files or hwmon's energyN_input files.
+#[derive(Debug, Clone)]
+pub struct FileEnergyCounter {
+    /// Path to the file containing the counter value
+    pub path: String,
+}
+
+impl FileEnergyCounter {
+    /// Instantiates FileEnergyCounter and returns the instance.
+    pub fn new(path: &str) -> FileEnergyCounter {
+        FileEnergyCounter {
+            path: String::from(path),
+        }
+    }
+}
+
+impl EnergyCounter for FileEnergyCounter {
+    fn read_uj(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(fs::read_to_string(&self.path)?.trim().parse::<u64>()?)
+    }
+
+    fn box_clone(&self) -> Box<dyn EnergyCounter> {
+        Box::new(self.clone())
+    }
+}
+
+/// Maximum power assumed for a counter whose maximum power is unknown, in
+/// microwatts, to tell a wraparound from a reset.
+pub const DEFAULT_MAX_POWER_UW: u64 = 2_000_000_000;
+
+/// Counter wrapping another counter that overflows once it reaches
+/// *max_range_uj*, like powercap's energy_uj files, and accumulating its
+/// increments so that the value returned never goes backwards.
+#[derive(Debug, Clone)]
+pub struct WrappingEnergyCounter {
+    /// Underlying counter
+    pub counter: Box<dyn EnergyCounter>,
+    /// Value after which the underlying counter restarts from 0, in microjoules
+    pub max_range_uj: u64,
+    /// Highest power the counter may measure, in microwatts
+    pub max_power_uw: u64,
+    /// Previous value of the underlying counter and the time it was read
+    last_raw_uj: Option<(Duration, u64)>,
+    /// Energy accumulated so far, in microjoules
+    energy_uj: u64,
+}
+
+impl WrappingEnergyCounter {
+    /// Instantiates WrappingEnergyCounter and returns the instance.
+    /// Its first value is the value of the underlying counter.
+    pub fn new(
+        counter: Box<dyn EnergyCounter>,
+        max_range_uj: u64,
+        max_power_uw: u64,
+    ) -> WrappingEnergyCounter {
+        WrappingEnergyCounter {
+            counter,
+            max_range_uj,
+            max_power_uw,
+            last_raw_uj: None,
+            energy_uj: 0,
+        }
+    }
+
+    /// Reads the underlying counter, adds the energy consumed since the
+    /// previous read, at *timestamp*, and returns the new value of the counter.
+    /// When the underlying counter went backwards, the energy consumed through
+    /// a wraparound is added if the host could have consumed it since the
+    /// previous read at max_power_uw. The counter has been reset otherwise
+    /// (module reload, resume from suspend...) and the interval is ignored.
+    pub fn read_at(&mut self, timestamp: Duration) -> Result<u64, Box<dyn Error>> {
+        let raw = self.counter.read_uj()?;
+        match self.last_raw_uj {
+            None => self.energy_uj = raw,
+            Some((_, last_raw)) if raw >= last_raw => self.energy_uj += raw - last_raw,
+            Some((last_timestamp, last_raw)) => {
+                let wrapped_uj = self.max_range_uj.saturating_sub(last_raw) + raw;
+                let seconds = timestamp.saturating_sub(last_timestamp).as_secs_f64();
+                if wrapped_uj as f64 <= self.max_power_uw as f64 * seconds {
+                    self.energy_uj += wrapped_uj;
+                } else {
+                    warn!(
+                        "Energy counter {:?} has been reset (from {} to {} uJ), ignoring this measurement interval.",
+                        self.counter, last_raw, raw
+                    );
+                }
+            }
+        }
+        self.last_raw_uj = Some((timestamp, raw));
+        Ok(self.energy_uj)
+    }
+}
+
+impl EnergyCounter for WrappingEnergyCounter {
+    fn read_uj(&mut self) -> Result<u64, Box<dyn Error>> {
+        self.read_at(current_system_time_since_epoch())
+    }
+
+    fn box_clone(&self) -> Box<dyn EnergyCounter> {
+        Box::new(self.clone())
+    }
+}
+
+/// Returns the highest power the powercap zone found at *zone_path* may
+/// consume, in microwatts: twice the highest of its max_power_range_uw and of
+/// the maximum power and power limit of its constraints, as the power may
+/// exceed them for short periods. DEFAULT_MAX_POWER_UW if none is found.
+pub fn read_zone_max_power_uw(zone_path: &str) -> u64 {
+    let mut max_power_uw = None;
+    if let Ok(entries) = fs::read_dir(zone_path) {
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let name = name.to_str().unwrap_or_default();
+            let is_constraint = name.starts_with("constraint_")
+                && (name.ends_with("_max_power_uw") || name.ends_with("_power_limit_uw"));
+            if name == "max_power_range_uw" || is_constraint {
+                if let Ok(Ok(value)) =
+                    fs::read_to_string(entry.path()).map(|v| v.trim().parse::<u64>())
+                {
+                    max_power_uw = max_power_uw.max(Some(value));
+                }
+            }
+        }
+    }
+    match max_power_uw {
+        Some(value) if value > 0 => value.saturating_mul(2),
+        _ => DEFAULT_MAX_POWER_UW,
+    }
+}
+
+/// Returns a counter reading the energy_uj file of the powercap zone found
+/// at *zone_path*, handling its wraparound at the max_energy_range_uj value
+/// of the zone. A zone without max_energy_range_uj is read as is.
+pub fn powercap_zone_counter(zone_path: &str) -> Box<dyn EnergyCounter> {
+    let counter = Box::new(FileEnergyCounter::new(&format!("{}/energy_uj", zone_path)));
+    match fs::read_to_string(format!("{}/max_energy_range_uj", zone_path)) {
+        Ok(content) => match content.trim().parse::<u64>() {
+            Ok(max_range_uj) => Box::new(WrappingEnergyCounter::new(
+                counter,
+                max_range_uj,
+                read_zone_max_power_uw(zone_path),
+            )),
+            Err(e) => {
+                warn!("Invalid max_energy_range_uj in {}: {}", zone_path, e);
+                counter
+            }
+        },
+        Err(_) => counter,
+    }
+}
+
+/// Counter whose value is the sum of several other counters
+/// (for instance per-core counters summed up as a socket level domain).
+#[derive(Debug, Clone)]
+pub struct SumEnergyCounter {
+    /// Counters to be summed
+    pub counters: Vec<Box<dyn EnergyCounter>>,
+}
+
+impl SumEnergyCounter {
+    /// Instantiates SumEnergyCounter and returns the instance.
+    pub fn new(counters: Vec<Box<dyn EnergyCounter>>) -> SumEnergyCounter {
+        SumEnergyCounter { counters }
+    }
+}
+
+impl EnergyCounter for SumEnergyCounter {
+    fn read_uj(&mut self) -> Result<u64, Box<dyn Error>> {
+        let mut total = 0;
+        for c in self.counters.iter_mut() {
+            total += c.read_uj()?;
+        }
+        Ok(total)
+    }
+
+    fn box_clone(&self) -> Box<dyn EnergyCounter> {
+        Box::new(self.clone())
+    }
+}
+
+/// Counter whose value is the energy consumed by *total* that isn't consumed
+/// by *parts* (for instance what the wall power measures on top of RAPL),
+/// accumulated from the differences between successive reads. An interval
+/// in which any of the counters goes backwards (reset or overflow) is
+/// skipped, as the energy consumed by the counter that did is unknown.
+#[derive(Debug, Clone)]
+pub struct DifferenceEnergyCounter {
+    /// Counter measuring the whole consumption
+    pub total: Box<dyn EnergyCounter>,
+    /// Counters measuring parts of the whole consumption
+    pub parts: Vec<Box<dyn EnergyCounter>>,
+    /// Energy accumulated so far, in microjoules
+    energy_uj: u64,
+    /// Values of total and of each part at the previous read
+    last_values: Option<(u64, Vec<u64>)>,
+}
+
+impl DifferenceEnergyCounter {
+    /// Instantiates DifferenceEnergyCounter, starting at 0 microjoules, and returns the instance.
+    pub fn new(
+        total: Box<dyn EnergyCounter>,
+        parts: Vec<Box<dyn EnergyCounter>>,
+    ) -> DifferenceEnergyCounter {
+        DifferenceEnergyCounter {
+            total,
+            parts,
+            energy_uj: 0,
+            last_values: None,
+        }
+    }
+}
+
+impl EnergyCounter for DifferenceEnergyCounter {
+    fn read_uj(&mut self) -> Result<u64, Box<dyn Error>> {
+        let total = self.total.read_uj()?;
+        let mut parts = vec![];
+        for p in self.parts.iter_mut() {
+            parts.push(p.read_uj()?);
+        }
+        if let Some((last_total, last_parts)) = &self.last_values {
+            let reset = total < *last_total
+                || parts.len() != last_parts.len()
+                || parts.iter().zip(last_parts).any(|(part, last)| part < last);
+            if reset {
+                debug!("An energy counter went backwards, skipping the interval.");
+            } else {
+                let parts_diff: u64 = parts.iter().zip(last_parts).map(|(p, l)| p - l).sum();
+                self.energy_uj += (total - last_total).saturating_sub(parts_diff);
+            }
+        }
+        self.last_values = Some((total, parts));
+        Ok(self.energy_uj)
+    }
+
+    fn box_clone(&self) -> Box<dyn EnergyCounter> {
+        Box::new(self.clone())
+    }
+}
+
+/// Counter owned by a SharedEnergyCounter, with its last value and the time it was read.
+type SharedCounterState = (Box<dyn EnergyCounter>, Option<(Duration, u64)>);
+
+/// Counter shared between all its clones, reading the underlying counter at
+/// most once per *max_age*, so that several owners (the host and a
+/// reconciliation counter, for instance) may read the same stateful or slow
+/// counter during a refresh.
+#[derive(Debug, Clone)]
+pub struct SharedEnergyCounter {
+    /// Underlying counter and its last value
+    inner: Arc<Mutex<SharedCounterState>>,
+    /// Maximum age of a value to be reused instead of reading the counter again
+    pub max_age: Duration,
+}
+
+impl SharedEnergyCounter {
+    /// Instantiates SharedEnergyCounter and returns the instance.
+    pub fn new(counter: Box<dyn EnergyCounter>, max_age: Duration) -> SharedEnergyCounter {
+        SharedEnergyCounter {
+            inner: Arc::new(Mutex::new((counter, None))),
+            max_age,
+        }
+    }
+}
+
+impl EnergyCounter for SharedEnergyCounter {
+    fn read_uj(&mut self) -> Result<u64, Box<dyn Error>> {
+        let now = current_system_time_since_epoch();
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((timestamp, value)) = inner.1 {
+            if now >= timestamp && now - timestamp < self.max_age {
+                return Ok(value);
+            }
+        }
+        let value = inner.0.read_uj()?;
+        inner.1 = Some((now, value));
+        Ok(value)
+    }
+
+    fn box_clone(&self) -> Box<dyn EnergyCounter> {
+        Box::new(self.clone())
+    }
+}
+
+/// PowerMeter trait, implemented by sources providing instantaneous power
+/// measurements, in microwatts.
+pub trait PowerMeter: fmt::Debug + Clone + Send + 'static {
+    /// Returns the power currently measured, in microwatts.
+    fn read_uw(&mut self) -> Result<u64, Box<dyn Error>>;
+}
+
+/// Power measurement stored in a file containing a number of microwatts,
+/// like hwmon's powerN_input or power_supply's power_now files.
+#[derive(Debug, Clone)]
+pub struct FilePowerMeter {
+    /// Path to the file containing the power value
+    pub path: String,
+}
+
+impl FilePowerMeter {
+    /// Instantiates FilePowerMeter and returns the instance.
+    pub fn new(path: &str) -> FilePowerMeter {
+        FilePowerMeter {
+            path: String::from(path),
+        }
+    }
+}
+
+impl PowerMeter for FilePowerMeter {
+    fn read_uw(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(fs::read_to_string(&self.path)?.trim().parse::<u64>()?)
+    }
+}
+
+/// Counter computing energy consumption by integrating the power measured
+/// by a PowerMeter between two reads (trapezoidal rule).
+#[derive(Debug, Clone)]
+pub struct IntegratedPowerCounter<M: PowerMeter> {
+    /// Source of the power measurements
+    pub meter: M,
+    /// Energy integrated so far, in microjoules
+    energy_uj: f64,
+    /// Timestamp and value, in microwatts, of the previous power measurement
+    last_sample: Option<(Duration, u64)>,
+}
+
+impl<M: PowerMeter> IntegratedPowerCounter<M> {
+    /// Instantiates IntegratedPowerCounter, starting at 0 microjoules, and returns the instance.
+    pub fn new(meter: M) -> IntegratedPowerCounter<M> {
+        IntegratedPowerCounter {
+            meter,
+            energy_uj: 0.0,
+            last_sample: None,
+        }
+    }
+
+    /// Adds the energy consumed since the previous sample to the counter and
+    /// returns its new value, in microjoules.
+    pub fn add_sample(&mut self, timestamp: Duration, power_uw: u64) -> u64 {
+        if let Some((last_timestamp, last_power_uw)) = self.last_sample {
+            if timestamp > last_timestamp {
+                let seconds = (timestamp - last_timestamp).as_secs_f64();
+                self.energy_uj += (last_power_uw + power_uw) as f64 / 2.0 * seconds;
+            }
+        }
+        self.last_sample = Some((timestamp, power_uw));
+        self.energy_uj as u64
+    }
+}
+
+impl<M: PowerMeter> EnergyCounter for IntegratedPowerCounter<M> {
+    fn read_uj(&mut self) -> Result<u64, Box<dyn Error>> {
+        let power_uw = self.meter.read_uw()?;
+        Ok(self.add_sample(current_system_time_since_epoch(), power_uw))
+    }
+
+    fn box_clone(&self) -> Box<dyn EnergyCounter> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::test_utils::TempDir;
+
+    #[test]
+    fn sum_counter_adds_file_counters() {
+        let dir = TempDir::new("counters");
+        fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("energy1_input");
+        let second = dir.join("energy2_input");
+        fs::write(&first, "1000\n").unwrap();
+        fs::write(&second, "2500\n").unwrap();
+        let mut counter = SumEnergyCounter::new(vec![
+            Box::new(FileEnergyCounter::new(first.to_str().unwrap())),
+            Box::new(FileEnergyCounter::new(second.to_str().unwrap())),
+        ]);
+        assert_eq!(counter.read_uj().unwrap(), 3500);
+    }
+
+    #[test]
+    fn difference_counter_accumulates_unattributed_energy() {
+        let dir = TempDir::new("difference");
+        fs::create_dir_all(&dir).unwrap();
+        let total = dir.join("total");
+        let part = dir.join("part");
+        fs::write(&total, "1000").unwrap();
+        fs::write(&part, "500000").unwrap();
+        let mut counter = DifferenceEnergyCounter::new(
+            Box::new(FileEnergyCounter::new(total.to_str().unwrap())),
+            vec![Box::new(FileEnergyCounter::new(part.to_str().unwrap()))],
+        );
+        assert_eq!(counter.read_uj().unwrap(), 0);
+        fs::write(&total, "3000").unwrap();
+        fs::write(&part, "500500").unwrap();
+        assert_eq!(counter.read_uj().unwrap(), 1500);
+        // parts counter overflowed: the interval is skipped
+        fs::write(&total, "4000").unwrap();
+        fs::write(&part, "100").unwrap();
+        assert_eq!(counter.read_uj().unwrap(), 1500);
+        fs::write(&total, "5000").unwrap();
+        fs::write(&part, "300").unwrap();
+        assert_eq!(counter.read_uj().unwrap(), 2300);
+    }
+
+    #[test]
+    fn wrapping_counter_handles_wraparound_and_reset() {
+        let dir = TempDir::new("wrapping");
+        let energy = dir.join("energy_uj");
+        fs::write(&energy, "900").unwrap();
+        fs::write(dir.join("max_energy_range_uj"), "1000").unwrap();
+        assert_eq!(
+            read_zone_max_power_uw(dir.to_str().unwrap()),
+            DEFAULT_MAX_POWER_UW
+        );
+        fs::write(dir.join("constraint_0_power_limit_uw"), "80").unwrap();
+        fs::write(dir.join("constraint_1_power_limit_uw"), "100").unwrap();
+        assert_eq!(read_zone_max_power_uw(dir.to_str().unwrap()), 200);
+        let mut zone_counter = powercap_zone_counter(dir.to_str().unwrap());
+        assert_eq!(zone_counter.read_uj().unwrap(), 900);
+
+        // at most 200uW, 200uJ per second
+        let file_counter = Box::new(FileEnergyCounter::new(energy.to_str().unwrap()));
+        let mut counter = WrappingEnergyCounter::new(file_counter, 1000, 200);
+        let mut read_at = |seconds: u64, raw: &str| {
+            fs::write(&energy, raw).unwrap();
+            counter.read_at(Duration::from_secs(seconds)).unwrap()
+        };
+        assert_eq!(read_at(0, "900"), 900);
+        // wraparound: 100uJ to reach the max range, then 50uJ
+        assert_eq!(read_at(1, "50"), 1050);
+        assert_eq!(read_at(2, "300"), 1300);
+        // reset: the interval is ignored, then the counter goes on
+        assert_eq!(read_at(3, "100"), 1300);
+        assert_eq!(read_at(4, "150"), 1350);
+        // reset from a high value, more than half of the range is lost but
+        // 400uJ can't be consumed in a second
+        assert_eq!(read_at(5, "750"), 1950);
+        assert_eq!(read_at(6, "150"), 1950);
+        // the same drop is a wraparound if it happened in 2 seconds
+        assert_eq!(read_at(7, "750"), 2550);
+        assert_eq!(read_at(9, "150"), 2950);
+    }
+
+    #[test]
+    fn integrated_counter_applies_trapezoidal_rule() {
+        let mut counter = IntegratedPowerCounter::new(FilePowerMeter::new("/nonexistent"));
+        assert_eq!(counter.add_sample(Duration::from_secs(10), 2_000_000), 0);
+        assert_eq!(
+            counter.add_sample(Duration::from_secs(12), 4_000_000),
+            6_000_000
+        );
+        assert_eq!(
+            counter.add_sample(Duration::from_secs(13), 4_000_000),
+            10_000_000
+        );
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
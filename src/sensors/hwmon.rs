@@ -0,0 +1,252 @@
+use crate::sensors::counters::{
+    EnergyCounter, FileEnergyCounter, FilePowerMeter, IntegratedPowerCounter,
+};
+use crate::sensors::{Sensor, Topology};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Default folder containing hwmon devices.
+pub const DEFAULT_HWMON_PATH: &str = "/sys/class/hwmon";
+/// Name of the hwmon driver exposing the power measured by the platform (PSU, BMC),
+/// used as the host power source if none is specified.
+const ACPI_POWER_METER: &str = "acpi_power_meter";
+
+/// Power or energy channel found on a hwmon device.
+#[derive(Debug, Clone)]
+struct HwmonChannel {
+    /// Name of the channel, as "<device name>/<channel label>"
+    name: String,
+    /// Name of the hwmon folder and of the channel, as "hwmonX/powerY"
+    path_name: String,
+    /// Name of the driver exposing the channel
+    device_name: String,
+    /// File read to get the channel's measurements
+    path: String,
+    /// True if the channel provides power (microwatts), false if it provides energy (microjoules)
+    is_power: bool,
+}
+
+/// This is a Sensor type that relies on hwmon devices (power supplies, BMC,
+/// boards...) exposing power (powerN_input, powerN_average) or energy
+/// (energyN_input) channels. Each channel is added to the topology as a
+/// component and one of them is used as the host power source.
+pub struct HwmonSensor {
+    hwmon_path: String,
+    buffer_per_component_max_kbytes: u16,
+    host_channel: Option<String>,
+}
+
+impl HwmonSensor {
+    /// Instantiates and returns an instance of HwmonSensor.
+    /// *host_channel* is the name ("<device name>/<label>" or "hwmonX/powerY")
+    /// of the channel measuring the whole host consumption.
+    pub fn new(
+        buffer_per_component_max_kbytes: u16,
+        hwmon_path: &str,
+        host_channel: Option<String>,
+    ) -> HwmonSensor {
+        HwmonSensor {
+            hwmon_path: String::from(hwmon_path),
+            buffer_per_component_max_kbytes,
+            host_channel,
+        }
+    }
+
+    /// Returns the folder holding the attributes of a hwmon device, which is
+    /// either the hwmon folder itself or its "device" subfolder on older kernels.
+    fn get_attributes_folder(device: &Path) -> Option<String> {
+        for folder in [device.to_path_buf(), device.join("device")].iter() {
+            if folder.join("name").exists() {
+                return Some(String::from(folder.to_str().unwrap()));
+            }
+        }
+        None
+    }
+
+    /// Lists the power and energy channels of all hwmon devices, sorted by
+    /// device and channel number.
+    fn list_channels(&self) -> Result<Vec<HwmonChannel>, Box<dyn Error>> {
+        let re_channel = Regex::new(r"^(power|energy)(\d+)_(input|average)$").unwrap();
+        let mut devices = vec![];
+        for entry in fs::read_dir(&self.hwmon_path)? {
+            devices.push(entry?.path());
+        }
+        devices.sort();
+
+        let mut channels = vec![];
+        for device in devices {
+            let device_folder = match HwmonSensor::get_attributes_folder(&device) {
+                Some(folder) => folder,
+                None => continue,
+            };
+            let hwmon_name = String::from(device.file_name().unwrap().to_str().unwrap());
+            let device_name =
+                String::from(fs::read_to_string(format!("{}/name", device_folder))?.trim());
+            // channels are indexed by kind and number, "input" files take
+            // precedence over "average" ones
+            let mut device_channels: BTreeMap<(String, u16), (String, String)> = BTreeMap::new();
+            for file in fs::read_dir(&device_folder)? {
+                let file_name = String::from(file?.file_name().to_str().unwrap());
+                if let Some(captures) = re_channel.captures(&file_name) {
+                    let key = (String::from(&captures[1]), captures[2].parse::<u16>()?);
+                    let kind = String::from(&captures[3]);
+                    let replace = match device_channels.get(&key) {
+                        Some((existing, _)) => existing != "input",
+                        None => true,
+                    };
+                    if replace {
+                        device_channels.insert(key, (kind, file_name.clone()));
+                    }
+                }
+            }
+            for ((kind, number), (_, file_name)) in device_channels {
+                let channel = format!("{}{}", kind, number);
+                let label = match fs::read_to_string(format!("{}/{}_label", device_folder, channel))
+                {
+                    Ok(label) => String::from(label.trim()),
+                    Err(_) => channel.clone(),
+                };
+                channels.push(HwmonChannel {
+                    name: format!("{}/{}", device_name, label),
+                    path_name: format!("{}/{}", hwmon_name, channel),
+                    device_name: device_name.clone(),
+                    path: format!("{}/{}", device_folder, file_name),
+                    is_power: kind == "power",
+                });
+            }
+        }
+        Ok(channels)
+    }
+
+    /// Returns an energy counter reading the given channel.
+    fn get_counter(channel: &HwmonChannel) -> Box<dyn EnergyCounter> {
+        if channel.is_power {
+            Box::new(IntegratedPowerCounter::new(FilePowerMeter::new(
+                &channel.path,
+            )))
+        } else {
+            Box::new(FileEnergyCounter::new(&channel.path))
+        }
+    }
+
+    /// Returns the index of the channel to be used as the host power source.
+    fn find_host_channel(&self, channels: &[HwmonChannel]) -> Result<usize, Box<dyn Error>> {
+        match &self.host_channel {
+            Some(name) => channels
+                .iter()
+                .position(|c| &c.name == name || &c.path_name == name)
+                .ok_or_else(|| From::from(format!("hwmon channel {} not found", name))),
+            None => {
+                if let Some(index) = channels
+                    .iter()
+                    .position(|c| c.is_power && c.device_name == ACPI_POWER_METER)
+                {
+                    return Ok(index);
+                }
+                warn!(
+                    "No {} device found, using {} as host power source. Use --sensor-hwmon-host-channel to choose another one.",
+                    ACPI_POWER_METER, channels[0].name
+                );
+                Ok(0)
+            }
+        }
+    }
+}
+
+impl Sensor for HwmonSensor {
+    /// Creates a Topology instance with a component per hwmon power or energy channel.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let channels = self.list_channels()?;
+        if channels.is_empty() {
+            return Err(From::from(format!(
+                "No hwmon power or energy channel found in {}",
+                self.hwmon_path
+            )));
+        }
+        let host_index = self.find_host_channel(&channels)?;
+        let mut topo = Topology::new();
+        for (id, channel) in channels.iter().enumerate() {
+            debug!("Adding hwmon channel {} ({})", channel.name, channel.path);
+            topo.safe_add_component(
+                id as u16,
+                &channel.name,
+                &channel.path,
+                HwmonSensor::get_counter(channel),
+                self.buffer_per_component_max_kbytes,
+            );
+        }
+        info!(
+            "Using hwmon channel {} as host power source.",
+            channels[host_index].name
+        );
+        topo.host_counter = Some(HwmonSensor::get_counter(&channels[host_index]));
+        Ok(topo)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::test_utils::TempDir;
+    use crate::sensors::RecordGenerator;
+
+    #[test]
+    fn hwmon_channels_become_components_and_host_counter() {
+        let root = TempDir::new("hwmon");
+        let psu = root.join("hwmon0");
+        let board = root.join("hwmon1");
+        fs::create_dir_all(&psu).unwrap();
+        fs::create_dir_all(&board).unwrap();
+        fs::write(psu.join("name"), "acpi_power_meter\n").unwrap();
+        fs::write(psu.join("power1_average"), "150000000\n").unwrap();
+        fs::write(board.join("name"), "board\n").unwrap();
+        fs::write(board.join("energy1_input"), "42000\n").unwrap();
+        fs::write(board.join("energy1_label"), "DRAM\n").unwrap();
+
+        let hwmon_path = root.to_str().unwrap();
+        let sensor = HwmonSensor::new(1, hwmon_path, None);
+        let mut topo = sensor.generate_topology().unwrap();
+        assert!(topo.sockets.is_empty());
+        let names: Vec<&str> = topo.components.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["acpi_power_meter/power1", "board/DRAM"]);
+        let dram = &mut topo.get_components()[1];
+        dram.refresh_record();
+        assert_eq!(dram.record_buffer[0].value, "42000");
+        topo.refresh_record();
+        assert_eq!(topo.record_buffer.len(), 1);
+
+        let sensor = HwmonSensor::new(1, hwmon_path, Some(String::from("hwmon1/energy1")));
+        let mut topo = sensor.generate_topology().unwrap();
+        topo.refresh_record();
+        assert_eq!(topo.record_buffer[0].value, "42000");
+
+        let sensor = HwmonSensor::new(1, hwmon_path, Some(String::from("psu/unknown")));
+        assert!(sensor.generate_topology().is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,263 @@
+use crate::sensors::counters::{IntegratedPowerCounter, PowerMeter};
+use crate::sensors::utils::current_system_time_since_epoch;
+use crate::sensors::{Sensor, Topology};
+use regex::Regex;
+use std::error::Error;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Default command used to get the power reading from the BMC. ipmitool uses
+/// the in-band interface (/dev/ipmi0) unless told otherwise with its -I option.
+pub const DEFAULT_IPMI_COMMAND: &str = "ipmitool dcmi power reading";
+
+/// Maximum age of a power reading to be reused instead of running the command again
+const READING_MAX_AGE: Duration = Duration::from_millis(500);
+
+/// Power values reported by the DCMI "Get Power Reading" command, in watts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DcmiPowerReading {
+    /// Instantaneous power reading
+    pub instantaneous_watts: u64,
+    /// Average power reading over the sample period, if reported
+    pub average_watts: Option<u64>,
+}
+
+/// Parses the output of `ipmitool dcmi power reading`.
+pub fn parse_dcmi_power_reading(output: &str) -> Result<DcmiPowerReading, Box<dyn Error>> {
+    let re_instantaneous =
+        Regex::new(r"(?m)^\s*Instantaneous power reading:\s*(\d+)\s*Watts").unwrap();
+    let re_average =
+        Regex::new(r"(?m)^\s*Average power reading over sample period:\s*(\d+)\s*Watts").unwrap();
+    let instantaneous_watts = match re_instantaneous.captures(output) {
+        Some(captures) => captures[1].parse::<u64>()?,
+        None => {
+            return Err(From::from(format!(
+                "No instantaneous power reading found in: {}",
+                output
+            )))
+        }
+    };
+    let average_watts = match re_average.captures(output) {
+        Some(captures) => Some(captures[1].parse::<u64>()?),
+        None => None,
+    };
+    Ok(DcmiPowerReading {
+        instantaneous_watts,
+        average_watts,
+    })
+}
+
+/// Last power reading and the time it was read, shared between meters.
+type SharedPowerReading = Arc<Mutex<Option<(Duration, DcmiPowerReading)>>>;
+
+/// PowerMeter running a DCMI power reading command and returning either the
+/// instantaneous or the average power reported.
+#[derive(Debug, Clone)]
+pub struct IpmiPowerMeter {
+    /// Command to run, as the program followed by its arguments
+    pub command: Vec<String>,
+    /// True to return the average power over the BMC sample period instead of the instantaneous one
+    pub average: bool,
+    /// Last power reading, shared with the clones of the meter and the
+    /// meters created by sharing_readings()
+    last_reading: SharedPowerReading,
+}
+
+impl IpmiPowerMeter {
+    /// Instantiates IpmiPowerMeter and returns the instance.
+    /// *command* is split on whitespaces.
+    pub fn new(command: &str, average: bool) -> IpmiPowerMeter {
+        IpmiPowerMeter {
+            command: command.split_whitespace().map(String::from).collect(),
+            average,
+            last_reading: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Returns a meter running the same command, reusing the readings of
+    /// this one, so that the instantaneous and the average power come from
+    /// a single run of the command per refresh.
+    pub fn sharing_readings(&self, average: bool) -> IpmiPowerMeter {
+        IpmiPowerMeter {
+            average,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the last power reading if it is recent enough, runs the
+    /// command otherwise.
+    fn get_power_reading(&self) -> Result<DcmiPowerReading, Box<dyn Error>> {
+        let now = current_system_time_since_epoch();
+        let mut last_reading = self.last_reading.lock().unwrap();
+        if let Some((timestamp, reading)) = last_reading.as_ref() {
+            if now >= *timestamp && now - *timestamp < READING_MAX_AGE {
+                return Ok(reading.clone());
+            }
+        }
+        let reading = self.read_power_reading()?;
+        *last_reading = Some((now, reading.clone()));
+        Ok(reading)
+    }
+
+    /// Runs the command and parses its output.
+    pub fn read_power_reading(&self) -> Result<DcmiPowerReading, Box<dyn Error>> {
+        if self.command.is_empty() {
+            return Err(From::from("IPMI command is empty"));
+        }
+        let output = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .output()?;
+        if !output.status.success() {
+            return Err(From::from(format!(
+                "{} failed: {}",
+                self.command.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        parse_dcmi_power_reading(&String::from_utf8_lossy(&output.stdout))
+    }
+}
+
+impl PowerMeter for IpmiPowerMeter {
+    fn read_uw(&mut self) -> Result<u64, Box<dyn Error>> {
+        let reading = self.get_power_reading()?;
+        let watts = if self.average {
+            reading
+                .average_watts
+                .ok_or("No average power reading reported")?
+        } else {
+            reading.instantaneous_watts
+        };
+        Ok(watts * 1_000_000)
+    }
+}
+
+/// This is a Sensor type that gets the power consumed by the whole host from
+/// the BMC, through the IPMI DCMI "Get Power Reading" command. The instantaneous
+/// power is integrated over time as the host energy consumption.
+pub struct IpmiSensor {
+    command: String,
+    buffer_per_component_max_kbytes: u16,
+}
+
+impl IpmiSensor {
+    /// Instantiates and returns an instance of IpmiSensor.
+    pub fn new(buffer_per_component_max_kbytes: u16, command: &str) -> IpmiSensor {
+        IpmiSensor {
+            command: String::from(command),
+            buffer_per_component_max_kbytes,
+        }
+    }
+}
+
+impl Sensor for IpmiSensor {
+    /// Creates a Topology instance whose host energy consumption comes from the
+    /// BMC instantaneous power readings, with the average power reading as a component.
+    /// Both come from the same reading, the command runs once per refresh.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let meter = IpmiPowerMeter::new(&self.command, false);
+        let reading = meter.read_power_reading()?;
+        debug!("First DCMI power reading: {:?}", reading);
+        let mut topo = Topology::new();
+        if reading.average_watts.is_some() {
+            topo.safe_add_component(
+                0,
+                "dcmi/average",
+                &self.command,
+                Box::new(IntegratedPowerCounter::new(meter.sharing_readings(true))),
+                self.buffer_per_component_max_kbytes,
+            );
+        }
+        topo.host_counter = Some(Box::new(IntegratedPowerCounter::new(meter)));
+        Ok(topo)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::test_utils::TempDir;
+    use crate::sensors::RecordGenerator;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    const IPMITOOL_OUTPUT: &str = "
+    Instantaneous power reading:                   220 Watts
+    Minimum during sampling period:                 90 Watts
+    Maximum during sampling period:                310 Watts
+    Average power reading over sample period:      205 Watts
+    IPMI timestamp:                           Thu Jan  1 00:00:00 2021
+    Sampling period:                          00000005 Seconds.
+    Power reading state is:                   activated
+";
+
+    #[test]
+    fn parse_ipmitool_output() {
+        assert_eq!(
+            parse_dcmi_power_reading(IPMITOOL_OUTPUT).unwrap(),
+            DcmiPowerReading {
+                instantaneous_watts: 220,
+                average_watts: Some(205),
+            }
+        );
+        assert!(parse_dcmi_power_reading("Power reading state is: deactivated").is_err());
+    }
+
+    #[test]
+    fn ipmi_sensor_runs_stub_command() {
+        let root = TempDir::new("ipmi");
+        fs::create_dir_all(&root).unwrap();
+        let stub = root.join("ipmitool");
+        let runs = root.join("runs");
+        fs::write(
+            &stub,
+            format!(
+                "#!/bin/sh\necho run >> {}\ncat <<EOF\n{}\nEOF\n",
+                runs.to_str().unwrap(),
+                IPMITOOL_OUTPUT
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let command = format!("{} dcmi power reading", stub.to_str().unwrap());
+        let mut meter = IpmiPowerMeter::new(&command, false);
+        assert_eq!(meter.read_uw().unwrap(), 220_000_000);
+        let sensor = IpmiSensor::new(1, &command);
+        let mut topo = sensor.generate_topology().unwrap();
+        assert_eq!(topo.components[0].name, "dcmi/average");
+        fs::remove_file(&runs).unwrap();
+        topo.refresh_record();
+        topo.get_components()[0].refresh_record();
+        assert_eq!(topo.record_buffer.len(), 1);
+        // the host and the component share the same reading
+        assert_eq!(fs::read_to_string(&runs).unwrap(), "run\n");
+
+        let sensor = IpmiSensor::new(1, "/nonexistent/ipmitool dcmi power reading");
+        assert!(sensor.generate_topology().is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -3,16 +3,47 @@
 //! `Sensor` is the root for all sensors. It defines the [Sensor] trait
 //! needed to implement a sensor.
 
+pub mod amd_rapl;
+pub mod attribution;
+pub mod cgroup;
+pub mod composite;
+pub mod counters;
+pub mod hwmon;
+pub mod ipmi;
+pub mod msr_rapl;
+pub mod power_supply;
 pub mod powercap_rapl;
+pub mod redfish;
+pub mod replay;
+pub mod synthetic;
+pub mod taskstats;
+#[cfg(test)]
+pub mod test_utils;
 pub mod units;
 pub mod utils;
-use procfs::{process, CpuInfo, CpuTime, KernelStats};
-use std::collections::HashMap;
+use crate::capping::PowerCapper;
+use attribution::{
+    attribute_by_socket, jiffies_by_socket, thread_jiffies, AttributionMode, AttributionModel,
+    ConsumerUsage, CpuTimeShare, HostUsage, IdlePowerEstimator,
+};
+use cgroup::CgroupTracker;
+use counters::{EnergyCounter, FileEnergyCounter};
+use powercap_rapl::PowercapZone;
+use procfs::{process, CpuInfo, KernelStats};
+use regex::Regex;
+use replay::Trace;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
 use std::mem::size_of_val;
+use std::sync::Arc;
 use std::time::Duration;
-use std::{fmt, fs};
-use utils::{current_system_time_since_epoch, ProcessTracker};
+use taskstats::{ExitedProcess, TaskstatsListener};
+use utils::{
+    current_system_time_since_epoch, read_threads, ProcessRecord, ProcessTracker, SystemdUnit,
+    ThreadRecord,
+};
 
 // !!!!!!!!!!!!!!!!! Sensor !!!!!!!!!!!!!!!!!!!!!!!
 /// Sensor trait, the Sensor API.
@@ -48,6 +79,36 @@ pub struct Topology {
     pub buffer_max_kbytes: u16,
     /// Sorted list of all domains names
     pub domains_names: Option<Vec<String>>,
+    /// Power consumers that are not part of a CPU socket (PSU, board, BMC channels...)
+    pub components: Vec<Component>,
+    /// Platform (psys) power domains, measuring the whole SoC/platform rather
+    /// than the CPU sockets only
+    pub platforms: Vec<Component>,
+    /// Counter measuring the energy consumed by the whole host, if the sensor
+    /// provides one. Sockets records are summed up otherwise.
+    pub host_counter: Option<Box<dyn EnergyCounter>>,
+    /// Path of the procfs filesystem processes and CPU stats are read from
+    pub procfs_root: String,
+    /// Trace recorded or replayed on each refresh, if any
+    pub trace: Option<Trace>,
+    /// Power capper whose caps are reported, if any
+    pub power_capper: Option<PowerCapper>,
+    /// Rule used to attribute the measured power to processes
+    pub attribution_mode: AttributionMode,
+    /// Tells if the threads of each process are read on refresh, to get per-thread power
+    pub track_threads: bool,
+    /// CPU time of each cgroup, replacing the tracking of each process when set
+    pub cgroup_tracker: Option<CgroupTracker>,
+    /// Model attributing the measured power to processes or cgroups
+    pub attribution_model: Arc<dyn AttributionModel>,
+    /// Estimation of the idle power of the host
+    pub idle_power: IdlePowerEstimator,
+    /// Listener receiving the processes that exit, if any
+    pub exits_listener: Option<TaskstatsListener>,
+    /// Processes that exited since the previous refresh, with their CPU time
+    pub exited_processes: Vec<ExitedProcess>,
+    /// Power of each process computed on the last refresh, by PID
+    processes_power: HashMap<i32, Record>,
 }
 
 impl RecordGenerator for Topology {
@@ -55,6 +116,21 @@ impl RecordGenerator for Topology {
     /// and returns a clone of this record.
     ///
     fn refresh_record(&mut self) {
+        if let Some(counter) = self.host_counter.as_mut() {
+            match counter.read_uj() {
+                Ok(value) => {
+                    let record = Record::new(
+                        current_system_time_since_epoch(),
+                        value.to_string(),
+                        units::Unit::MicroJoule,
+                    );
+                    self.record_buffer.push(record);
+                    self.clean_old_records();
+                }
+                Err(e) => warn!("Couldn't read host energy counter: {}", e),
+            }
+            return;
+        }
         let mut value: u64 = 0;
         let mut last_timestamp = current_system_time_since_epoch();
         for s in self.get_sockets() {
@@ -140,10 +216,26 @@ impl Topology {
             record_buffer: vec![],
             buffer_max_kbytes: 1,
             domains_names: None,
+            components: vec![],
+            platforms: vec![],
+            host_counter: None,
+            procfs_root: String::from("/proc"),
+            trace: None,
+            power_capper: None,
+            attribution_mode: AttributionMode::Host,
+            track_threads: false,
+            cgroup_tracker: None,
+            attribution_model: Arc::new(CpuTimeShare),
+            idle_power: IdlePowerEstimator::new(None),
+            exits_listener: None,
+            exited_processes: vec![],
+            processes_power: HashMap::new(),
         }
     }
 
-    /// Parses /proc/cpuinfo and creates instances of CPUCore.
+    /// Lists the CPU cores found in sysfs (/proc/cpuinfo if not available)
+    /// and creates instances of CPUCore, attached to their socket and die
+    /// according to /sys/devices/system/cpu/cpuN/topology.
     ///
     ///# Examples
     ///
@@ -158,14 +250,69 @@ impl Topology {
     /// }
     /// ```
     pub fn generate_cpu_cores() -> Result<Vec<CPUCore>, String> {
-        let cpuinfo = CpuInfo::new().unwrap();
-        let mut cores = vec![];
-        for id in 0..(cpuinfo.num_cores() - 1) {
-            let mut info = HashMap::new();
-            for (k, v) in cpuinfo.get_info(id).unwrap().iter() {
-                info.insert(String::from(*k), String::from(*v));
+        Topology::generate_cpu_cores_from(DEFAULT_CPU_SYSFS_PATH, "/proc")
+    }
+
+    /// Same as generate_cpu_cores, with the cpu folder of sysfs being *sysfs_path*
+    /// and procfs being mounted on *procfs_root*.
+    pub fn generate_cpu_cores_from(
+        sysfs_path: &str,
+        procfs_root: &str,
+    ) -> Result<Vec<CPUCore>, String> {
+        // cpuinfo attributes, by processor number
+        let mut cpuinfo_attributes = HashMap::new();
+        if let Ok(file) = File::open(format!("{}/cpuinfo", procfs_root)) {
+            if let Ok(cpuinfo) = CpuInfo::from_reader(file) {
+                for index in 0..cpuinfo.num_cores() {
+                    if let Some(info) = cpuinfo.get_info(index) {
+                        let info: HashMap<String, String> = info
+                            .iter()
+                            .map(|(k, v)| (String::from(*k), String::from(*v)))
+                            .collect();
+                        let processor = info
+                            .get("processor")
+                            .and_then(|p| p.parse::<u16>().ok())
+                            .unwrap_or(index as u16);
+                        cpuinfo_attributes.insert(processor, info);
+                    }
+                }
             }
-            cores.push(CPUCore::new(id as u16, info));
+        }
+
+        let mut ids = vec![];
+        let re_cpu = Regex::new(r"^cpu(\d+)$").unwrap();
+        if let Ok(folders) = fs::read_dir(sysfs_path) {
+            for folder in folders.flatten() {
+                let folder_name = folder.file_name().to_string_lossy().to_string();
+                if let Some(captures) = re_cpu.captures(&folder_name) {
+                    // offline cores have no topology and no line in /proc/stat
+                    let online = fs::read_to_string(folder.path().join("online"))
+                        .map(|v| v.trim() != "0")
+                        .unwrap_or(true);
+                    if online {
+                        ids.push(captures[1].parse::<u16>().unwrap());
+                    }
+                }
+            }
+        }
+        if ids.is_empty() {
+            ids = cpuinfo_attributes.keys().cloned().collect();
+        }
+        if ids.is_empty() {
+            return Err(format!(
+                "No CPU core found in {} nor in {}/cpuinfo",
+                sysfs_path, procfs_root
+            ));
+        }
+        ids.sort_unstable();
+
+        let mut cores = vec![];
+        for id in ids {
+            let attributes = cpuinfo_attributes.remove(&id).unwrap_or_default();
+            let mut core = CPUCore::new(id, attributes);
+            core.sysfs_path = format!("{}/cpu{}", sysfs_path, id);
+            core.read_topology();
+            cores.push(core);
         }
         Ok(cores)
     }
@@ -179,6 +326,29 @@ impl Topology {
         attributes: Vec<Vec<HashMap<String, String>>>,
         counter_uj_path: String,
         buffer_max_kbytes: u16,
+    ) {
+        let counter = Box::new(FileEnergyCounter::new(&counter_uj_path));
+        self.safe_add_socket_with_counter(
+            socket_id,
+            domains,
+            attributes,
+            counter_uj_path,
+            counter,
+            buffer_max_kbytes,
+        );
+    }
+
+    /// Adds a Socket instance, reading its energy consumption from *counter*,
+    /// to self.sockets if and only if the socket id doesn't exist already.
+    /// *counter_uj_path* is kept as a reference to where the counter comes from.
+    pub fn safe_add_socket_with_counter(
+        &mut self,
+        socket_id: u16,
+        domains: Vec<Domain>,
+        attributes: Vec<Vec<HashMap<String, String>>>,
+        counter_uj_path: String,
+        counter: Box<dyn EnergyCounter>,
+        buffer_max_kbytes: u16,
     ) {
         if !self.sockets.iter().any(|s| s.id == socket_id) {
             let socket = CPUSocket::new(
@@ -186,12 +356,70 @@ impl Topology {
                 domains,
                 attributes,
                 counter_uj_path,
+                counter,
                 buffer_max_kbytes,
             );
             self.sockets.push(socket);
         }
     }
 
+    /// Adds a Component instance to self.components if and only if the
+    /// component id doesn't exist already.
+    pub fn safe_add_component(
+        &mut self,
+        component_id: u16,
+        name: &str,
+        uj_counter: &str,
+        counter: Box<dyn EnergyCounter>,
+        buffer_max_kbytes: u16,
+    ) {
+        if !self.components.iter().any(|c| c.id == component_id) {
+            self.components.push(Component::new(
+                component_id,
+                String::from(name),
+                String::from(uj_counter),
+                counter,
+                buffer_max_kbytes,
+            ));
+        }
+    }
+
+    /// Adds a platform power domain, as a Component instance, to self.platforms
+    /// if and only if the platform id doesn't exist already.
+    pub fn safe_add_platform(
+        &mut self,
+        platform_id: u16,
+        name: &str,
+        uj_counter: &str,
+        counter: Box<dyn EnergyCounter>,
+        buffer_max_kbytes: u16,
+    ) {
+        if !self.platforms.iter().any(|p| p.id == platform_id) {
+            self.platforms.push(Component::new(
+                platform_id,
+                String::from(name),
+                String::from(uj_counter),
+                counter,
+                buffer_max_kbytes,
+            ));
+        }
+    }
+
+    /// Returns an immutable reference to self.platforms
+    pub fn get_platforms_passive(&self) -> &Vec<Component> {
+        &self.platforms
+    }
+
+    /// Returns a mutable reference to self.components
+    pub fn get_components(&mut self) -> &mut Vec<Component> {
+        &mut self.components
+    }
+
+    /// Returns an immutable reference to self.components
+    pub fn get_components_passive(&self) -> &Vec<Component> {
+        &self.components
+    }
+
     /// Returns a immutable reference to self.proc_tracker
     pub fn get_proc_tracker(&self) -> &ProcessTracker {
         &self.proc_tracker
@@ -229,6 +457,27 @@ impl Topology {
         name: &str,
         uj_counter: &str,
         buffer_max_kbytes: u16,
+    ) {
+        self.safe_add_domain_to_socket_with_counter(
+            socket_id,
+            domain_id,
+            name,
+            uj_counter,
+            Box::new(FileEnergyCounter::new(uj_counter)),
+            buffer_max_kbytes,
+        );
+    }
+
+    /// Adds a Domain instance, reading its energy consumption from *counter*,
+    /// to a given socket, if and only if the domain id doesn't exist already for the socket.
+    pub fn safe_add_domain_to_socket_with_counter(
+        &mut self,
+        socket_id: u16,
+        domain_id: u16,
+        name: &str,
+        uj_counter: &str,
+        counter: Box<dyn EnergyCounter>,
+        buffer_max_kbytes: u16,
     ) {
         let iterator = self.sockets.iter_mut();
         for socket in iterator {
@@ -237,6 +486,7 @@ impl Topology {
                     domain_id,
                     String::from(name),
                     String::from(uj_counter),
+                    counter.clone(),
                     buffer_max_kbytes,
                 ));
             }
@@ -247,58 +497,164 @@ impl Topology {
     /// Generates CPUCore instances for the host and adds them
     /// to appropriate CPUSocket instance from self.sockets
     pub fn add_cpu_cores(&mut self) {
-        let mut cores = Topology::generate_cpu_cores().unwrap();
-        while !cores.is_empty() {
-            let c = cores.pop().unwrap();
-            let socket_id = &c
-                .attributes
-                .get("physical id")
-                .unwrap()
-                .parse::<u16>()
-                .unwrap();
-            let socket = self
-                .sockets
-                .iter_mut()
-                .find(|x| &x.id == socket_id)
-                .expect("Trick: if you are running on a vm, do not forget to use --vm parameter invoking scaphandre at the command line");
-            if socket_id == &socket.id {
-                socket.add_cpu_core(c);
+        self.add_cpu_cores_from(DEFAULT_CPU_SYSFS_PATH, "/proc");
+    }
+
+    /// Same as add_cpu_cores, with the cpu folder of sysfs being *sysfs_path*
+    /// and procfs being mounted on *procfs_root*. Cores of sockets that are
+    /// not measured by the sensor are left out.
+    pub fn add_cpu_cores_from(&mut self, sysfs_path: &str, procfs_root: &str) {
+        let cores = match Topology::generate_cpu_cores_from(sysfs_path, procfs_root) {
+            Ok(cores) => cores,
+            Err(e) => {
+                warn!("Couldn't add the CPU cores to the topology: {}", e);
+                return;
             }
+        };
+        let mut unknown_sockets = vec![];
+        for core in cores {
+            match self.sockets.iter_mut().find(|s| s.id == core.socket_id) {
+                Some(socket) => socket.add_cpu_core(core),
+                None => {
+                    if !unknown_sockets.contains(&core.socket_id) {
+                        unknown_sockets.push(core.socket_id);
+                    }
+                }
+            }
+        }
+        for socket_id in unknown_sockets {
+            warn!(
+                "CPU cores of socket {} are left out, as the sensor doesn't measure it. \
+                If you are running on a vm, do not forget to use --vm parameter invoking \
+                scaphandre at the command line.",
+                socket_id
+            );
         }
     }
 
     /// Triggers ProcessTracker refresh on process stats
     /// and power consumption, CPU stats and cores power comsumption,
     /// CPU sockets stats and power consumption.
+    /// When a trace is replayed, the next frame of the trace is used as
+    /// the current measurements, and nothing is refreshed anymore once the
+    /// end of the trace is reached.
     pub fn refresh(&mut self) {
+        if let Some(Trace::Replay(player)) = &self.trace {
+            match player.next_frame() {
+                Ok(true) => {}
+                Ok(false) => {
+                    debug!("End of the replayed trace reached.");
+                    return;
+                }
+                Err(e) => {
+                    warn!("Couldn't replay the next frame of the trace: {}", e);
+                    return;
+                }
+            }
+        }
+        // /proc/stat is read once for all cores
+        let mut cores_stats = match self.read_cores_stats() {
+            Ok(stats) => stats,
+            Err(e) => {
+                warn!("Couldn't read CPU cores stats: {}", e);
+                HashMap::new()
+            }
+        };
         let sockets = &mut self.sockets;
         for s in sockets {
             // refresh each socket with new record
             s.refresh_record();
+            for c in s.get_cores() {
+                if let Some(stats) = cores_stats.remove(&c.id) {
+                    c.refresh_stats(stats);
+                }
+                c.refresh_state();
+            }
             s.refresh_stats();
+            if let Some(zone) = s.powercap_zone.as_mut() {
+                zone.refresh();
+            }
             let domains = s.get_domains();
             for d in domains {
                 d.refresh_record();
+                if let Some(zone) = d.powercap_zone.as_mut() {
+                    zone.refresh();
+                }
             }
             //let cores = s.get_cores();
             //for c in cores {
             //
             //}
         }
-        self.refresh_procs();
+        for c in self.get_components() {
+            c.refresh_record();
+        }
+        for p in &mut self.platforms {
+            p.refresh_record();
+        }
+        match self.cgroup_tracker.as_mut() {
+            Some(tracker) => tracker.refresh(),
+            None => {
+                self.refresh_procs();
+                if let Some(listener) = &self.exits_listener {
+                    self.exited_processes = listener.drain();
+                }
+            }
+        }
         self.refresh_record();
         self.refresh_stats();
+        if let Some(usage) = self.get_usage() {
+            self.idle_power.add_sample(&usage);
+        }
+        if self.cgroup_tracker.is_none() {
+            // attributing power to every process is costly, it is done once per refresh
+            self.processes_power = self.compute_processes_power_consumption_microwatts();
+            self.proc_tracker.add_energy(&self.processes_power);
+            if let Some(window) = self.get_records_window() {
+                self.proc_tracker.add_exited_energy(
+                    &self.exited_processes,
+                    &self.processes_power,
+                    window,
+                );
+            }
+        }
+        if let Some(Trace::Record(recorder)) = &self.trace {
+            if let Err(e) = recorder.record(self) {
+                warn!("Couldn't record trace to {}: {}", recorder.path, e);
+            }
+        }
+    }
+
+    /// Refreshes the energy records of the sockets and of the host only, which
+    /// is enough to get their power, without the cost of tracking processes.
+    pub fn refresh_records(&mut self) {
+        for s in &mut self.sockets {
+            s.refresh_record();
+        }
+        self.refresh_record();
     }
 
     /// Gets currently running processes (as procfs::Process instances) and stores
     /// them in self.proc_tracker
     fn refresh_procs(&mut self) {
         //! current_procs is the up to date list of processus running on the host
-        let current_procs = process::all_processes().unwrap();
+        let current_procs = process::all_processes_with_root(&self.procfs_root).unwrap();
+        // the CPUs threads run on are also needed to attribute power by socket
+        // and by some attribution models
+        let track_threads = self.track_threads
+            || self.attribution_mode == AttributionMode::Socket
+            || self.attribution_model.needs_cores_jiffies();
 
         for p in current_procs {
             let pid = p.pid;
-            let res = self.proc_tracker.add_process_record(p);
+            let threads = if track_threads {
+                Some(read_threads(&self.procfs_root, pid))
+            } else {
+                None
+            };
+            let res = self
+                .proc_tracker
+                .add_process_record_with_threads(p, threads);
             match res {
                 Ok(_) => {}
                 Err(msg) => panic!("Failed to track process with pid {} !\nGot: {}", pid, msg),
@@ -341,6 +697,17 @@ impl Topology {
         }
     }
 
+    /// Returns the time between the last and previous measurement.
+    pub fn get_records_window(&self) -> Option<Duration> {
+        let len = self.record_buffer.len();
+        if len > 1 {
+            let last = &self.record_buffer[len - 1];
+            let previous = &self.record_buffer[len - 2];
+            return Some(last.timestamp.saturating_sub(previous.timestamp));
+        }
+        None
+    }
+
     /// Returns a Record instance containing the difference (attribute by attribute, except timestamp which will be the timestamp from the last record)
     /// between the last (in time) record from self.record_buffer and the previous one
     pub fn get_records_diff(&self) -> Option<Record> {
@@ -431,9 +798,21 @@ impl Topology {
         None
     }
 
+    /// Reads content from the stat file of self.procfs_root (/proc/stat by default).
+    fn read_kernel_stats(&self) -> Result<KernelStats, Box<dyn Error>> {
+        let file = File::open(format!("{}/stat", self.procfs_root))?;
+        Ok(KernelStats::from_reader(file)?)
+    }
+
+    /// Reads the cpuN lines of /proc/stat and returns the stats of each CPU core, by core id.
+    pub fn read_cores_stats(&self) -> Result<HashMap<u16, CPUStat>, Box<dyn Error>> {
+        let content = fs::read_to_string(format!("{}/stat", self.procfs_root))?;
+        Ok(parse_cores_stats(&content))
+    }
+
     /// Reads content from /proc/stat and extracts the stats of the whole CPU topology.
     pub fn read_stats(&self) -> Option<CPUStat> {
-        let kernelstats_or_not = KernelStats::new();
+        let kernelstats_or_not = self.read_kernel_stats();
         if let Ok(res_cputime) = kernelstats_or_not {
             return Some(CPUStat {
                 user: res_cputime.total.user,
@@ -453,7 +832,7 @@ impl Topology {
 
     /// Returns the number of processes currently available
     pub fn read_nb_process_total_count(&self) -> Option<u64> {
-        if let Ok(result) = KernelStats::new() {
+        if let Ok(result) = self.read_kernel_stats() {
             return Some(result.processes);
         }
         None
@@ -461,7 +840,7 @@ impl Topology {
 
     /// Returns the number of processes currently in a running state
     pub fn read_nb_process_running_current(&self) -> Option<u32> {
-        if let Ok(result) = KernelStats::new() {
+        if let Ok(result) = self.read_kernel_stats() {
             if let Some(procs_running) = result.procs_running {
                 return Some(procs_running);
             }
@@ -470,7 +849,7 @@ impl Topology {
     }
     /// Returns the number of processes currently blocked waiting
     pub fn read_nb_process_blocked_current(&self) -> Option<u32> {
-        if let Ok(result) = KernelStats::new() {
+        if let Ok(result) = self.read_kernel_stats() {
             if let Some(procs_blocked) = result.procs_blocked {
                 return Some(procs_blocked);
             }
@@ -479,44 +858,340 @@ impl Topology {
     }
     /// Returns the current number of context switches
     pub fn read_nb_context_switches_total_count(&self) -> Option<u64> {
-        if let Ok(result) = KernelStats::new() {
+        if let Ok(result) = self.read_kernel_stats() {
             return Some(result.ctxt);
         }
         None
     }
 
-    /// Returns the power consumed between last and previous measurement for a given process ID, in microwatts
+    /// Returns the power consumed between last and previous measurement for a given process ID, in microwatts.
+    /// Use get_processes_power_consumption_microwatts to get the power of every process at once.
     pub fn get_process_power_consumption_microwatts(&self, pid: i32) -> Option<Record> {
-        let tracker = self.get_proc_tracker();
-        if let Some(recs) = tracker.find_records(pid) {
-            if recs.len() > 1 {
-                let last = recs.first().unwrap();
-                let previous = recs.get(1).unwrap();
-                if let Some(topo_stats_diff) = self.get_stats_diff() {
-                    //trace!("Topology stats measured diff: {:?}", topo_stats_diff);
-                    let process_total_time =
-                        last.total_time_jiffies() - previous.total_time_jiffies();
-                    let topo_total_time = topo_stats_diff.total_time_jiffies();
-                    let usage_percent = process_total_time as f64 / topo_total_time as f64;
-                    let topo_conso = self.get_records_diff_power_microwatts();
-                    if let Some(val) = &topo_conso {
-                        //trace!("topo conso: {}", val);
-                        let val_f64 = val.value.parse::<f64>().unwrap();
-                        //trace!("val f64: {}", val_f64);
-                        let result = (val_f64 * usage_percent) as u64;
-                        //trace!("result: {}", result);
-                        return Some(Record::new(
-                            last.timestamp,
-                            result.to_string(),
-                            units::Unit::MicroWatt,
-                        ));
-                    }
+        self.processes_power.get(&pid).cloned()
+    }
+
+    /// Returns the power consumed between last and previous measurement by each process
+    /// tracked, in microwatts, by PID, as computed on the last refresh.
+    pub fn get_processes_power_consumption_microwatts(&self) -> HashMap<i32, Record> {
+        self.processes_power.clone()
+    }
+
+    /// Computes the power consumed between last and previous measurement by each process
+    /// tracked, in microwatts, by PID. The power of the host (or of each socket and
+    /// the rest of the host, with AttributionMode::Socket) is attributed to processes
+    /// by self.attribution_model.
+    fn compute_processes_power_consumption_microwatts(&self) -> HashMap<i32, Record> {
+        let cpus_sockets = self.get_cpus_sockets();
+        // (pid, timestamp, usage of the process, CPU time by socket when known)
+        let mut processes = vec![];
+        for recs in self.proc_tracker.get_alive_processes() {
+            if recs.len() < 2 {
+                continue;
+            }
+            let (last, previous) = (&recs[0], &recs[1]);
+            let usage =
+                get_process_usage(last, previous, self.attribution_model.needs_reserved_cpus());
+            let mut by_socket = None;
+            if self.attribution_mode == AttributionMode::Socket {
+                if let (Some(last_threads), Some(previous_threads)) =
+                    (&last.threads, &previous.threads)
+                {
+                    by_socket = jiffies_by_socket(
+                        last_threads,
+                        previous_threads,
+                        usage.cpu_jiffies,
+                        last.process.pid,
+                        &cpus_sockets,
+                    );
                 }
             }
-        } else {
-            trace!("Couldn't find records for PID: {}", pid);
+            processes.push((last.process.pid, last.timestamp, usage, by_socket));
         }
-        None
+        let host = self.get_usage();
+        // processes that exited since the previous refresh, for the CPU time they
+        // used since they were last seen (if ever)
+        if !self.exited_processes.is_empty() {
+            let ticks_per_second = procfs::ticks_per_second().unwrap_or(100) as u64;
+            let boot_time_secs = procfs::boot_time_secs().unwrap_or_default();
+            let alive_pids: HashSet<i32> = processes.iter().map(|p| p.0).collect();
+            let start_times = self.proc_tracker.get_energy_start_times();
+            // as for cgroups, the CPU time of a process can't exceed the host one
+            let busy_jiffies = host.as_ref().map_or(u64::MAX, |h| h.busy_jiffies);
+            for exited in &self.exited_processes {
+                if alive_pids.contains(&exited.pid) {
+                    continue;
+                }
+                let counted_jiffies = self
+                    .proc_tracker
+                    .find_exited_process_energy(
+                        &start_times,
+                        exited,
+                        boot_time_secs,
+                        ticks_per_second,
+                    )
+                    .map(|(_, energy)| energy.cpu_jiffies)
+                    .unwrap_or_default();
+                let usage = ConsumerUsage {
+                    cpu_jiffies: exited
+                        .cpu_time_jiffies(ticks_per_second)
+                        .saturating_sub(counted_jiffies)
+                        .min(busy_jiffies),
+                    ..Default::default()
+                };
+                processes.push((exited.pid, exited.timestamp, usage, None));
+            }
+        }
+
+        let mut microwatts: HashMap<i32, f64> = HashMap::new();
+        if self.attribution_mode == AttributionMode::Socket {
+            let sockets: Vec<(u16, HostUsage)> = self
+                .sockets
+                .iter()
+                .filter_map(|s| Some((s.id, self.get_socket_usage(s, host.as_ref())?)))
+                .collect();
+            let consumers: Vec<(ConsumerUsage, Option<HashMap<u16, u64>>)> = processes
+                .iter()
+                .map(|p| (p.2.clone(), p.3.clone()))
+                .collect();
+            let powers = attribute_by_socket(
+                self.attribution_model.as_ref(),
+                host.as_ref(),
+                &sockets,
+                &consumers,
+                &cpus_sockets,
+            );
+            if host.is_some() || !sockets.is_empty() {
+                for (process, power) in processes.iter().zip(powers) {
+                    microwatts.insert(process.0, power);
+                }
+            }
+        } else if let Some(host) = &host {
+            let consumers: Vec<ConsumerUsage> = processes.iter().map(|p| p.2.clone()).collect();
+            let powers = self.attribution_model.attribute(host, &consumers);
+            for (process, power) in processes.iter().zip(powers) {
+                microwatts.insert(process.0, power);
+            }
+        }
+
+        let mut result = HashMap::new();
+        for (pid, timestamp, _, _) in processes {
+            if let Some(power) = microwatts.get(&pid) {
+                result.insert(
+                    pid,
+                    Record::new(
+                        timestamp,
+                        (*power as u64).to_string(),
+                        units::Unit::MicroWatt,
+                    ),
+                );
+            }
+        }
+        result
+    }
+
+    /// Returns the power consumed between last and previous measurement by each
+    /// process tree, in microwatts, by PID of its root: the sum of the power of
+    /// the processes of the tree, given in *processes_power*. The processes that
+    /// exited are counted in the tree of their parent. Empty if process trees
+    /// are not enabled.
+    pub fn get_trees_power_consumption_microwatts(
+        &self,
+        processes_power: &HashMap<i32, Record>,
+    ) -> HashMap<i32, Record> {
+        let mut roots = self.proc_tracker.get_tree_roots();
+        for exited in &self.exited_processes {
+            if let Some(root) = roots.get(&exited.ppid).cloned() {
+                roots.entry(exited.pid).or_insert(root);
+            }
+        }
+        let mut trees: HashMap<i32, (Duration, f64)> = HashMap::new();
+        for (pid, power) in processes_power {
+            if let Some(root) = roots.get(pid) {
+                let tree = trees.entry(*root).or_default();
+                tree.0 = tree.0.max(power.timestamp);
+                tree.1 += power.value.parse::<f64>().unwrap_or_default();
+            }
+        }
+        trees
+            .into_iter()
+            .map(|(root, (timestamp, microwatts))| {
+                (
+                    root,
+                    Record::new(
+                        timestamp,
+                        (microwatts as u64).to_string(),
+                        units::Unit::MicroWatt,
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the power consumed between last and previous measurement by the
+    /// processes of each systemd unit, in microwatts, from the power of each
+    /// process in *processes_power*. Empty if processes are not mapped to units.
+    pub fn get_systemd_units_power_consumption_microwatts(
+        &self,
+        processes_power: &HashMap<i32, Record>,
+    ) -> HashMap<SystemdUnit, Record> {
+        let processes_units = self.proc_tracker.get_processes_systemd_units();
+        let mut units: HashMap<SystemdUnit, (Duration, f64)> = HashMap::new();
+        for (pid, power) in processes_power {
+            if let Some(unit) = processes_units.get(pid) {
+                let unit = units.entry((*unit).clone()).or_default();
+                unit.0 = unit.0.max(power.timestamp);
+                unit.1 += power.value.parse::<f64>().unwrap_or_default();
+            }
+        }
+        units
+            .into_iter()
+            .map(|(unit, (timestamp, microwatts))| {
+                (
+                    unit,
+                    Record::new(
+                        timestamp,
+                        (microwatts as u64).to_string(),
+                        units::Unit::MicroWatt,
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the power consumed between last and previous measurement by the
+    /// processes of each user, in microwatts, by real UID, from the power of each
+    /// process in *processes_power*. Empty if processes are not mapped to users.
+    pub fn get_users_power_consumption_microwatts(
+        &self,
+        processes_power: &HashMap<i32, Record>,
+    ) -> HashMap<u32, Record> {
+        let processes_uids = self.proc_tracker.get_processes_uids();
+        let mut users: HashMap<u32, (Duration, f64)> = HashMap::new();
+        for (pid, power) in processes_power {
+            if let Some(uid) = processes_uids.get(pid) {
+                let user = users.entry(*uid).or_default();
+                user.0 = user.0.max(power.timestamp);
+                user.1 += power.value.parse::<f64>().unwrap_or_default();
+            }
+        }
+        users
+            .into_iter()
+            .map(|(uid, (timestamp, microwatts))| {
+                (
+                    uid,
+                    Record::new(
+                        timestamp,
+                        (microwatts as u64).to_string(),
+                        units::Unit::MicroWatt,
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the power consumed between last and previous measurement by each thread
+    /// of a given process ID, in microwatts, along with the last record of the thread.
+    /// *process_power* is split between the threads according to their CPU time.
+    /// Returns an empty vector if the threads are not tracked.
+    pub fn get_threads_power_consumption_microwatts(
+        &self,
+        pid: i32,
+        process_power: &Record,
+    ) -> Vec<(ThreadRecord, Record)> {
+        let mut result = vec![];
+        let recs = match self.get_proc_tracker().find_records(pid) {
+            Some(recs) if recs.len() > 1 => recs,
+            _ => return result,
+        };
+        let (last, previous) = match (&recs[0].threads, &recs[1].threads) {
+            (Some(last), Some(previous)) => (last, previous),
+            _ => return result,
+        };
+        let process_microwatts = process_power.value.parse::<f64>().unwrap_or_default();
+        let jiffies: Vec<u64> = last.iter().map(|t| thread_jiffies(t, previous)).collect();
+        let total_jiffies: u64 = jiffies.iter().sum();
+        for (thread, jiffies) in last.iter().zip(jiffies) {
+            let microwatts = if total_jiffies > 0 {
+                process_microwatts * jiffies as f64 / total_jiffies as f64
+            } else {
+                0.0
+            };
+            result.push((
+                thread.clone(),
+                Record::new(
+                    process_power.timestamp,
+                    (microwatts as u64).to_string(),
+                    units::Unit::MicroWatt,
+                ),
+            ));
+        }
+        result
+    }
+
+    /// Returns the power consumed between last and previous measurement by each cgroup,
+    /// in microwatts, by path (relative to the root of the cgroup hierarchy). The power of
+    /// the host is attributed to cgroups by self.attribution_model.
+    pub fn get_cgroups_power_consumption_microwatts(&self) -> BTreeMap<String, Record> {
+        let mut result = BTreeMap::new();
+        let (tracker, host) = match (&self.cgroup_tracker, self.get_usage()) {
+            (Some(tracker), Some(host)) => (tracker, host),
+            _ => return result,
+        };
+        let ticks_per_second = procfs::ticks_per_second().unwrap_or(100) as u64;
+        let powers =
+            tracker.attribute_power(self.attribution_model.as_ref(), &host, ticks_per_second);
+        for (path, (timestamp, power)) in powers {
+            result.insert(
+                path,
+                Record::new(
+                    timestamp,
+                    (power as u64).to_string(),
+                    units::Unit::MicroWatt,
+                ),
+            );
+        }
+        result
+    }
+
+    /// Returns the power and CPU time of the host between last and previous measurement,
+    /// with its idle power when known.
+    pub fn get_usage(&self) -> Option<HostUsage> {
+        let mut usage = get_usage(
+            self.get_records_diff_power_microwatts(),
+            self.get_stats_diff(),
+            self.sockets.iter().flat_map(|s| s.get_cores_passive()),
+        )?;
+        usage.idle_power_microwatts = self
+            .idle_power
+            .get_idle_power_microwatts()
+            .map(|idle| idle.min(usage.power_microwatts));
+        Some(usage)
+    }
+
+    /// Returns the power and CPU time of *socket* between last and previous measurement.
+    /// Its idle power is the one of the *host*, in proportion of the power of the socket.
+    fn get_socket_usage(&self, socket: &CPUSocket, host: Option<&HostUsage>) -> Option<HostUsage> {
+        let mut usage = socket.get_usage()?;
+        if let Some(host) = host {
+            if let Some(idle_power) = host.idle_power_microwatts {
+                if host.power_microwatts > 0.0 {
+                    usage.idle_power_microwatts =
+                        Some(idle_power * usage.power_microwatts / host.power_microwatts);
+                }
+            }
+        }
+        Some(usage)
+    }
+
+    /// Returns the socket id of each CPU core of the topology, by core id.
+    pub fn get_cpus_sockets(&self) -> HashMap<u16, u16> {
+        let mut cpus_sockets = HashMap::new();
+        for socket in &self.sockets {
+            for core in socket.get_cores_passive() {
+                cpus_sockets.insert(core.id, socket.id);
+            }
+        }
+        cpus_sockets
     }
 
     pub fn get_process_cpu_consumption_percentage(&self, pid: i32) -> Option<Record> {
@@ -558,6 +1233,8 @@ pub struct CPUSocket {
     pub attributes: Vec<Vec<HashMap<String, String>>>,
     /// Path to the file that provides the counter for energy consumed by the socket, in microjoules.
     pub counter_uj_path: String,
+    /// Counter read to get the energy consumed by the socket, in microjoules.
+    pub counter: Box<dyn EnergyCounter>,
     /// Comsumption records measured and stored by scaphandre for this socket.
     pub record_buffer: Vec<Record>,
     /// Maximum size of the record_buffer in kilobytes.
@@ -566,6 +1243,10 @@ pub struct CPUSocket {
     pub cpu_cores: Vec<CPUCore>,
     /// Usage statistics records stored for this socket.
     pub stat_buffer: Vec<CPUStat>,
+    /// Dies of the socket, grouping its CPU cores.
+    pub dies: Vec<CPUDie>,
+    /// Power limits of the socket, if it is a powercap zone.
+    pub powercap_zone: Option<PowercapZone>,
 }
 
 impl RecordGenerator for CPUSocket {
@@ -635,6 +1316,7 @@ impl CPUSocket {
         domains: Vec<Domain>,
         attributes: Vec<Vec<HashMap<String, String>>>,
         counter_uj_path: String,
+        counter: Box<dyn EnergyCounter>,
         buffer_max_kbytes: u16,
     ) -> CPUSocket {
         CPUSocket {
@@ -642,10 +1324,13 @@ impl CPUSocket {
             domains,
             attributes,
             counter_uj_path,
+            counter,
             record_buffer: vec![], // buffer has to be empty first
             buffer_max_kbytes,
             cpu_cores: vec![], // cores are instantiated on a later step
             stat_buffer: vec![],
+            dies: vec![],
+            powercap_zone: None,
         }
     }
 
@@ -656,23 +1341,17 @@ impl CPUSocket {
         }
     }
 
-    /// Returns the content of the energy consumption counter file, as a String
+    /// Returns the value of the energy consumption counter, as a String
     /// value of microjoules.
-    pub fn read_counter_uj(&self) -> Result<String, Box<dyn Error>> {
-        match fs::read_to_string(&self.counter_uj_path) {
-            Ok(result) => Ok(result),
-            Err(error) => Err(Box::new(error)),
-        }
+    pub fn read_counter_uj(&mut self) -> Result<String, Box<dyn Error>> {
+        Ok(self.counter.read_uj()?.to_string())
     }
-    pub fn read_record_uj(&self) -> Result<Record, Box<dyn Error>> {
-        match fs::read_to_string(&self.counter_uj_path) {
-            Ok(result) => Ok(Record::new(
-                current_system_time_since_epoch(),
-                result,
-                units::Unit::MicroJoule,
-            )),
-            Err(error) => Err(Box::new(error)),
-        }
+    pub fn read_record_uj(&mut self) -> Result<Record, Box<dyn Error>> {
+        Ok(Record::new(
+            current_system_time_since_epoch(),
+            self.read_counter_uj()?,
+            units::Unit::MicroJoule,
+        ))
     }
 
     /// Returns a mutable reference to the domains vector.
@@ -695,11 +1374,23 @@ impl CPUSocket {
         &self.cpu_cores
     }
 
-    /// Adds a CPU core instance to the cores vector.
+    /// Adds a CPU core instance to the cores vector, and to the die it belongs to.
     pub fn add_cpu_core(&mut self, core: CPUCore) {
+        match self.dies.iter_mut().find(|d| d.id == core.die_id) {
+            Some(die) => die.cores_ids.push(core.id),
+            None => self.dies.push(CPUDie {
+                id: core.die_id,
+                cores_ids: vec![core.id],
+            }),
+        }
         self.cpu_cores.push(core);
     }
 
+    /// Returns a immutable reference to the dies vector.
+    pub fn get_dies_passive(&self) -> &Vec<CPUDie> {
+        &self.dies
+    }
+
     /// Generates a new CPUStat object storing current usage statistics of the socket
     /// and stores it in the stat_buffer.
     pub fn refresh_stats(&mut self) {
@@ -751,8 +1442,8 @@ impl CPUSocket {
         }
     }
 
-    /// Combines stats from all CPU cores owned byu the socket and returns
-    /// a CpuTime struct containing stats for the whole socket.
+    /// Combines the last stats of the CPU cores owned by the socket and returns
+    /// a CPUStat struct containing stats for the whole socket.
     pub fn read_stats(&self) -> Option<CPUStat> {
         let mut stats = CPUStat {
             user: 0,
@@ -767,24 +1458,26 @@ impl CPUSocket {
             steal: Some(0),
         };
         for c in &self.cpu_cores {
-            let c_stats = c.read_stats().unwrap();
-            stats.user += c_stats.user;
-            stats.nice += c_stats.nice;
-            stats.system += c_stats.system;
-            stats.idle += c_stats.idle;
-            stats.iowait =
-                Some(stats.iowait.unwrap_or_default() + c_stats.iowait.unwrap_or_default());
-            stats.irq = Some(stats.irq.unwrap_or_default() + c_stats.irq.unwrap_or_default());
-            stats.softirq =
-                Some(stats.softirq.unwrap_or_default() + c_stats.softirq.unwrap_or_default());
+            if let Some(c_stats) = c.stat_buffer.first() {
+                stats.add(c_stats);
+            }
         }
         Some(stats)
     }
 
+    /// Returns the power and CPU time of the socket between last and previous measurement.
+    pub fn get_usage(&self) -> Option<HostUsage> {
+        get_usage(
+            self.get_records_diff_power_microwatts(),
+            self.get_stats_diff(),
+            self.cpu_cores.iter(),
+        )
+    }
+
     /// Computes the difference between previous usage statistics record for the socket
     /// and the current one. Returns a CPUStat object containing this difference, field
     /// by field.
-    pub fn get_stats_diff(&mut self) -> Option<CPUStat> {
+    pub fn get_stats_diff(&self) -> Option<CPUStat> {
         if self.stat_buffer.len() > 1 {
             let last = &self.stat_buffer[0];
             let previous = &self.stat_buffer[1];
@@ -874,30 +1567,279 @@ impl CPUSocket {
     }
 }
 
+/// Returns the HostUsage of the host, or of a socket, from its *power* and *stats*
+/// between last and previous measurement and from its *cores*.
+fn get_usage<'a>(
+    power: Option<Record>,
+    stats: Option<CPUStat>,
+    cores: impl Iterator<Item = &'a CPUCore>,
+) -> Option<HostUsage> {
+    let stats = stats?;
+    let mut usage = HostUsage {
+        power_microwatts: power?.value.parse::<f64>().ok()?,
+        busy_jiffies: stats.total_time_jiffies(),
+        idle_jiffies: stats.idle + stats.iowait.unwrap_or_default(),
+        ..Default::default()
+    };
+    for core in cores {
+        if let Some(jiffies) = core.get_busy_jiffies_diff() {
+            usage.cores_busy_jiffies.insert(core.id, jiffies);
+        }
+        if let Some(frequency) = core.get_last_state().and_then(|s| s.frequency_khz) {
+            usage.cores_frequency_khz.insert(core.id, frequency);
+        }
+    }
+    Some(usage)
+}
+
+/// Returns the CPU time consumed by a process between its *previous* and *last*
+/// records, as a ConsumerUsage. The CPU time by core is only known when its threads
+/// are tracked. The CPU affinity of the process is read if *reserved_cpus* is true.
+fn get_process_usage(
+    last: &ProcessRecord,
+    previous: &ProcessRecord,
+    reserved_cpus: bool,
+) -> ConsumerUsage {
+    let mut usage = ConsumerUsage {
+        cpu_jiffies: last
+            .total_time_jiffies()
+            .saturating_sub(previous.total_time_jiffies()),
+        ..Default::default()
+    };
+    if reserved_cpus {
+        usage.reserved_cpus = last
+            .process
+            .status()
+            .ok()
+            .and_then(|status| status.cpus_allowed_list)
+            .map(|ranges| {
+                ranges
+                    .iter()
+                    .map(|(first, last)| last - first + 1)
+                    .sum::<u32>() as u16
+            });
+    }
+    if let (Some(last_threads), Some(previous_threads)) = (&last.threads, &previous.threads) {
+        for thread in last_threads {
+            if let Some(cpu) = thread.processor {
+                *usage.cores_jiffies.entry(cpu).or_insert(0) +=
+                    thread_jiffies(thread, previous_threads);
+            }
+        }
+    }
+    usage
+}
+
 // !!!!!!!!!!!!!!!!! CPUCore !!!!!!!!!!!!!!!!!!!!!!!
+/// Default folder containing the cpuN folders of the CPU cores in sysfs.
+pub const DEFAULT_CPU_SYSFS_PATH: &str = "/sys/devices/system/cpu";
+
+/// Returns the id of the socket the CPU *cpu_id* belongs to, as found in the
+/// cpu folder of sysfs *sysfs_path*.
+pub fn read_cpu_socket_id(sysfs_path: &str, cpu_id: u16) -> Option<u16> {
+    fs::read_to_string(format!(
+        "{}/cpu{}/topology/physical_package_id",
+        sysfs_path, cpu_id
+    ))
+    .ok()?
+    .trim()
+    .parse::<u16>()
+    .ok()
+}
+
+/// Maximum number of CPUStat and CPUCoreState instances kept by a CPUCore.
+const CORE_BUFFER_MAX_LEN: usize = 5;
+
 /// CPUCore reprensents each CPU core on the host,
 /// owned by a CPUSocket. CPUCores are instanciated regardless if
 /// HyperThreading is activated on the host.
 /// Reprensents the processor field in /proc/cpuinfo.
 #[derive(Debug, Clone)]
 pub struct CPUCore {
+    /// Number of the logical CPU (N in cpuN)
     pub id: u16,
+    /// Id of the socket (physical package) of the core
+    pub socket_id: u16,
+    /// Id of the die of the core, in its socket
+    pub die_id: u16,
+    /// Id of the physical core, shared by hyperthreads
+    pub core_id: Option<u16>,
     pub attributes: HashMap<String, String>,
+    /// Path to the sysfs folder of the core, like /sys/devices/system/cpu/cpu0
+    pub sysfs_path: String,
+    /// Frequency and idle states measurements, the most recent first
+    pub state_buffer: Vec<CPUCoreState>,
+    /// Usage statistics (cpuN line of /proc/stat), the most recent first
+    pub stat_buffer: Vec<CPUStat>,
 }
 
 impl CPUCore {
     /// Instantiates CPUCore and returns the instance.
+    /// Socket and core ids are taken from the "physical id" and "core id"
+    /// *attributes* (as found in /proc/cpuinfo), if any.
     pub fn new(id: u16, attributes: HashMap<String, String>) -> CPUCore {
-        CPUCore { id, attributes }
+        let attribute = |name: &str| attributes.get(name).and_then(|v| v.parse::<u16>().ok());
+        CPUCore {
+            id,
+            socket_id: attribute("physical id").unwrap_or_default(),
+            die_id: 0,
+            core_id: attribute("core id"),
+            sysfs_path: format!("{}/cpu{}", DEFAULT_CPU_SYSFS_PATH, id),
+            attributes,
+            state_buffer: vec![],
+            stat_buffer: vec![],
+        }
     }
 
-    /// Reads content from /proc/stat and extracts the stats of the CPU core
-    fn read_stats(&self) -> Option<CpuTime> {
-        if let Ok(mut kernelstats) = KernelStats::new() {
-            return Some(kernelstats.cpu_time.remove(self.id as usize));
+    /// Reads the socket, die and core ids of the core from the topology
+    /// folder of its sysfs folder, keeping the current values for missing
+    /// files (die_id appeared in kernel 5.2).
+    pub fn read_topology(&mut self) {
+        let sysfs_path = self.sysfs_path.clone();
+        let read = |file: &str| {
+            fs::read_to_string(format!("{}/topology/{}", sysfs_path, file))
+                .ok()
+                .and_then(|v| v.trim().parse::<i32>().ok())
+                // -1 is used when the firmware doesn't provide the value
+                .filter(|v| *v >= 0)
+                .map(|v| v as u16)
+        };
+        if let Some(socket_id) = read("physical_package_id") {
+            self.socket_id = socket_id;
         }
-        None
+        if let Some(die_id) = read("die_id") {
+            self.die_id = die_id;
+        }
+        if let Some(core_id) = read("core_id") {
+            self.core_id = Some(core_id);
+        }
+    }
+
+    /// Reads content from the stat file of procfs, mounted on *procfs_root*
+    /// (like Topology.procfs_root), and extracts the stats of the CPU core.
+    /// Topology::read_cores_stats reads the stats of all cores at once.
+    pub fn read_stats(&self, procfs_root: &str) -> Option<CPUStat> {
+        let content = fs::read_to_string(format!("{}/stat", procfs_root)).ok()?;
+        parse_cores_stats(&content).remove(&self.id)
+    }
+
+    /// Stores *stats* in self.stat_buffer, dropping the oldest ones.
+    pub fn refresh_stats(&mut self, stats: CPUStat) {
+        self.stat_buffer.insert(0, stats);
+        self.stat_buffer.truncate(CORE_BUFFER_MAX_LEN);
+    }
+
+    /// Reads the current frequency, governor and idle states counters of
+    /// the core from sysfs. Values that can't be read (no cpufreq or cpuidle
+    /// driver, as in most virtual machines) are left empty.
+    pub fn read_state(&self) -> CPUCoreState {
+        let read = |file: &str| {
+            fs::read_to_string(format!("{}/{}", self.sysfs_path, file))
+                .ok()
+                .map(|v| String::from(v.trim()))
+        };
+        let mut idle_states = vec![];
+        let mut index = 0;
+        while let Some(name) = read(&format!("cpuidle/state{}/name", index)) {
+            let value = |file: &str| {
+                read(&format!("cpuidle/state{}/{}", index, file))
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or_default()
+            };
+            idle_states.push(IdleStateStat {
+                name,
+                time_us: value("time"),
+                usage: value("usage"),
+            });
+            index += 1;
+        }
+        CPUCoreState {
+            timestamp: current_system_time_since_epoch(),
+            frequency_khz: read("cpufreq/scaling_cur_freq").and_then(|v| v.parse::<u64>().ok()),
+            governor: read("cpufreq/scaling_governor"),
+            idle_states,
+        }
+    }
+
+    /// Reads the current state of the core and stores it in self.state_buffer,
+    /// dropping the oldest ones.
+    pub fn refresh_state(&mut self) {
+        self.state_buffer.insert(0, self.read_state());
+        self.state_buffer.truncate(CORE_BUFFER_MAX_LEN);
+    }
+
+    /// Returns the CPU time the core spent running tasks between the two last
+    /// measurements, in jiffies.
+    pub fn get_busy_jiffies_diff(&self) -> Option<u64> {
+        match (self.stat_buffer.first(), self.stat_buffer.get(1)) {
+            (Some(last), Some(previous)) => Some(
+                last.total_time_jiffies()
+                    .saturating_sub(previous.total_time_jiffies()),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Returns the last measured state of the core, if any.
+    pub fn get_last_state(&self) -> Option<&CPUCoreState> {
+        self.state_buffer.first()
     }
+
+    /// Returns, for each idle state, the part of the time (between 0 and 1)
+    /// the core spent in that state between the two last measurements.
+    pub fn get_idle_states_residency(&self) -> Option<Vec<(String, f64)>> {
+        if self.state_buffer.len() < 2 {
+            return None;
+        }
+        let last = &self.state_buffer[0];
+        let previous = &self.state_buffer[1];
+        let elapsed_us = last.timestamp.as_micros() as f64 - previous.timestamp.as_micros() as f64;
+        if elapsed_us <= 0.0 {
+            return None;
+        }
+        let mut residency = vec![];
+        for state in &last.idle_states {
+            if let Some(previous_state) = previous.idle_states.iter().find(|s| s.name == state.name)
+            {
+                let time_us = state.time_us.saturating_sub(previous_state.time_us) as f64;
+                residency.push((state.name.clone(), (time_us / elapsed_us).clamp(0.0, 1.0)));
+            }
+        }
+        Some(residency)
+    }
+}
+
+// !!!!!!!!!!!!!!!!! CPUDie !!!!!!!!!!!!!!!!!!!!!!!
+/// CPUDie represents a die of a CPUSocket (recent CPUs may have several
+/// dies per package), as the ids of the CPUCore instances it holds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CPUDie {
+    /// die_id of the cores in sysfs
+    pub id: u16,
+    pub cores_ids: Vec<u16>,
+}
+
+/// Frequency and idle states of a CPUCore at a given time, as found in sysfs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CPUCoreState {
+    /// Time of the measurement, since epoch
+    pub timestamp: Duration,
+    /// Current frequency (cpufreq/scaling_cur_freq), in kHz
+    pub frequency_khz: Option<u64>,
+    /// Frequency scaling governor (powersave, performance, schedutil...)
+    pub governor: Option<String>,
+    pub idle_states: Vec<IdleStateStat>,
+}
+
+/// Counters of an idle state (C-state) of a CPU core, from cpuidle/stateN.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdleStateStat {
+    /// Name of the state (POLL, C1, C1E, C6...)
+    pub name: String,
+    /// Total time spent in this state, in microseconds
+    pub time_us: u64,
+    /// Number of times this state was entered
+    pub usage: u64,
 }
 
 // !!!!!!!!!!!!!!!!! Domain !!!!!!!!!!!!!!!!!!!!!!!
@@ -911,10 +1853,14 @@ pub struct Domain {
     pub name: String,
     /// Path to the domain's energy counter file, microjoules extracted
     pub counter_uj_path: String,
+    /// Counter read to get the energy consumed by the domain, in microjoules
+    pub counter: Box<dyn EnergyCounter>,
     /// History of energy consumption measurements, stored as Record instances
     pub record_buffer: Vec<Record>,
     /// Maximum size of record_buffer, in kilobytes
     pub buffer_max_kbytes: u16,
+    /// Power limits of the domain, if it is a powercap zone.
+    pub powercap_zone: Option<PowercapZone>,
 }
 impl RecordGenerator for Domain {
     /// Computes a measurement of energy comsumption for this CPU domain,
@@ -963,32 +1909,34 @@ impl RecordGenerator for Domain {
 }
 impl Domain {
     /// Instanciates Domain and returns the instance
-    fn new(id: u16, name: String, counter_uj_path: String, buffer_max_kbytes: u16) -> Domain {
+    fn new(
+        id: u16,
+        name: String,
+        counter_uj_path: String,
+        counter: Box<dyn EnergyCounter>,
+        buffer_max_kbytes: u16,
+    ) -> Domain {
         Domain {
             id,
             name,
             counter_uj_path,
+            counter,
             record_buffer: vec![],
             buffer_max_kbytes,
+            powercap_zone: None,
         }
     }
-    /// Reads content of this domain's energy_uj file
-    pub fn read_counter_uj(&self) -> Result<String, Box<dyn Error>> {
-        match fs::read_to_string(&self.counter_uj_path) {
-            Ok(result) => Ok(result),
-            Err(error) => Err(Box::new(error)),
-        }
+    /// Reads the value of this domain's energy counter
+    pub fn read_counter_uj(&mut self) -> Result<String, Box<dyn Error>> {
+        Ok(self.counter.read_uj()?.to_string())
     }
 
-    pub fn read_record_uj(&self) -> Result<Record, Box<dyn Error>> {
-        match fs::read_to_string(&self.counter_uj_path) {
-            Ok(result) => Ok(Record {
-                timestamp: current_system_time_since_epoch(),
-                unit: units::Unit::MicroJoule,
-                value: result,
-            }),
-            Err(error) => Err(Box::new(error)),
-        }
+    pub fn read_record_uj(&mut self) -> Result<Record, Box<dyn Error>> {
+        Ok(Record {
+            timestamp: current_system_time_since_epoch(),
+            unit: units::Unit::MicroJoule,
+            value: self.read_counter_uj()?,
+        })
     }
 
     /// Returns a Record instance containing the power consumed between
@@ -1027,6 +1975,132 @@ impl fmt::Display for Domain {
     }
 }
 
+// !!!!!!!!!!!!!!!!! Component !!!!!!!!!!!!!!!!!!!!!!!
+/// Component struct represents a power consumer of the host that doesn't
+/// belong to a CPUSocket, like a power supply, a board or a BMC channel.
+#[derive(Debug, Clone)]
+pub struct Component {
+    /// Numerical ID of the component, unique in the topology
+    pub id: u16,
+    /// Name of the component, as built by the sensor
+    pub name: String,
+    /// Path to the source of the component's measurements
+    pub counter_uj_path: String,
+    /// Counter read to get the energy consumed by the component, in microjoules
+    pub counter: Box<dyn EnergyCounter>,
+    /// History of energy consumption measurements, stored as Record instances
+    pub record_buffer: Vec<Record>,
+    /// Maximum size of record_buffer, in kilobytes
+    pub buffer_max_kbytes: u16,
+}
+
+impl RecordGenerator for Component {
+    /// Computes a measurement of energy comsumption for this component
+    /// and stores it in self.record_buffer.
+    fn refresh_record(&mut self) {
+        match self.read_record_uj() {
+            Ok(record) => self.record_buffer.push(record),
+            Err(e) => debug!("Couldn't read counter of component {}: {}", self.name, e),
+        }
+
+        if !self.record_buffer.is_empty() {
+            self.clean_old_records();
+        }
+    }
+
+    /// Removes as many Record instances from self.record_buffer as needed
+    /// for record_buffer to take less than 'buffer_max_kbytes' in memory
+    fn clean_old_records(&mut self) {
+        let record_size = size_of_val(&self.record_buffer[0]);
+        let curr_size = record_size * self.record_buffer.len();
+        if curr_size > (self.buffer_max_kbytes * 1000) as usize {
+            let size_diff = curr_size - (self.buffer_max_kbytes * 1000) as usize;
+            if size_diff > record_size {
+                let nb_records_to_delete = size_diff as f32 / record_size as f32;
+                for _ in 1..nb_records_to_delete as u32 {
+                    if !self.record_buffer.is_empty() {
+                        self.record_buffer.remove(0);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns a copy of self.record_buffer
+    fn get_records_passive(&self) -> Vec<Record> {
+        self.record_buffer.clone()
+    }
+}
+
+impl Component {
+    /// Instanciates Component and returns the instance
+    fn new(
+        id: u16,
+        name: String,
+        counter_uj_path: String,
+        counter: Box<dyn EnergyCounter>,
+        buffer_max_kbytes: u16,
+    ) -> Component {
+        Component {
+            id,
+            name,
+            counter_uj_path,
+            counter,
+            record_buffer: vec![],
+            buffer_max_kbytes,
+        }
+    }
+
+    /// Reads the value of this component's energy counter
+    pub fn read_counter_uj(&mut self) -> Result<String, Box<dyn Error>> {
+        Ok(self.counter.read_uj()?.to_string())
+    }
+
+    pub fn read_record_uj(&mut self) -> Result<Record, Box<dyn Error>> {
+        Ok(Record::new(
+            current_system_time_since_epoch(),
+            self.read_counter_uj()?,
+            units::Unit::MicroJoule,
+        ))
+    }
+
+    /// Returns a Record instance containing the power consumed between
+    /// last and previous measurement, in microwatts.
+    pub fn get_records_diff_power_microwatts(&self) -> Option<Record> {
+        if self.record_buffer.len() > 1 {
+            let last_record = self.record_buffer.last().unwrap();
+            let previous_record = self
+                .record_buffer
+                .get(self.record_buffer.len() - 2)
+                .unwrap();
+            if let (Ok(last_microjoules), Ok(previous_microjoules)) = (
+                last_record.value.trim().parse::<u64>(),
+                previous_record.value.trim().parse::<u64>(),
+            ) {
+                if previous_microjoules > last_microjoules {
+                    return None;
+                }
+                let microjoules = last_microjoules - previous_microjoules;
+                let time_diff =
+                    last_record.timestamp.as_secs_f64() - previous_record.timestamp.as_secs_f64();
+                let microwatts = microjoules as f64 / time_diff;
+                return Some(Record::new(
+                    last_record.timestamp,
+                    (microwatts as u64).to_string(),
+                    units::Unit::MicroWatt,
+                ));
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for Component {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Component: {}", self.name)
+    }
+}
+
 // !!!!!!!!!!!!!!!!! Record !!!!!!!!!!!!!!!!!!!!!!!
 /// Record struct represents an electricity consumption measurement
 /// tied to a domain.
@@ -1074,7 +2148,61 @@ pub struct CPUStat {
     guest_nice: Option<u64>,
 }
 
+/// Parses the cpuN lines of the content of /proc/stat and returns the stats
+/// of each CPU core, by core id.
+fn parse_cores_stats(content: &str) -> HashMap<u16, CPUStat> {
+    let mut stats = HashMap::new();
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let id = match fields
+            .next()
+            .and_then(|name| name.strip_prefix("cpu"))
+            .and_then(|id| id.parse::<u16>().ok())
+        {
+            Some(id) => id,
+            None => continue,
+        };
+        let values: Vec<u64> = fields.filter_map(|v| v.parse::<u64>().ok()).collect();
+        if values.len() < 4 {
+            continue;
+        }
+        let value = |index: usize| values.get(index).cloned();
+        stats.insert(
+            id,
+            CPUStat {
+                user: values[0],
+                nice: values[1],
+                system: values[2],
+                idle: values[3],
+                iowait: value(4),
+                irq: value(5),
+                softirq: value(6),
+                steal: value(7),
+                guest: value(8),
+                guest_nice: value(9),
+            },
+        );
+    }
+    stats
+}
+
 impl CPUStat {
+    /// Adds the values of *other* to this CPUStat, field by field.
+    fn add(&mut self, other: &CPUStat) {
+        let add =
+            |a: Option<u64>, b: Option<u64>| Some(a.unwrap_or_default() + b.unwrap_or_default());
+        self.user += other.user;
+        self.nice += other.nice;
+        self.system += other.system;
+        self.idle += other.idle;
+        self.iowait = add(self.iowait, other.iowait);
+        self.irq = add(self.irq, other.irq);
+        self.softirq = add(self.softirq, other.softirq);
+        self.steal = add(self.steal, other.steal);
+        self.guest = add(self.guest, other.guest);
+        self.guest_nice = add(self.guest_nice, other.guest_nice);
+    }
+
     /// Returns the total of active CPU time spent, for this stat measurement
     /// (not iowait, idle, irq or softirq)
     pub fn total_time_jiffies(&self) -> u64 {
@@ -1118,6 +2246,7 @@ impl Clone for CPUStat {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sensors::test_utils::TempDir;
     #[test]
     fn get_proc_cpuinfo() {
         let cores = Topology::generate_cpu_cores().unwrap();
@@ -1148,11 +2277,124 @@ mod tests {
         let mut topo = (*sensor.get_topology()).unwrap();
         for s in topo.get_sockets() {
             for c in s.get_cores() {
-                println!("{:?}", c.read_stats());
+                println!("{:?}", c.read_stats("/proc"));
             }
         }
     }
 
+    #[test]
+    fn core_state_reads_frequency_and_idle_states() {
+        let root = TempDir::new("core");
+        let write = |file: &str, value: &str| {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, value).unwrap();
+        };
+        write("cpufreq/scaling_cur_freq", "2400000\n");
+        write("cpufreq/scaling_governor", "powersave\n");
+        write("cpuidle/state0/name", "POLL\n");
+        write("cpuidle/state0/time", "100");
+        write("cpuidle/state0/usage", "10");
+        write("cpuidle/state1/name", "C6\n");
+        write("cpuidle/state1/time", "1000000");
+        write("cpuidle/state1/usage", "50");
+
+        let mut core = CPUCore::new(0, HashMap::new());
+        core.sysfs_path = String::from(root.to_str().unwrap());
+        let mut state = core.read_state();
+        assert_eq!(state.frequency_khz, Some(2_400_000));
+        assert_eq!(state.governor, Some(String::from("powersave")));
+        assert_eq!(state.idle_states.len(), 2);
+        assert_eq!(state.idle_states[1].usage, 50);
+        assert_eq!(core.get_idle_states_residency(), None);
+
+        // 2 seconds later, 1.5 of them spent in C6
+        state.timestamp = Duration::from_secs(10);
+        let mut next = state.clone();
+        next.timestamp = Duration::from_secs(12);
+        next.idle_states[1].time_us += 1_500_000;
+        core.state_buffer = vec![next, state];
+        assert_eq!(
+            core.get_idle_states_residency(),
+            Some(vec![
+                (String::from("POLL"), 0.0),
+                (String::from("C6"), 0.75)
+            ])
+        );
+    }
+
+    #[test]
+    fn cores_are_mapped_to_sockets_and_dies_from_sysfs() {
+        let root = TempDir::new("cores");
+        let write = |file: &str, value: &str| {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, value).unwrap();
+        };
+        for (cpu, package, die) in [(0, 0, 0), (1, 0, 1), (2, 1, 0), (3, 1, 0)].iter() {
+            write(
+                &format!("sys/cpu{}/topology/physical_package_id", cpu),
+                &package.to_string(),
+            );
+            write(&format!("sys/cpu{}/topology/die_id", cpu), &die.to_string());
+            write(&format!("sys/cpu{}/topology/core_id", cpu), "0");
+        }
+        write("sys/cpu3/online", "0\n");
+        write(
+            "proc/stat",
+            "cpu  60 0 60 300 0 0 0 0 0 0\ncpu0 10 0 10 100 0 0 0 0 0 0\n\
+             cpu1 20 0 20 100 0 0 0 0 0 0\ncpu2 30 0 30 100 0 0 0 0 0 0\n",
+        );
+        let cores = Topology::generate_cpu_cores_from(
+            root.join("sys").to_str().unwrap(),
+            root.join("proc").to_str().unwrap(),
+        )
+        .unwrap();
+        let ids: Vec<(u16, u16, u16)> = cores
+            .iter()
+            .map(|c| (c.id, c.socket_id, c.die_id))
+            .collect();
+        assert_eq!(ids, [(0, 0, 0), (1, 0, 1), (2, 1, 0)]);
+
+        let mut topo = Topology::new();
+        topo.procfs_root = String::from(root.join("proc").to_str().unwrap());
+        for socket_id in 0..2 {
+            topo.safe_add_socket(socket_id, vec![], vec![], String::new(), 1);
+        }
+        for core in cores {
+            let socket = topo
+                .sockets
+                .iter_mut()
+                .find(|s| s.id == core.socket_id)
+                .unwrap();
+            socket.add_cpu_core(core);
+        }
+        assert_eq!(topo.sockets[0].get_dies_passive().len(), 2);
+        assert_eq!(topo.sockets[1].get_dies_passive()[0].cores_ids, [2]);
+
+        let cpu2 = &topo.sockets[1].get_cores_passive()[0];
+        assert_eq!(
+            cpu2.read_stats(&topo.procfs_root)
+                .unwrap()
+                .total_time_jiffies(),
+            60
+        );
+        let mut cores_stats = topo.read_cores_stats().unwrap();
+        for socket in topo.get_sockets() {
+            for core in socket.get_cores() {
+                core.refresh_stats(cores_stats.remove(&core.id).unwrap());
+            }
+        }
+        assert_eq!(
+            topo.sockets[0].read_stats().unwrap().total_time_jiffies(),
+            60
+        );
+        assert_eq!(
+            topo.sockets[1].read_stats().unwrap().total_time_jiffies(),
+            60
+        );
+    }
+
     #[test]
     fn read_socket_stats() {
         let mut sensor = powercap_rapl::PowercapRAPLSensor::new(8, 8, false);
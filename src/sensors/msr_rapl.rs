@@ -0,0 +1,379 @@
+use crate::sensors::counters::{EnergyCounter, WrappingEnergyCounter, DEFAULT_MAX_POWER_UW};
+use crate::sensors::{read_cpu_socket_id, Sensor, Topology};
+use procfs::modules;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::os::unix::fs::FileExt;
+
+/// Default folder containing the msr device of each CPU, as /dev/cpu/N/msr.
+pub const DEFAULT_MSR_PATH: &str = "/dev/cpu";
+
+/// Register giving the units used by the RAPL energy status registers.
+pub const MSR_RAPL_POWER_UNIT: u64 = 0x606;
+/// Energy consumed by the whole package (socket).
+pub const MSR_PKG_ENERGY_STATUS: u64 = 0x611;
+/// Energy consumed by the DRAM attached to the package.
+pub const MSR_DRAM_ENERGY_STATUS: u64 = 0x619;
+/// Energy consumed by the cores of the package (power plane 0).
+pub const MSR_PP0_ENERGY_STATUS: u64 = 0x639;
+/// Energy consumed by the uncore devices of the package, usually the GPU (power plane 1).
+pub const MSR_PP1_ENERGY_STATUS: u64 = 0x641;
+
+/// RAPL domains read for each socket, with the same ids and names as the
+/// intel-rapl:X:Y powercap zones.
+const MSR_DOMAINS: [(u16, &str, u64); 3] = [
+    (0, "core", MSR_PP0_ENERGY_STATUS),
+    (1, "uncore", MSR_PP1_ENERGY_STATUS),
+    (2, "dram", MSR_DRAM_ENERGY_STATUS),
+];
+
+/// Energy unit of the DRAM domain on server CPUs, in microjoules: 2^-16 J,
+/// whatever the unit given by MSR_RAPL_POWER_UNIT.
+pub const DRAM_FIXED_ENERGY_UNIT_UJ: f64 = 1_000_000.0 / 65536.0;
+
+/// Models of the Intel CPUs (family 6) using DRAM_FIXED_ENERGY_UNIT_UJ:
+/// Haswell-X, Broadwell-X and D, Skylake-X (Cascade and Cooper Lake included),
+/// Ice Lake-X and D, Sapphire and Emerald Rapids, Granite Rapids and Xeon Phi.
+const DRAM_FIXED_UNIT_MODELS: [u8; 12] = [
+    0x3f, 0x4f, 0x56, 0x55, 0x6a, 0x6c, 0x8f, 0xcf, 0xad, 0xae, 0x57, 0x85,
+];
+
+/// Reads a 64 bits register from an msr device file.
+pub fn read_msr(path: &str, register: u64) -> Result<u64, Box<dyn Error>> {
+    let file = fs::File::open(path)?;
+    let mut buffer = [0u8; 8];
+    file.read_exact_at(&mut buffer, register)?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+/// Returns the energy unit, in microjoules, given the value of MSR_RAPL_POWER_UNIT.
+/// Energy status units are stored in bits 12:8, as a power of 1/2 joule.
+pub fn energy_unit_uj(power_unit: u64) -> f64 {
+    let energy_status_units = (power_unit >> 8) & 0x1f;
+    1_000_000.0 / (1u64 << energy_status_units) as f64
+}
+
+/// Returns the energy unit of the DRAM domain, in microjoules, given the
+/// *cpuinfo* content of the host and the *energy_unit_uj* of the other domains.
+pub fn dram_energy_unit_uj(cpuinfo: &str, energy_unit_uj: f64) -> f64 {
+    let mut vendor = None;
+    let mut family = None;
+    let mut model = None;
+    // the first CPU is enough, all the sockets have the same model
+    for line in cpuinfo.lines().take_while(|l| !l.trim().is_empty()) {
+        if let Some((key, value)) = line.split_once(':') {
+            match key.trim() {
+                "vendor_id" => vendor = Some(value.trim()),
+                "cpu family" => family = value.trim().parse::<u8>().ok(),
+                "model" => model = value.trim().parse::<u8>().ok(),
+                _ => {}
+            }
+        }
+    }
+    match (vendor, family, model) {
+        (Some("GenuineIntel"), Some(6), Some(model)) if DRAM_FIXED_UNIT_MODELS.contains(&model) => {
+            DRAM_FIXED_ENERGY_UNIT_UJ
+        }
+        _ => energy_unit_uj,
+    }
+}
+
+/// Counter reading a RAPL energy status register, converted to microjoules.
+#[derive(Debug, Clone)]
+pub struct MsrEnergyCounter {
+    /// Path to the msr device of one of the CPUs of the socket
+    pub path: String,
+    /// Address of the energy status register
+    pub register: u64,
+    /// Value of one unit of the register, in microjoules
+    pub energy_unit_uj: f64,
+}
+
+impl MsrEnergyCounter {
+    /// Instantiates MsrEnergyCounter and returns the instance.
+    pub fn new(path: &str, register: u64, energy_unit_uj: f64) -> MsrEnergyCounter {
+        MsrEnergyCounter {
+            path: String::from(path),
+            register,
+            energy_unit_uj,
+        }
+    }
+}
+
+impl EnergyCounter for MsrEnergyCounter {
+    fn read_uj(&mut self) -> Result<u64, Box<dyn Error>> {
+        // only the 32 lower bits hold the energy counter
+        let raw = read_msr(&self.path, self.register)? & 0xffff_ffff;
+        Ok((raw as f64 * self.energy_unit_uj) as u64)
+    }
+
+    fn box_clone(&self) -> Box<dyn EnergyCounter> {
+        Box::new(self.clone())
+    }
+}
+
+/// Returns a counter reading the energy status *register*, handling the
+/// wraparound of its 32 bits.
+fn msr_counter(path: &str, register: u64, energy_unit_uj: f64) -> Box<dyn EnergyCounter> {
+    let max_range_uj = ((1u64 << 32) as f64 * energy_unit_uj) as u64;
+    Box::new(WrappingEnergyCounter::new(
+        Box::new(MsrEnergyCounter::new(path, register, energy_unit_uj)),
+        max_range_uj,
+        DEFAULT_MAX_POWER_UW,
+    ))
+}
+
+/// This is a Sensor type that reads RAPL energy counters directly from the
+/// Model Specific Registers of the CPUs, through the msr kernel module, for
+/// hosts where powercap is not available or not readable.
+pub struct MsrRAPLSensor {
+    base_path: String,
+    cpu_sysfs_path: String,
+    procfs_root: String,
+    buffer_per_socket_max_kbytes: u16,
+    buffer_per_domain_max_kbytes: u16,
+}
+
+impl MsrRAPLSensor {
+    /// Instantiates and returns an instance of MsrRAPLSensor.
+    /// *base_path* is the folder containing N/msr devices files,
+    /// *cpu_sysfs_path* the one containing the cpuN folders, used to find
+    /// the socket of each CPU, and *procfs_root* the one where procfs is
+    /// mounted, used to find the CPU model.
+    pub fn new(
+        buffer_per_socket_max_kbytes: u16,
+        buffer_per_domain_max_kbytes: u16,
+        base_path: &str,
+        cpu_sysfs_path: &str,
+        procfs_root: &str,
+    ) -> MsrRAPLSensor {
+        MsrRAPLSensor {
+            base_path: String::from(base_path),
+            cpu_sysfs_path: String::from(cpu_sysfs_path),
+            procfs_root: String::from(procfs_root),
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
+        }
+    }
+
+    /// Checks if the msr module is present and activated.
+    pub fn check_module() -> Result<String, String> {
+        if let Ok(modules) = modules() {
+            if modules.values().any(|m| m.name == "msr") {
+                return Ok(String::from("msr module found."));
+            }
+        }
+        Err(String::from("msr kernel module not found."))
+    }
+
+    /// Returns, for each socket, the path of the msr device of its first CPU.
+    fn find_sockets_devices(&self) -> Result<BTreeMap<u16, String>, Box<dyn Error>> {
+        let mut cpus = vec![];
+        for entry in fs::read_dir(&self.base_path)? {
+            let entry = entry?;
+            if let Ok(cpu_id) = entry.file_name().to_str().unwrap().parse::<u16>() {
+                let msr = entry.path().join("msr");
+                if msr.exists() {
+                    cpus.push((cpu_id, String::from(msr.to_str().unwrap())));
+                }
+            }
+        }
+        cpus.sort();
+        let mut sockets = BTreeMap::new();
+        for (cpu_id, msr) in cpus {
+            let socket_id = read_cpu_socket_id(&self.cpu_sysfs_path, cpu_id).unwrap_or(0);
+            sockets.entry(socket_id).or_insert(msr);
+        }
+        Ok(sockets)
+    }
+}
+
+impl Sensor for MsrRAPLSensor {
+    /// Creates a Topology instance, with a socket per CPU package and
+    /// a domain per RAPL energy status register available on it.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        if MsrRAPLSensor::check_module().is_err() {
+            warn!("Couldn't find msr module.");
+        }
+        let sockets = self.find_sockets_devices()?;
+        if sockets.is_empty() {
+            return Err(From::from(format!(
+                "No msr device found in {}",
+                self.base_path
+            )));
+        }
+        let cpuinfo =
+            fs::read_to_string(format!("{}/cpuinfo", self.procfs_root)).unwrap_or_default();
+        let mut topo = Topology::new();
+        for (socket_id, msr) in sockets {
+            let unit = energy_unit_uj(read_msr(&msr, MSR_RAPL_POWER_UNIT)?);
+            let dram_unit = dram_energy_unit_uj(&cpuinfo, unit);
+            debug!(
+                "Socket {} uses {} with an energy unit of {} uJ ({} uJ for dram)",
+                socket_id, msr, unit, dram_unit
+            );
+            topo.safe_add_socket_with_counter(
+                socket_id,
+                vec![],
+                vec![],
+                msr.clone(),
+                msr_counter(&msr, MSR_PKG_ENERGY_STATUS, unit),
+                self.buffer_per_socket_max_kbytes,
+            );
+            for (domain_id, name, register) in MSR_DOMAINS.iter() {
+                let unit = if *register == MSR_DRAM_ENERGY_STATUS {
+                    dram_unit
+                } else {
+                    unit
+                };
+                // unsupported registers can't be read, or stay at 0
+                match read_msr(&msr, *register) {
+                    Ok(value) if value & 0xffff_ffff != 0 => {
+                        topo.safe_add_domain_to_socket_with_counter(
+                            socket_id,
+                            *domain_id,
+                            name,
+                            &msr,
+                            msr_counter(&msr, *register, unit),
+                            self.buffer_per_domain_max_kbytes,
+                        );
+                    }
+                    _ => debug!("Domain {} not available on socket {}", name, socket_id),
+                }
+            }
+        }
+        topo.add_cpu_cores_from(&self.cpu_sysfs_path, &self.procfs_root);
+        Ok(topo)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::test_utils::TempDir;
+    use crate::sensors::RecordGenerator;
+
+    fn write_register(file: &fs::File, register: u64, value: u64) {
+        file.write_all_at(&value.to_le_bytes(), register).unwrap();
+    }
+
+    #[test]
+    fn energy_unit_is_decoded_from_power_unit_register() {
+        // 0xA0E03: energy status units of 14, i.e. 1/16384 J
+        assert!((energy_unit_uj(0xA0E03) - 61.03515625).abs() < f64::EPSILON);
+        assert!((energy_unit_uj(0x1000) - 1_000_000.0 / 65536.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn dram_unit_is_fixed_on_server_cpus() {
+        let cpuinfo = |vendor: &str, model: u8| {
+            format!(
+                "processor\t: 0\nvendor_id\t: {}\ncpu family\t: 6\nmodel\t\t: {}\n\n\
+                 processor\t: 1\nvendor_id\t: GenuineIntel\ncpu family\t: 6\nmodel\t\t: 85\n",
+                vendor, model
+            )
+        };
+        let unit = energy_unit_uj(0xA0E03);
+        // Skylake-X
+        assert_eq!(
+            dram_energy_unit_uj(&cpuinfo("GenuineIntel", 0x55), unit),
+            DRAM_FIXED_ENERGY_UNIT_UJ
+        );
+        // Kaby Lake client, and the first CPU only is looked at
+        assert_eq!(
+            dram_energy_unit_uj(&cpuinfo("GenuineIntel", 0x9e), unit),
+            unit
+        );
+        assert_eq!(
+            dram_energy_unit_uj(&cpuinfo("AuthenticAMD", 0x55), unit),
+            unit
+        );
+        assert_eq!(dram_energy_unit_uj("", unit), unit);
+    }
+
+    #[test]
+    fn msr_counters_build_sockets_and_domains() {
+        let root = TempDir::new("msr");
+        // two CPUs on two sockets, the second one with no RAPL domain
+        for cpu in 0..2 {
+            fs::create_dir_all(root.join(cpu.to_string())).unwrap();
+            let file = fs::File::create(root.join(cpu.to_string()).join("msr")).unwrap();
+            write_register(&file, MSR_RAPL_POWER_UNIT, 0xA0E03);
+            let topology = root.join(format!("cpu/cpu{}/topology", cpu));
+            fs::create_dir_all(&topology).unwrap();
+            fs::write(topology.join("physical_package_id"), cpu.to_string()).unwrap();
+        }
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(root.join("0").join("msr"))
+            .unwrap();
+        // upper bits are reserved and must be ignored
+        write_register(
+            &file,
+            MSR_PKG_ENERGY_STATUS,
+            0xdead_0000_0000_0000 + 65536 * 3,
+        );
+        write_register(&file, MSR_PP0_ENERGY_STATUS, 65536);
+        write_register(&file, MSR_DRAM_ENERGY_STATUS, 32768);
+        // a Skylake-X, the dram unit is 2^-16 J instead of 2^-14 J
+        fs::write(
+            root.join("cpuinfo"),
+            "processor\t: 0\nvendor_id\t: GenuineIntel\ncpu family\t: 6\nmodel\t\t: 85\n",
+        )
+        .unwrap();
+
+        let cpu_sysfs_path = root.join("cpu");
+        let sensor = MsrRAPLSensor::new(
+            1,
+            1,
+            root.to_str().unwrap(),
+            cpu_sysfs_path.to_str().unwrap(),
+            root.to_str().unwrap(),
+        );
+        let mut topo = sensor.generate_topology().unwrap();
+        assert_eq!(topo.sockets.len(), 2);
+        assert_eq!(
+            topo.domains_names,
+            Some(vec![String::from("core"), String::from("dram")])
+        );
+        let socket = &mut topo.get_sockets()[0];
+        // cores are found in the cpu folder given to the sensor
+        let cores: Vec<u16> = socket.get_cores_passive().iter().map(|c| c.id).collect();
+        assert_eq!(cores, vec![0]);
+        socket.refresh_record();
+        assert_eq!(socket.record_buffer[0].value, "12000000");
+        for domain in socket.get_domains() {
+            domain.refresh_record();
+        }
+        let values: Vec<&str> = socket
+            .get_domains_passive()
+            .iter()
+            .map(|d| d.record_buffer[0].value.as_str())
+            .collect();
+        assert_eq!(values, vec!["4000000", "500000"]);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,306 @@
+use crate::sensors::counters::{EnergyCounter, SumEnergyCounter};
+use crate::sensors::utils::current_system_time_since_epoch;
+use crate::sensors::{Sensor, Topology};
+use std::error::Error;
+use std::fs;
+use std::time::Duration;
+
+/// Default folder containing power supplies (batteries, AC adapters...).
+pub const DEFAULT_POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+/// Measurement read from the batteries, as found in the power_supply class.
+#[derive(Debug, Clone, Copy)]
+enum BatterySample {
+    /// Power drawn from the batteries, in microwatts
+    Power(u64),
+    /// Energy left in the batteries, in microwatt-hours
+    Energy(u64),
+}
+
+/// Reads an integer attribute of a power supply, as an absolute value
+/// (some drivers report a negative current or power while discharging).
+fn read_attribute(supply: &str, attribute: &str) -> Option<u64> {
+    match fs::read_to_string(format!("{}/{}", supply, attribute)) {
+        Ok(value) => value.trim().parse::<i64>().ok().map(|v| v.unsigned_abs()),
+        Err(_) => None,
+    }
+}
+
+/// Returns the paths of the power supplies of the given type ("Battery", "Mains"...).
+fn list_supplies(power_supply_path: &str, supply_type: &str) -> Vec<String> {
+    let mut supplies = vec![];
+    if let Ok(entries) = fs::read_dir(power_supply_path) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if let Ok(t) = fs::read_to_string(path.join("type")) {
+                if t.trim() == supply_type {
+                    supplies.push(String::from(path.to_str().unwrap()));
+                }
+            }
+        }
+    }
+    supplies.sort();
+    supplies
+}
+
+/// Counter of the energy consumed by the whole host: drawn from the batteries
+/// while the host is discharging, read from a fallback counter (RAPL) otherwise.
+#[derive(Debug, Clone)]
+pub struct BatteryEnergyCounter {
+    /// Folder containing the power supplies
+    pub power_supply_path: String,
+    /// Counter read when the host is on AC power
+    pub fallback: Option<Box<dyn EnergyCounter>>,
+    /// Energy consumed so far, in microjoules
+    energy_uj: f64,
+    /// Timestamp and value of the previous battery measurement
+    last_battery_sample: Option<(Duration, BatterySample)>,
+    /// Previous value of the fallback counter
+    last_fallback_uj: Option<u64>,
+}
+
+impl BatteryEnergyCounter {
+    /// Instantiates BatteryEnergyCounter, starting at 0 microjoules, and returns the instance.
+    pub fn new(
+        power_supply_path: &str,
+        fallback: Option<Box<dyn EnergyCounter>>,
+    ) -> BatteryEnergyCounter {
+        BatteryEnergyCounter {
+            power_supply_path: String::from(power_supply_path),
+            fallback,
+            energy_uj: 0.0,
+            last_battery_sample: None,
+            last_fallback_uj: None,
+        }
+    }
+
+    /// Returns true if the host is running on its batteries.
+    pub fn is_on_battery(&self) -> bool {
+        let mains = list_supplies(&self.power_supply_path, "Mains");
+        if !mains.is_empty() {
+            return !mains.iter().any(|m| read_attribute(m, "online") == Some(1));
+        }
+        list_supplies(&self.power_supply_path, "Battery")
+            .iter()
+            .any(|b| match fs::read_to_string(format!("{}/status", b)) {
+                Ok(status) => status.trim() == "Discharging",
+                Err(_) => false,
+            })
+    }
+
+    /// Reads the power drawn from the batteries, from power_now or
+    /// current_now and voltage_now, or the energy left in the batteries, from
+    /// energy_now, if the power can't be read.
+    fn read_battery(&self) -> Option<BatterySample> {
+        let batteries = list_supplies(&self.power_supply_path, "Battery");
+        let mut power_uw = 0;
+        let mut energy_uwh = 0;
+        let mut power_found = true;
+        let mut energy_found = true;
+        for b in &batteries {
+            if let Some(power) = read_attribute(b, "power_now") {
+                power_uw += power;
+            } else if let (Some(current), Some(voltage)) = (
+                read_attribute(b, "current_now"),
+                read_attribute(b, "voltage_now"),
+            ) {
+                power_uw += current * voltage / 1_000_000;
+            } else {
+                power_found = false;
+            }
+            match read_attribute(b, "energy_now") {
+                Some(energy) => energy_uwh += energy,
+                None => energy_found = false,
+            }
+        }
+        if batteries.is_empty() {
+            None
+        } else if power_found {
+            Some(BatterySample::Power(power_uw))
+        } else if energy_found {
+            Some(BatterySample::Energy(energy_uwh))
+        } else {
+            None
+        }
+    }
+
+    /// Adds the energy consumed since the previous read to the counter and
+    /// returns its new value, in microjoules.
+    pub fn read_at(&mut self, timestamp: Duration) -> Result<u64, Box<dyn Error>> {
+        if self.is_on_battery() {
+            self.last_fallback_uj = None;
+            let sample = self.read_battery().ok_or_else(|| {
+                format!(
+                    "Couldn't read power or energy of batteries in {}",
+                    self.power_supply_path
+                )
+            })?;
+            if let Some((last_timestamp, last_sample)) = self.last_battery_sample {
+                if timestamp > last_timestamp {
+                    let seconds = (timestamp - last_timestamp).as_secs_f64();
+                    match (last_sample, sample) {
+                        (BatterySample::Power(previous), BatterySample::Power(current)) => {
+                            self.energy_uj += (previous + current) as f64 / 2.0 * seconds;
+                        }
+                        (BatterySample::Energy(previous), BatterySample::Energy(current))
+                            if previous > current =>
+                        {
+                            // microwatt-hours to microjoules
+                            self.energy_uj += (previous - current) as f64 * 3600.0;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            self.last_battery_sample = Some((timestamp, sample));
+        } else {
+            self.last_battery_sample = None;
+            if let Some(fallback) = self.fallback.as_mut() {
+                let value = fallback.read_uj()?;
+                if let Some(previous) = self.last_fallback_uj {
+                    if value >= previous {
+                        self.energy_uj += (value - previous) as f64;
+                    }
+                }
+                self.last_fallback_uj = Some(value);
+            } else {
+                debug!("Host is on AC power and no fallback counter is available.");
+            }
+        }
+        Ok(self.energy_uj as u64)
+    }
+}
+
+impl EnergyCounter for BatteryEnergyCounter {
+    fn read_uj(&mut self) -> Result<u64, Box<dyn Error>> {
+        self.read_at(current_system_time_since_epoch())
+    }
+
+    fn box_clone(&self) -> Box<dyn EnergyCounter> {
+        Box::new(self.clone())
+    }
+}
+
+/// This is a Sensor type that relies on the batteries exposed in the
+/// power_supply class to measure the power consumed by the whole host, while
+/// running on battery. CPU sockets and domains, as well as the host consumption
+/// when running on AC power, come from a fallback sensor (RAPL).
+pub struct PowerSupplySensor {
+    power_supply_path: String,
+    fallback: Option<Box<dyn Sensor>>,
+}
+
+impl PowerSupplySensor {
+    /// Instantiates and returns an instance of PowerSupplySensor.
+    pub fn new(power_supply_path: &str, fallback: Option<Box<dyn Sensor>>) -> PowerSupplySensor {
+        PowerSupplySensor {
+            power_supply_path: String::from(power_supply_path),
+            fallback,
+        }
+    }
+}
+
+impl Sensor for PowerSupplySensor {
+    /// Creates a Topology instance from the fallback sensor, with a host
+    /// counter reading the batteries.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        if list_supplies(&self.power_supply_path, "Battery").is_empty() {
+            return Err(From::from(format!(
+                "No battery found in {}",
+                self.power_supply_path
+            )));
+        }
+        let mut topo = match &self.fallback {
+            Some(sensor) => match sensor.generate_topology() {
+                Ok(topo) => topo,
+                Err(e) => {
+                    warn!("Couldn't generate topology from fallback sensor: {}", e);
+                    Topology::new()
+                }
+            },
+            None => Topology::new(),
+        };
+        let mut fallback: Option<Box<dyn EnergyCounter>> = None;
+        if !topo.sockets.is_empty() {
+            let counters = topo.sockets.iter().map(|s| s.counter.clone()).collect();
+            fallback = Some(Box::new(SumEnergyCounter::new(counters)));
+        }
+        topo.host_counter = Some(Box::new(BatteryEnergyCounter::new(
+            &self.power_supply_path,
+            fallback,
+        )));
+        Ok(topo)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::counters::FileEnergyCounter;
+    use crate::sensors::test_utils::TempDir;
+
+    #[test]
+    fn battery_counter_integrates_battery_then_falls_back_on_ac() {
+        let root = TempDir::new("psu");
+        let battery = root.join("BAT0");
+        let ac = root.join("AC");
+        fs::create_dir_all(&battery).unwrap();
+        fs::create_dir_all(&ac).unwrap();
+        fs::write(battery.join("type"), "Battery\n").unwrap();
+        fs::write(battery.join("current_now"), "-2000000\n").unwrap();
+        fs::write(battery.join("voltage_now"), "5000000\n").unwrap();
+        fs::write(ac.join("type"), "Mains\n").unwrap();
+        fs::write(ac.join("online"), "0\n").unwrap();
+        let rapl = root.join("energy_uj");
+        fs::write(&rapl, "100\n").unwrap();
+
+        let path = root.to_str().unwrap();
+        let fallback = FileEnergyCounter::new(rapl.to_str().unwrap());
+        let mut counter = BatteryEnergyCounter::new(path, Some(Box::new(fallback)));
+        assert!(counter.is_on_battery());
+        assert_eq!(counter.read_at(Duration::from_secs(10)).unwrap(), 0);
+        // 10 W during 2 seconds
+        assert_eq!(
+            counter.read_at(Duration::from_secs(12)).unwrap(),
+            20_000_000
+        );
+
+        fs::write(ac.join("online"), "1\n").unwrap();
+        assert_eq!(
+            counter.read_at(Duration::from_secs(14)).unwrap(),
+            20_000_000
+        );
+        fs::write(&rapl, "600\n").unwrap();
+        assert_eq!(
+            counter.read_at(Duration::from_secs(15)).unwrap(),
+            20_000_500
+        );
+
+        let sensor = PowerSupplySensor::new(path, None);
+        let topo = sensor.generate_topology().unwrap();
+        assert!(topo.host_counter.is_some());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
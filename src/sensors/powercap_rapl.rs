@@ -1,11 +1,272 @@
+use crate::sensors::counters::powercap_zone_counter;
 use crate::sensors::Sensor;
 use crate::sensors::Topology;
 use procfs::{modules, KernelModule};
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
+use std::path::Path;
 use std::{env, fs};
 
+/// Power limit of a powercap zone, as found in its constraint_N_* files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowerConstraint {
+    /// N in constraint_N_* files names
+    pub id: u16,
+    /// Name of the constraint (long_term, short_term, peak_power...)
+    pub name: String,
+    /// Power limit, in microwatts
+    pub power_limit_uw: Option<u64>,
+    /// Time window the power is averaged over before applying the limit, in microseconds
+    pub time_window_us: Option<u64>,
+    /// Maximum power limit allowed for this constraint, in microwatts
+    pub max_power_uw: Option<u64>,
+}
+
+/// Power limits of a powercap zone (a RAPL socket or domain), read from the
+/// zone folder each time the topology is refreshed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowercapZone {
+    /// Path to the zone folder, like /sys/class/powercap/intel-rapl:0
+    pub path: String,
+    /// True if the power limits of the zone are enforced
+    pub enabled: Option<bool>,
+    pub constraints: Vec<PowerConstraint>,
+}
+
+impl PowercapZone {
+    /// Instantiates PowercapZone, reads its power limits and returns the instance.
+    pub fn new(path: &str) -> PowercapZone {
+        let mut zone = PowercapZone {
+            path: String::from(path),
+            enabled: None,
+            constraints: vec![],
+        };
+        zone.refresh();
+        zone
+    }
+
+    /// Reads the current power limits of the zone.
+    pub fn refresh(&mut self) {
+        self.enabled = self.read_value("enabled").map(|v| v != 0);
+        let mut constraints = vec![];
+        let mut id = 0;
+        while let Ok(name) = fs::read_to_string(format!("{}/constraint_{}_name", self.path, id)) {
+            constraints.push(PowerConstraint {
+                id,
+                name: String::from(name.trim()),
+                power_limit_uw: self.read_value(&format!("constraint_{}_power_limit_uw", id)),
+                time_window_us: self.read_value(&format!("constraint_{}_time_window_us", id)),
+                max_power_uw: self.read_value(&format!("constraint_{}_max_power_uw", id)),
+            });
+            id += 1;
+        }
+        self.constraints = constraints;
+    }
+
+    /// Returns the numerical value of the *file* of the zone, if it can be read.
+    fn read_value(&self, file: &str) -> Option<u64> {
+        fs::read_to_string(format!("{}/{}", self.path, file))
+            .ok()?
+            .trim()
+            .parse::<u64>()
+            .ok()
+    }
+}
+
+/// Adds a PowercapZone to the sockets and domains of *topo* whose energy
+/// counter is the energy_uj file of a zone having power limits.
+pub fn add_powercap_zones(topo: &mut Topology) {
+    let zone_of = |counter_uj_path: &str| {
+        let path = counter_uj_path.strip_suffix("/energy_uj")?;
+        let zone = PowercapZone::new(path);
+        if zone.enabled.is_none() && zone.constraints.is_empty() {
+            return None;
+        }
+        Some(zone)
+    };
+    for socket in topo.get_sockets() {
+        socket.powercap_zone = zone_of(&socket.counter_uj_path);
+        for domain in socket.get_domains() {
+            domain.powercap_zone = zone_of(&domain.counter_uj_path);
+        }
+    }
+}
+
+/// Role of a RAPL powercap zone in the topology.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RaplZoneKind {
+    /// Package zone, measuring a CPU socket
+    Socket(u16),
+    /// Sub-zone of a package (core, uncore, dram...)
+    Domain { socket_id: u16, domain_id: u16 },
+    /// Platform zone (psys), measuring the whole SoC/platform
+    Platform(u16),
+}
+
+/// RAPL powercap zone exposing an energy counter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RaplZone {
+    /// Path to the zone folder, like /sys/class/powercap/intel-rapl:0:1
+    pub path: String,
+    /// Content of the name file of the zone (package-0, core, dram, psys...)
+    pub name: String,
+    pub kind: RaplZoneKind,
+}
+
+/// Walks the intel-rapl and intel-rapl-mmio zones hierarchy found in
+/// *base_path* and returns the zones having an energy counter, each package
+/// zone being followed by its sub-zones.
+/// Zones are found at the root of *base_path* as well as in the folder of
+/// their parent zone. Socket ids are taken from the package-N names of the
+/// zones (psys zones take zone numbers in sysfs) and MMIO zones are ignored
+/// when the same package is already exposed by the MSR interface.
+pub fn discover_rapl_zones(base_path: &str) -> Result<Vec<RaplZone>, Box<dyn Error>> {
+    let re_zone = Regex::new(r"^intel-rapl(-mmio)?:(\d+)$").unwrap();
+    let re_package = Regex::new(r"^package-(\d+)$").unwrap();
+    // (mmio, zone number, folder name)
+    let mut top_zones = vec![];
+    for folder in fs::read_dir(base_path)? {
+        // names that aren't valid UTF-8 can't be zones
+        let folder_name = match folder?.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if let Some(captures) = re_zone.captures(&folder_name) {
+            top_zones.push((
+                captures.get(1).is_some(),
+                captures[2].parse::<u16>()?,
+                folder_name,
+            ));
+        }
+    }
+    top_zones.sort();
+
+    let mut zones = vec![];
+    let mut sockets_ids = vec![];
+    let mut platforms_nb = 0;
+    for (mmio, number, folder_name) in top_zones {
+        let path = format!("{}/{}", base_path, folder_name);
+        let name = match fs::read_to_string(format!("{}/name", path)) {
+            Ok(name) => String::from(name.trim()),
+            Err(_) => continue,
+        };
+        let has_counter = Path::new(&format!("{}/energy_uj", path)).exists();
+        if name == "psys" {
+            if has_counter {
+                zones.push(RaplZone {
+                    path,
+                    name,
+                    kind: RaplZoneKind::Platform(platforms_nb),
+                });
+                platforms_nb += 1;
+            }
+            continue;
+        }
+        let socket_id = match re_package.captures(&name) {
+            Some(captures) => captures[1].parse::<u16>()?,
+            None => number,
+        };
+        if sockets_ids.contains(&socket_id) {
+            debug!(
+                "Zone {} measures an already known socket, skipping it.",
+                path
+            );
+            continue;
+        }
+        if !has_counter {
+            debug!("Zone {} has no energy counter, skipping it.", path);
+            continue;
+        }
+        sockets_ids.push(socket_id);
+        zones.push(RaplZone {
+            path: path.clone(),
+            name,
+            kind: RaplZoneKind::Socket(socket_id),
+        });
+
+        // sub-zones are linked at the root of the powercap class and in
+        // the folder of their parent zone
+        let prefix = if mmio {
+            "intel-rapl-mmio"
+        } else {
+            "intel-rapl"
+        };
+        let re_child = Regex::new(&format!(r"^{}:{}:(\d+)$", prefix, number)).unwrap();
+        let mut children = BTreeMap::new();
+        for parent in &[&path, &String::from(base_path)] {
+            for folder in fs::read_dir(parent)? {
+                let folder_name = match folder?.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                };
+                if let Some(captures) = re_child.captures(&folder_name) {
+                    children.insert(
+                        captures[1].parse::<u16>()?,
+                        format!("{}/{}", parent, folder_name),
+                    );
+                }
+            }
+        }
+        for (domain_id, child_path) in children {
+            if let Ok(domain_name) = fs::read_to_string(format!("{}/name", child_path)) {
+                zones.push(RaplZone {
+                    path: child_path,
+                    name: String::from(domain_name.trim()),
+                    kind: RaplZoneKind::Domain {
+                        socket_id,
+                        domain_id,
+                    },
+                });
+            }
+        }
+    }
+    Ok(zones)
+}
+
+/// Adds the sockets, domains and platforms measured by the RAPL zones found
+/// in *base_path* to *topo*, with their power limits.
+pub fn add_rapl_zones(
+    topo: &mut Topology,
+    base_path: &str,
+    buffer_per_socket_max_kbytes: u16,
+    buffer_per_domain_max_kbytes: u16,
+) -> Result<(), Box<dyn Error>> {
+    for zone in discover_rapl_zones(base_path)? {
+        let counter_uj_path = format!("{}/energy_uj", zone.path);
+        match zone.kind {
+            RaplZoneKind::Socket(socket_id) => topo.safe_add_socket_with_counter(
+                socket_id,
+                vec![],
+                vec![],
+                counter_uj_path,
+                powercap_zone_counter(&zone.path),
+                buffer_per_socket_max_kbytes,
+            ),
+            RaplZoneKind::Domain {
+                socket_id,
+                domain_id,
+            } => topo.safe_add_domain_to_socket_with_counter(
+                socket_id,
+                domain_id,
+                &zone.name,
+                &counter_uj_path,
+                powercap_zone_counter(&zone.path),
+                buffer_per_domain_max_kbytes,
+            ),
+            RaplZoneKind::Platform(platform_id) => topo.safe_add_platform(
+                platform_id,
+                &zone.name,
+                &counter_uj_path,
+                powercap_zone_counter(&zone.path),
+                buffer_per_socket_max_kbytes,
+            ),
+        }
+    }
+    add_powercap_zones(topo);
+    Ok(())
+}
+
 /// This is a Sensor type that relies on powercap and rapl linux modules
 /// to collect energy consumption from CPU sockets and RAPL domains
 pub struct PowercapRAPLSensor {
@@ -72,37 +333,12 @@ impl Sensor for PowercapRAPLSensor {
             warn!("Couldn't find intel_rapl modules.");
         }
         let mut topo = Topology::new();
-        let re_domain = Regex::new(r"^.*/intel-rapl:\d+:\d+$").unwrap();
-        for folder in fs::read_dir(&self.base_path).unwrap() {
-            let folder_name = String::from(folder.unwrap().path().to_str().unwrap());
-            // let's catch domain folders
-            if re_domain.is_match(&folder_name) {
-                // let's get the second number of the intel-rapl:X:X string
-                let mut splitted = folder_name.split(':');
-                let _ = splitted.next();
-                let socket_id = String::from(splitted.next().unwrap()).parse().unwrap();
-                let domain_id = String::from(splitted.next().unwrap()).parse().unwrap();
-                topo.safe_add_socket(
-                    socket_id,
-                    vec![],
-                    vec![],
-                    format!("{}/intel-rapl:{}/energy_uj", self.base_path, socket_id),
-                    self.buffer_per_socket_max_kbytes,
-                );
-                if let Ok(domain_name) = &fs::read_to_string(format!("{}/name", folder_name)) {
-                    topo.safe_add_domain_to_socket(
-                        socket_id,
-                        domain_id,
-                        domain_name.trim(),
-                        &format!(
-                            "{}/intel-rapl:{}:{}/energy_uj",
-                            self.base_path, socket_id, domain_id
-                        ),
-                        self.buffer_per_domain_max_kbytes,
-                    );
-                }
-            }
-        }
+        add_rapl_zones(
+            &mut topo,
+            &self.base_path,
+            self.buffer_per_socket_max_kbytes,
+            self.buffer_per_domain_max_kbytes,
+        )?;
         topo.add_cpu_cores();
         Ok(topo)
     }
@@ -120,11 +356,115 @@ impl Sensor for PowercapRAPLSensor {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sensors::test_utils::TempDir;
     use std::any::type_name;
+    use std::os::unix::ffi::OsStrExt;
 
     fn type_of<T>(_: T) -> &'static str {
         type_name::<T>()
     }
+    #[test]
+    fn powercap_zone_reads_constraints() {
+        let root = TempDir::new("zone");
+        fs::create_dir_all(&root).unwrap();
+        for (file, value) in [
+            ("enabled", "1"),
+            ("constraint_0_name", "long_term"),
+            ("constraint_0_power_limit_uw", "150000000"),
+            ("constraint_0_time_window_us", "999424"),
+            ("constraint_1_name", "short_term"),
+            ("constraint_1_power_limit_uw", "180000000"),
+        ]
+        .iter()
+        {
+            fs::write(root.join(file), value).unwrap();
+        }
+        let mut zone = PowercapZone::new(root.to_str().unwrap());
+        assert_eq!(zone.enabled, Some(true));
+        assert_eq!(zone.constraints.len(), 2);
+        assert_eq!(
+            zone.constraints[0],
+            PowerConstraint {
+                id: 0,
+                name: String::from("long_term"),
+                power_limit_uw: Some(150_000_000),
+                time_window_us: Some(999_424),
+                max_power_uw: None,
+            }
+        );
+        fs::write(root.join("constraint_1_power_limit_uw"), "120000000").unwrap();
+        fs::write(root.join("enabled"), "0").unwrap();
+        zone.refresh();
+        assert_eq!(zone.enabled, Some(false));
+        assert_eq!(zone.constraints[1].power_limit_uw, Some(120_000_000));
+    }
+
+    #[test]
+    fn discovery_finds_packages_psys_and_mmio_zones() {
+        let root = TempDir::new("discovery");
+        let zones = [
+            ("intel-rapl:0", "package-0", true),
+            ("intel-rapl:0/intel-rapl:0:0", "core", true),
+            ("intel-rapl:1", "psys", true),
+            ("intel-rapl:2", "package-1", true),
+            ("intel-rapl-mmio:0", "package-0", true),
+            ("intel-rapl-mmio:1", "package-2", false),
+            // a package exposed by the MMIO interface only
+            ("intel-rapl-mmio:2", "package-3", true),
+            ("intel-rapl-mmio:2:0", "dram", true),
+        ];
+        for (folder, name, has_counter) in zones.iter() {
+            let path = root.join(folder);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("name"), format!("{}\n", name)).unwrap();
+            if *has_counter {
+                fs::write(path.join("energy_uj"), "1000").unwrap();
+            }
+        }
+        // entries whose names aren't valid UTF-8 are skipped
+        let invalid = std::ffi::OsStr::from_bytes(b"intel-rapl:\xff");
+        fs::create_dir_all(root.join(invalid)).unwrap();
+        fs::create_dir_all(root.join("intel-rapl:0").join(invalid)).unwrap();
+        let base_path = root.to_str().unwrap();
+        let kinds: Vec<(String, RaplZoneKind)> = discover_rapl_zones(base_path)
+            .unwrap()
+            .into_iter()
+            .map(|z| (z.name, z.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (String::from("package-0"), RaplZoneKind::Socket(0)),
+                (
+                    String::from("core"),
+                    RaplZoneKind::Domain {
+                        socket_id: 0,
+                        domain_id: 0
+                    }
+                ),
+                (String::from("psys"), RaplZoneKind::Platform(0)),
+                (String::from("package-1"), RaplZoneKind::Socket(1)),
+                (String::from("package-3"), RaplZoneKind::Socket(3)),
+                (
+                    String::from("dram"),
+                    RaplZoneKind::Domain {
+                        socket_id: 3,
+                        domain_id: 0
+                    }
+                ),
+            ]
+        );
+
+        let mut topo = Topology::new();
+        add_rapl_zones(&mut topo, base_path, 1, 1).unwrap();
+        assert_eq!(topo.sockets.len(), 3);
+        assert_eq!(
+            topo.sockets[1].counter_uj_path,
+            format!("{}/intel-rapl:2/energy_uj", base_path)
+        );
+        assert_eq!(topo.platforms.len(), 1);
+    }
+
     #[test]
     fn get_topology_returns_topology_type() {
         let mut sensor = PowercapRAPLSensor::new(1, 1, false);
@@ -0,0 +1,477 @@
+use crate::sensors::counters::{IntegratedPowerCounter, PowerMeter};
+use crate::sensors::{Sensor, Topology};
+use isahc::auth::{Authentication, Credentials};
+use isahc::config::{Configurable, SslOption};
+use isahc::{HttpClient, ReadResponseExt, Request};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Path of the collection of chassis managed by the BMC.
+const CHASSIS_COLLECTION: &str = "/redfish/v1/Chassis";
+/// Path of the collection of sessions, where session tokens are requested.
+const SESSIONS_COLLECTION: &str = "/redfish/v1/SessionService/Sessions";
+
+/// HTTP client for the Redfish API of a BMC, authenticating either with
+/// basic auth on each request or with a session token.
+/// Clones share the same session token.
+#[derive(Clone)]
+pub struct RedfishClient {
+    base_url: String,
+    username: String,
+    password: String,
+    use_session: bool,
+    http_client: HttpClient,
+    token: Arc<Mutex<Option<String>>>,
+}
+
+impl fmt::Debug for RedfishClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "RedfishClient {{ base_url: {}, username: {}, use_session: {} }}",
+            self.base_url, self.username, self.use_session
+        )
+    }
+}
+
+impl RedfishClient {
+    /// Instantiates RedfishClient and returns the instance.
+    /// *base_url* is the scheme and host of the BMC, like https://bmc.example.org
+    pub fn new(
+        base_url: &str,
+        username: &str,
+        password: &str,
+        use_session: bool,
+        accept_invalid_certs: bool,
+    ) -> Result<RedfishClient, Box<dyn Error>> {
+        let mut builder = HttpClient::builder().timeout(Duration::from_secs(10));
+        if accept_invalid_certs {
+            builder = builder.ssl_options(
+                SslOption::DANGER_ACCEPT_INVALID_CERTS | SslOption::DANGER_ACCEPT_INVALID_HOSTS,
+            );
+        }
+        Ok(RedfishClient {
+            base_url: String::from(base_url.trim_end_matches('/')),
+            username: String::from(username),
+            password: String::from(password),
+            use_session,
+            http_client: builder.build()?,
+            token: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Returns the current session token, creating a session if there is none.
+    fn get_token(&self) -> Result<String, Box<dyn Error>> {
+        let mut token = self.token.lock().unwrap();
+        if let Some(value) = token.as_ref() {
+            return Ok(value.clone());
+        }
+        let body = json!({"UserName": self.username, "Password": self.password}).to_string();
+        let request = Request::post(format!("{}{}", self.base_url, SESSIONS_COLLECTION))
+            .header("Content-Type", "application/json")
+            .body(body)?;
+        let response = self.http_client.send(request)?;
+        if !response.status().is_success() {
+            return Err(From::from(format!(
+                "Couldn't create Redfish session: {}",
+                response.status()
+            )));
+        }
+        let value = match response.headers().get("X-Auth-Token") {
+            Some(header) => String::from(header.to_str()?),
+            None => return Err(From::from("No X-Auth-Token in Redfish session response")),
+        };
+        debug!("Created Redfish session on {}", self.base_url);
+        *token = Some(value.clone());
+        Ok(value)
+    }
+
+    /// Gets a Redfish resource and returns it as a JSON value.
+    pub fn get_json(&self, path: &str) -> Result<Value, Box<dyn Error>> {
+        let url = format!("{}{}", self.base_url, path);
+        for attempt in 0..2 {
+            let mut builder = Request::get(&url).header("Accept", "application/json");
+            if self.use_session {
+                builder = builder.header("X-Auth-Token", self.get_token()?);
+            } else {
+                builder = builder
+                    .authentication(Authentication::basic())
+                    .credentials(Credentials::new(&self.username, &self.password));
+            }
+            let mut response = self.http_client.send(builder.body(())?)?;
+            if response.status() == 401 && self.use_session && attempt == 0 {
+                // the session has probably expired, let's open a new one
+                *self.token.lock().unwrap() = None;
+                continue;
+            }
+            if !response.status().is_success() {
+                return Err(From::from(format!("GET {}: {}", url, response.status())));
+            }
+            return Ok(serde_json::from_str(&response.text()?)?);
+        }
+        Err(From::from(format!("GET {}: unauthorized", url)))
+    }
+}
+
+/// PowerMeter reading a power value, in watts, from a Redfish resource.
+#[derive(Debug, Clone)]
+pub struct RedfishPowerMeter {
+    /// Client used to get the resource
+    pub client: RedfishClient,
+    /// Path of the resource, like /redfish/v1/Chassis/1/Power
+    pub path: String,
+    /// JSON pointer to the power value in the resource, like /PowerControl/0/PowerConsumedWatts
+    pub pointer: String,
+}
+
+impl RedfishPowerMeter {
+    /// Instantiates RedfishPowerMeter and returns the instance.
+    pub fn new(client: &RedfishClient, path: &str, pointer: &str) -> RedfishPowerMeter {
+        RedfishPowerMeter {
+            client: client.clone(),
+            path: String::from(path),
+            pointer: String::from(pointer),
+        }
+    }
+}
+
+impl PowerMeter for RedfishPowerMeter {
+    fn read_uw(&mut self) -> Result<u64, Box<dyn Error>> {
+        let resource = self.client.get_json(&self.path)?;
+        match resource.pointer(&self.pointer).and_then(|v| v.as_f64()) {
+            Some(watts) => Ok((watts * 1_000_000.0) as u64),
+            None => Err(From::from(format!(
+                "No power value at {} in {}",
+                self.pointer, self.path
+            ))),
+        }
+    }
+}
+
+/// Power value found in a Redfish chassis, as a component name and a meter.
+type RedfishChannel = (String, RedfishPowerMeter);
+
+/// This is a Sensor type that relies on the Redfish API of a BMC to get the
+/// power consumed by the chassis and their power supplies, from the Power
+/// resource (PowerControl and PowerSupplies) or from the newer EnvironmentMetrics
+/// resource. The power of the first chassis is used as the host consumption.
+pub struct RedfishSensor {
+    base_url: String,
+    username: String,
+    password: String,
+    use_session: bool,
+    accept_invalid_certs: bool,
+    buffer_per_component_max_kbytes: u16,
+}
+
+impl RedfishSensor {
+    /// Instantiates and returns an instance of RedfishSensor.
+    pub fn new(
+        buffer_per_component_max_kbytes: u16,
+        base_url: &str,
+        username: &str,
+        password: &str,
+        use_session: bool,
+        accept_invalid_certs: bool,
+    ) -> RedfishSensor {
+        RedfishSensor {
+            base_url: String::from(base_url),
+            username: String::from(username),
+            password: String::from(password),
+            use_session,
+            accept_invalid_certs,
+            buffer_per_component_max_kbytes,
+        }
+    }
+
+    /// Returns the power channels of a chassis. The first one, if any,
+    /// measures the whole chassis.
+    fn list_chassis_channels(
+        client: &RedfishClient,
+        chassis_path: &str,
+    ) -> Result<Vec<RedfishChannel>, Box<dyn Error>> {
+        let chassis = client.get_json(chassis_path)?;
+        let chassis_id = chassis["Id"].as_str().unwrap_or(chassis_path);
+        let mut channels = vec![];
+        if let Some(power_path) = chassis["Power"]["@odata.id"].as_str() {
+            let power = client.get_json(power_path)?;
+            if let Some(controls) = power["PowerControl"].as_array() {
+                for (i, control) in controls.iter().enumerate() {
+                    if control["PowerConsumedWatts"].is_number() {
+                        channels.push((
+                            format!("{}/power_control{}", chassis_id, i),
+                            RedfishPowerMeter::new(
+                                client,
+                                power_path,
+                                &format!("/PowerControl/{}/PowerConsumedWatts", i),
+                            ),
+                        ));
+                    }
+                }
+            }
+            if let Some(supplies) = power["PowerSupplies"].as_array() {
+                for (i, supply) in supplies.iter().enumerate() {
+                    let name = match (supply["Name"].as_str(), supply["MemberId"].as_str()) {
+                        (Some(name), _) => String::from(name),
+                        (None, Some(member_id)) => format!("psu{}", member_id),
+                        (None, None) => format!("psu{}", i),
+                    };
+                    for field in ["PowerInputWatts", "LastPowerOutputWatts"].iter() {
+                        if supply[field].is_number() {
+                            channels.push((
+                                format!("{}/{}", chassis_id, name),
+                                RedfishPowerMeter::new(
+                                    client,
+                                    power_path,
+                                    &format!("/PowerSupplies/{}/{}", i, field),
+                                ),
+                            ));
+                            break;
+                        }
+                    }
+                }
+            }
+        } else if let Some(metrics_path) = chassis["EnvironmentMetrics"]["@odata.id"].as_str() {
+            let metrics = client.get_json(metrics_path)?;
+            if metrics["PowerWatts"]["Reading"].is_number() {
+                channels.push((
+                    format!("{}/environment", chassis_id),
+                    RedfishPowerMeter::new(client, metrics_path, "/PowerWatts/Reading"),
+                ));
+            }
+        }
+        Ok(channels)
+    }
+}
+
+impl Sensor for RedfishSensor {
+    /// Creates a Topology instance with a component per chassis and power supply.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let client = RedfishClient::new(
+            &self.base_url,
+            &self.username,
+            &self.password,
+            self.use_session,
+            self.accept_invalid_certs,
+        )?;
+        let collection = client.get_json(CHASSIS_COLLECTION)?;
+        let mut channels = vec![];
+        let mut host_meter = None;
+        if let Some(members) = collection["Members"].as_array() {
+            for member in members {
+                if let Some(chassis_path) = member["@odata.id"].as_str() {
+                    let chassis_channels =
+                        RedfishSensor::list_chassis_channels(&client, chassis_path)?;
+                    if host_meter.is_none() && !chassis_channels.is_empty() {
+                        host_meter = Some(chassis_channels[0].1.clone());
+                    }
+                    channels.extend(chassis_channels);
+                }
+            }
+        }
+        let host_meter = match host_meter {
+            Some(meter) => meter,
+            None => {
+                return Err(From::from(format!(
+                    "No chassis power reading found on {}",
+                    self.base_url
+                )))
+            }
+        };
+        let mut topo = Topology::new();
+        for (id, (name, meter)) in channels.into_iter().enumerate() {
+            debug!("Adding Redfish channel {} ({})", name, meter.path);
+            let path = format!("{}#{}", meter.path, meter.pointer);
+            topo.safe_add_component(
+                id as u16,
+                &name,
+                &path,
+                Box::new(IntegratedPowerCounter::new(meter)),
+                self.buffer_per_component_max_kbytes,
+            );
+        }
+        topo.host_counter = Some(Box::new(IntegratedPowerCounter::new(host_meter)));
+        Ok(topo)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::RecordGenerator;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// Answers a request the way a BMC would, accepting basic auth as
+    /// admin:secret or the token of a session created with those credentials.
+    fn handle_request(mut stream: TcpStream) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut authorized = false;
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_at(line.find(':').unwrap());
+            let value = value[1..].trim();
+            match &name.to_lowercase()[..] {
+                // admin:secret
+                "authorization" => authorized = value == "Basic YWRtaW46c2VjcmV0",
+                "x-auth-token" => authorized = value == "token42",
+                "content-length" => content_length = value.parse().unwrap(),
+                _ => {}
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap();
+        let path = parts.next().unwrap();
+        let (status, headers, body) = match (method, path, authorized) {
+            ("POST", "/redfish/v1/SessionService/Sessions", _) => {
+                let credentials: Value = serde_json::from_slice(&body).unwrap();
+                if credentials == json!({"UserName": "admin", "Password": "secret"}) {
+                    ("201 Created", "X-Auth-Token: token42\r\n", String::from("{}"))
+                } else {
+                    ("401 Unauthorized", "", String::from("{}"))
+                }
+            }
+            (_, _, false) => ("401 Unauthorized", "", String::from("{}")),
+            (_, "/redfish/v1/Chassis", _) => (
+                "200 OK",
+                "",
+                json!({"Members": [
+                    {"@odata.id": "/redfish/v1/Chassis/1"},
+                    {"@odata.id": "/redfish/v1/Chassis/2"}
+                ]})
+                .to_string(),
+            ),
+            (_, "/redfish/v1/Chassis/1", _) => (
+                "200 OK",
+                "",
+                json!({"Id": "1", "Power": {"@odata.id": "/redfish/v1/Chassis/1/Power"}})
+                    .to_string(),
+            ),
+            (_, "/redfish/v1/Chassis/1/Power", _) => (
+                "200 OK",
+                "",
+                json!({
+                    "PowerControl": [{"PowerConsumedWatts": 250}],
+                    "PowerSupplies": [
+                        {"Name": "PSU1", "PowerInputWatts": 130},
+                        {"MemberId": "1", "LastPowerOutputWatts": 118.5},
+                        {"Name": "PSU3", "PowerInputWatts": null}
+                    ]
+                })
+                .to_string(),
+            ),
+            (_, "/redfish/v1/Chassis/2", _) => (
+                "200 OK",
+                "",
+                json!({"Id": "2", "EnvironmentMetrics": {"@odata.id": "/redfish/v1/Chassis/2/EnvironmentMetrics"}})
+                    .to_string(),
+            ),
+            (_, "/redfish/v1/Chassis/2/EnvironmentMetrics", _) => (
+                "200 OK",
+                "",
+                json!({"PowerWatts": {"Reading": 42.5}}).to_string(),
+            ),
+            _ => ("404 Not Found", "", String::from("{}")),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            headers,
+            body.len(),
+            body
+        )
+        .unwrap();
+    }
+
+    fn start_mock_bmc() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                handle_request(stream);
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    #[test]
+    fn redfish_sensor_maps_chassis_and_psus_with_basic_auth() {
+        let url = start_mock_bmc();
+        let sensor = RedfishSensor::new(1, &url, "admin", "secret", false, false);
+        let mut topo = sensor.generate_topology().unwrap();
+        let names: Vec<&str> = topo.components.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["1/power_control0", "1/PSU1", "1/psu1", "2/environment"]
+        );
+        topo.refresh_record();
+        assert_eq!(topo.record_buffer.len(), 1);
+        let component = &mut topo.get_components()[3];
+        component.refresh_record();
+        assert_eq!(component.record_buffer.len(), 1);
+
+        let sensor = RedfishSensor::new(1, &url, "admin", "wrong", false, false);
+        assert!(sensor.generate_topology().is_err());
+    }
+
+    #[test]
+    fn redfish_meter_reads_power_with_session_token() {
+        let url = start_mock_bmc();
+        let client = RedfishClient::new(&url, "admin", "secret", true, false).unwrap();
+        let mut meter = RedfishPowerMeter::new(
+            &client,
+            "/redfish/v1/Chassis/1/Power",
+            "/PowerSupplies/1/LastPowerOutputWatts",
+        );
+        assert_eq!(meter.read_uw().unwrap(), 118_500_000);
+        assert_eq!(
+            client.token.lock().unwrap().as_ref().unwrap().as_str(),
+            "token42"
+        );
+        let mut meter = RedfishPowerMeter::new(
+            &client,
+            "/redfish/v1/Chassis/1/Power",
+            "/PowerSupplies/2/PowerInputWatts",
+        );
+        assert!(meter.read_uw().is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
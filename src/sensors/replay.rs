@@ -0,0 +1,747 @@
+use crate::sensors::counters::EnergyCounter;
+use crate::sensors::utils::{current_system_time_since_epoch, set_replayed_time};
+use crate::sensors::{RecordGenerator, Sensor, Topology};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::env;
+use std::error::Error;
+use std::ffi::{CString, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Energy counters and procfs data measured during one refresh of a Topology.
+/// A trace file contains one frame per line, as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceFrame {
+    /// Time of the measurement, since epoch
+    pub timestamp: Duration,
+    /// Energy consumed by the whole host, in microjoules, if the sensor has a host counter
+    pub host_uj: Option<u64>,
+    pub sockets: Vec<SocketFrame>,
+    pub components: Vec<ComponentFrame>,
+    /// Platform (psys) power domains, missing from traces recorded by older versions
+    #[serde(default)]
+    pub platforms: Vec<ComponentFrame>,
+    /// Content of /proc/stat
+    pub stat: StatFrame,
+    /// Processes listed in /proc
+    pub processes: Vec<ProcessFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SocketFrame {
+    pub id: u16,
+    pub uj: u64,
+    pub domains: Vec<DomainFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DomainFrame {
+    pub id: u16,
+    pub name: String,
+    pub uj: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ComponentFrame {
+    pub id: u16,
+    pub name: String,
+    pub uj: u64,
+}
+
+/// CPU time totals (in jiffies) and counters found in /proc/stat.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatFrame {
+    pub user: u64,
+    pub nice: u64,
+    pub system: u64,
+    pub idle: u64,
+    pub iowait: Option<u64>,
+    pub irq: Option<u64>,
+    pub softirq: Option<u64>,
+    pub steal: Option<u64>,
+    pub guest: Option<u64>,
+    pub guest_nice: Option<u64>,
+    pub ctxt: u64,
+    pub processes: u64,
+    pub procs_running: Option<u32>,
+    pub procs_blocked: Option<u32>,
+}
+
+/// Data of a process found in /proc/[pid].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProcessFrame {
+    pub pid: i32,
+    pub ppid: i32,
+    pub comm: String,
+    pub state: char,
+    pub cmdline: Vec<String>,
+    pub exe: Option<String>,
+    /// CPU time spent in user mode, in jiffies
+    pub utime: u64,
+    /// CPU time spent in kernel mode, in jiffies
+    pub stime: u64,
+}
+
+/// Trace recorded or replayed each time a Topology is refreshed.
+#[derive(Debug, Clone)]
+pub enum Trace {
+    Record(TraceRecorder),
+    Replay(TracePlayer),
+}
+
+/// Appends a TraceFrame to a trace file after each refresh of a Topology.
+#[derive(Debug, Clone)]
+pub struct TraceRecorder {
+    pub path: String,
+}
+
+impl TraceRecorder {
+    /// Instantiates TraceRecorder and returns the instance.
+    /// The trace file is created, or truncated if it exists.
+    pub fn new(path: &str) -> Result<TraceRecorder, Box<dyn Error>> {
+        File::create(path)?;
+        Ok(TraceRecorder {
+            path: String::from(path),
+        })
+    }
+
+    /// Writes the measurements of the last refresh of *topology* to the trace file.
+    pub fn record(&self, topology: &Topology) -> Result<(), Box<dyn Error>> {
+        let frame = TraceRecorder::build_frame(topology)?;
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(&frame)?)?;
+        Ok(())
+    }
+
+    /// Builds a TraceFrame from the last records of *topology*.
+    pub fn build_frame(topology: &Topology) -> Result<TraceFrame, Box<dyn Error>> {
+        let mut sockets = vec![];
+        for s in topology.get_sockets_passive() {
+            if let Some(uj) = last_record_uj(&s.get_records_passive()) {
+                let mut domains = vec![];
+                for d in s.get_domains_passive() {
+                    if let Some(uj) = last_record_uj(&d.get_records_passive()) {
+                        domains.push(DomainFrame {
+                            id: d.id,
+                            name: d.name.clone(),
+                            uj,
+                        });
+                    }
+                }
+                sockets.push(SocketFrame {
+                    id: s.id,
+                    uj,
+                    domains,
+                });
+            }
+        }
+        let mut components = vec![];
+        for c in topology.get_components_passive() {
+            if let Some(uj) = last_record_uj(&c.get_records_passive()) {
+                components.push(ComponentFrame {
+                    id: c.id,
+                    name: c.name.clone(),
+                    uj,
+                });
+            }
+        }
+        let mut platforms = vec![];
+        for p in topology.get_platforms_passive() {
+            if let Some(uj) = last_record_uj(&p.get_records_passive()) {
+                platforms.push(ComponentFrame {
+                    id: p.id,
+                    name: p.name.clone(),
+                    uj,
+                });
+            }
+        }
+        let host_uj = match topology.host_counter {
+            Some(_) => last_record_uj(&topology.record_buffer),
+            None => None,
+        };
+
+        let cpu = topology
+            .stat_buffer
+            .first()
+            .ok_or("No CPU stats measured yet")?;
+        let kernel_stats = topology.read_kernel_stats()?;
+        let stat = StatFrame {
+            user: cpu.user,
+            nice: cpu.nice,
+            system: cpu.system,
+            idle: cpu.idle,
+            iowait: cpu.iowait,
+            irq: cpu.irq,
+            softirq: cpu.softirq,
+            steal: cpu.steal,
+            guest: cpu.guest,
+            guest_nice: cpu.guest_nice,
+            ctxt: kernel_stats.ctxt,
+            processes: kernel_stats.processes,
+            procs_running: kernel_stats.procs_running,
+            procs_blocked: kernel_stats.procs_blocked,
+        };
+
+        // only the processes still running have to be recorded, the tracker
+        // also keeps the records of terminated ones
+        let running_pids = list_pids(Path::new(&topology.procfs_root))?;
+        let mut processes = vec![];
+        for records in &topology.proc_tracker.procs {
+            if let Some(record) = records.first() {
+                let p = &record.process;
+                if running_pids.contains(&p.pid) {
+                    processes.push(ProcessFrame {
+                        pid: p.pid,
+                        ppid: p.stat.ppid,
+                        comm: p.stat.comm.clone(),
+                        state: p.stat.state,
+                        cmdline: p.cmdline().unwrap_or_default(),
+                        exe: p.exe().ok().map(|x| x.to_string_lossy().to_string()),
+                        utime: p.stat.utime,
+                        stime: p.stat.stime,
+                    });
+                }
+            }
+        }
+        processes.sort_by_key(|p| p.pid);
+
+        Ok(TraceFrame {
+            timestamp: current_system_time_since_epoch(),
+            host_uj,
+            sockets,
+            components,
+            platforms,
+            stat,
+            processes,
+        })
+    }
+}
+
+/// Returns the value of the last record of *records*, in microjoules.
+fn last_record_uj(records: &[crate::sensors::Record]) -> Option<u64> {
+    records.last()?.value.trim().parse::<u64>().ok()
+}
+
+/// Returns the pids of the processes found in the procfs filesystem mounted on *root*.
+fn list_pids(root: &Path) -> Result<HashSet<i32>, Box<dyn Error>> {
+    let mut pids = HashSet::new();
+    for entry in fs::read_dir(root)? {
+        if let Ok(pid) = entry?.file_name().to_string_lossy().parse::<i32>() {
+            pids.insert(pid);
+        }
+    }
+    Ok(pids)
+}
+
+/// Creates a directory only readable by the current user in the temporary
+/// directory of the system, with a unique name starting with *prefix*, and
+/// returns its path. As mkdtemp fails if the path already exists, nothing
+/// planted in a shared /tmp (a symlink...) is ever followed.
+fn create_private_dir(prefix: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let template = env::temp_dir().join(format!("{}-XXXXXX", prefix));
+    let template = CString::new(template.into_os_string().into_vec())?;
+    let mut template = template.into_bytes_with_nul();
+    let path = unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) };
+    if path.is_null() {
+        return Err(From::from(format!(
+            "Couldn't create a directory in {}: {}",
+            env::temp_dir().display(),
+            io::Error::last_os_error()
+        )));
+    }
+    template.pop();
+    Ok(PathBuf::from(OsString::from_vec(template)))
+}
+
+/// Frames of a trace and position of the current one, shared by the clones
+/// of a TracePlayer. The procfs-like directory is removed when dropped.
+#[derive(Debug)]
+struct PlayerState {
+    frames: Vec<TraceFrame>,
+    position: Option<usize>,
+    procfs_root: PathBuf,
+}
+
+impl Drop for PlayerState {
+    fn drop(&mut self) {
+        if self.procfs_root.exists() {
+            if let Err(e) = fs::remove_dir_all(&self.procfs_root) {
+                warn!("Couldn't remove {}: {}", self.procfs_root.display(), e);
+            }
+        }
+    }
+}
+
+/// Reads the frames of a trace file and makes them the current measurements,
+/// one after the other. For each frame, the recorded procfs data is written
+/// to a procfs-like directory, *procfs_root*, and the recorded timestamp
+/// becomes the current time of the thread.
+#[derive(Debug, Clone)]
+pub struct TracePlayer {
+    pub procfs_root: PathBuf,
+    state: Arc<Mutex<PlayerState>>,
+}
+
+impl TracePlayer {
+    /// Loads the trace file at *path* and returns a TracePlayer, with no
+    /// current frame yet.
+    pub fn open(path: &str, procfs_root: PathBuf) -> Result<TracePlayer, Box<dyn Error>> {
+        let mut frames = vec![];
+        for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<TraceFrame>(&line) {
+                Ok(frame) => frames.push(frame),
+                Err(e) => {
+                    return Err(From::from(format!(
+                        "{}:{}: invalid trace frame: {}",
+                        path,
+                        index + 1,
+                        e
+                    )))
+                }
+            }
+        }
+        if frames.is_empty() {
+            return Err(From::from(format!("{} contains no trace frame", path)));
+        }
+        Ok(TracePlayer {
+            procfs_root: procfs_root.clone(),
+            state: Arc::new(Mutex::new(PlayerState {
+                frames,
+                position: None,
+                procfs_root,
+            })),
+        })
+    }
+
+    /// Returns the first frame of the trace.
+    pub fn first_frame(&self) -> TraceFrame {
+        self.state.lock().unwrap().frames[0].clone()
+    }
+
+    /// Returns the current frame, if the replay has started.
+    pub fn current_frame(&self) -> Option<TraceFrame> {
+        let state = self.state.lock().unwrap();
+        state
+            .position
+            .map(|position| state.frames[position].clone())
+    }
+
+    /// Makes the next frame of the trace the current one.
+    /// Returns false if the end of the trace has been reached.
+    pub fn next_frame(&self) -> Result<bool, Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        let position = state.position.map_or(0, |p| p + 1);
+        if position >= state.frames.len() {
+            return Ok(false);
+        }
+        let frame = &state.frames[position];
+        write_procfs(&self.procfs_root, frame)?;
+        set_replayed_time(Some(frame.timestamp));
+        state.position = Some(position);
+        Ok(true)
+    }
+}
+
+/// Writes the stat file and the [pid] folders of a procfs filesystem
+/// on *root*, as recorded in *frame*. Folders of processes that are not in
+/// the frame anymore are removed.
+fn write_procfs(root: &Path, frame: &TraceFrame) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(root)?;
+    let stat = &frame.stat;
+    let cpu = format!(
+        "{} {} {} {} {} {} {} {} {} {}",
+        stat.user,
+        stat.nice,
+        stat.system,
+        stat.idle,
+        stat.iowait.unwrap_or_default(),
+        stat.irq.unwrap_or_default(),
+        stat.softirq.unwrap_or_default(),
+        stat.steal.unwrap_or_default(),
+        stat.guest.unwrap_or_default(),
+        stat.guest_nice.unwrap_or_default()
+    );
+    fs::write(
+        root.join("stat"),
+        format!(
+            "cpu  {}\ncpu0 {}\nctxt {}\nbtime 0\nprocesses {}\nprocs_running {}\nprocs_blocked {}\n",
+            cpu,
+            cpu,
+            stat.ctxt,
+            stat.processes,
+            stat.procs_running.unwrap_or_default(),
+            stat.procs_blocked.unwrap_or_default()
+        ),
+    )?;
+
+    let pids: HashSet<i32> = frame.processes.iter().map(|p| p.pid).collect();
+    for pid in list_pids(root)? {
+        if !pids.contains(&pid) {
+            fs::remove_dir_all(root.join(pid.to_string()))?;
+        }
+    }
+    for p in &frame.processes {
+        let dir = root.join(p.pid.to_string());
+        fs::create_dir_all(&dir)?;
+        // pid (comm) state ppid, then 48 fields of which only utime and stime matter
+        let mut fields = vec![0; 48];
+        fields[9] = p.utime;
+        fields[10] = p.stime;
+        fields[15] = 1; // num_threads
+        let fields: Vec<String> = fields.iter().map(|f| f.to_string()).collect();
+        fs::write(
+            dir.join("stat"),
+            format!(
+                "{} ({}) {} {} {}\n",
+                p.pid,
+                p.comm,
+                p.state,
+                p.ppid,
+                fields.join(" ")
+            ),
+        )?;
+        fs::write(dir.join("status"), process_status(p))?;
+        let mut cmdline = p.cmdline.join("\0");
+        if !cmdline.is_empty() {
+            cmdline.push('\0');
+        }
+        fs::write(dir.join("cmdline"), cmdline)?;
+        let exe = dir.join("exe");
+        if fs::symlink_metadata(&exe).is_ok() {
+            fs::remove_file(&exe)?;
+        }
+        if let Some(target) = &p.exe {
+            symlink(target, &exe)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the content of a /proc/[pid]/status file for the process *p*.
+fn process_status(p: &ProcessFrame) -> String {
+    format!(
+        "Name:\t{comm}\nState:\t{state}\nTgid:\t{pid}\nPid:\t{pid}\nPPid:\t{ppid}\nTracerPid:\t0\n\
+         Uid:\t0\t0\t0\t0\nGid:\t0\t0\t0\t0\nFDSize:\t0\nGroups:\t\nThreads:\t1\nSigQ:\t0/0\n\
+         SigPnd:\t0\nShdPnd:\t0\nSigBlk:\t0\nSigIgn:\t0\nSigCgt:\t0\nCapInh:\t0\nCapPrm:\t0\n\
+         CapEff:\t0\n",
+        comm = p.comm,
+        state = p.state,
+        pid = p.pid,
+        ppid = p.ppid
+    )
+}
+
+/// Part of a TraceFrame an energy counter is replayed from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplayedCounter {
+    Host,
+    Socket(u16),
+    Domain(u16, u16),
+    Component(u16),
+    Platform(u16),
+}
+
+/// EnergyCounter returning the value recorded in the current frame of a TracePlayer.
+#[derive(Debug, Clone)]
+pub struct ReplayEnergyCounter {
+    pub player: TracePlayer,
+    pub counter: ReplayedCounter,
+}
+
+impl EnergyCounter for ReplayEnergyCounter {
+    fn read_uj(&mut self) -> Result<u64, Box<dyn Error>> {
+        let frame = self
+            .player
+            .current_frame()
+            .ok_or("The replay of the trace hasn't started")?;
+        let value = match self.counter {
+            ReplayedCounter::Host => frame.host_uj,
+            ReplayedCounter::Socket(socket_id) => frame
+                .sockets
+                .iter()
+                .find(|s| s.id == socket_id)
+                .map(|s| s.uj),
+            ReplayedCounter::Domain(socket_id, domain_id) => frame
+                .sockets
+                .iter()
+                .find(|s| s.id == socket_id)
+                .and_then(|s| s.domains.iter().find(|d| d.id == domain_id))
+                .map(|d| d.uj),
+            ReplayedCounter::Component(component_id) => frame
+                .components
+                .iter()
+                .find(|c| c.id == component_id)
+                .map(|c| c.uj),
+            ReplayedCounter::Platform(platform_id) => frame
+                .platforms
+                .iter()
+                .find(|p| p.id == platform_id)
+                .map(|p| p.uj),
+        };
+        value.ok_or_else(|| From::from(format!("{:?} not recorded in this frame", self.counter)))
+    }
+
+    fn box_clone(&self) -> Box<dyn EnergyCounter> {
+        Box::new(self.clone())
+    }
+}
+
+/// This is a Sensor type that replays a trace file recorded by scaphandre
+/// (see the --record option) instead of measuring the live system.
+/// Sockets, domains, components, platforms and the host counter are the ones of the
+/// first frame of the trace, processes and CPU stats are read from the
+/// replayed procfs data.
+pub struct ReplaySensor {
+    buffer_per_socket_max_kbytes: u16,
+    buffer_per_domain_max_kbytes: u16,
+    trace_path: String,
+}
+
+impl ReplaySensor {
+    /// Instantiates and returns an instance of ReplaySensor.
+    pub fn new(
+        buffer_per_socket_max_kbytes: u16,
+        buffer_per_domain_max_kbytes: u16,
+        trace_path: &str,
+    ) -> ReplaySensor {
+        ReplaySensor {
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
+            trace_path: String::from(trace_path),
+        }
+    }
+}
+
+impl Sensor for ReplaySensor {
+    /// Creates a Topology instance replaying the trace file.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let procfs_root = create_private_dir("scaphandre-replay")?;
+        let player = TracePlayer::open(&self.trace_path, procfs_root)?;
+        let frame = player.first_frame();
+        let counter = |counter| -> Box<dyn EnergyCounter> {
+            Box::new(ReplayEnergyCounter {
+                player: player.clone(),
+                counter,
+            })
+        };
+
+        let mut topo = Topology::new();
+        for s in &frame.sockets {
+            topo.safe_add_socket_with_counter(
+                s.id,
+                vec![],
+                vec![],
+                self.trace_path.clone(),
+                counter(ReplayedCounter::Socket(s.id)),
+                self.buffer_per_socket_max_kbytes,
+            );
+            for d in &s.domains {
+                topo.safe_add_domain_to_socket_with_counter(
+                    s.id,
+                    d.id,
+                    &d.name,
+                    &self.trace_path,
+                    counter(ReplayedCounter::Domain(s.id, d.id)),
+                    self.buffer_per_domain_max_kbytes,
+                );
+            }
+        }
+        for c in &frame.components {
+            topo.safe_add_component(
+                c.id,
+                &c.name,
+                &self.trace_path,
+                counter(ReplayedCounter::Component(c.id)),
+                self.buffer_per_domain_max_kbytes,
+            );
+        }
+        for p in &frame.platforms {
+            topo.safe_add_platform(
+                p.id,
+                &p.name,
+                &self.trace_path,
+                counter(ReplayedCounter::Platform(p.id)),
+                self.buffer_per_socket_max_kbytes,
+            );
+        }
+        if frame.host_uj.is_some() {
+            topo.host_counter = Some(counter(ReplayedCounter::Host));
+        }
+        topo.procfs_root = player.procfs_root.to_string_lossy().to_string();
+        topo.trace = Some(Trace::Replay(player));
+        Ok(topo)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+/// This is a Sensor type that wraps another sensor and records a trace of
+/// the measurements made each time the topology is refreshed, so that it
+/// can be replayed later with the ReplaySensor.
+pub struct RecordSensor {
+    sensor: Box<dyn Sensor>,
+    trace_path: String,
+}
+
+impl RecordSensor {
+    /// Instantiates and returns an instance of RecordSensor, recording the
+    /// measurements of *sensor* to the file at *trace_path*.
+    pub fn new(sensor: Box<dyn Sensor>, trace_path: &str) -> RecordSensor {
+        RecordSensor {
+            sensor,
+            trace_path: String::from(trace_path),
+        }
+    }
+}
+
+impl Sensor for RecordSensor {
+    /// Creates a Topology instance from the wrapped sensor, recording a trace.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut topo = self.sensor.generate_topology()?;
+        topo.trace = Some(Trace::Record(TraceRecorder::new(&self.trace_path)?));
+        Ok(topo)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let mut topology = *self.sensor.get_topology();
+        if let Some(topo) = topology.as_mut() {
+            match TraceRecorder::new(&self.trace_path) {
+                Ok(recorder) => topo.trace = Some(Trace::Record(recorder)),
+                Err(e) => panic!("Couldn't create trace file {}: {}", self.trace_path, e),
+            }
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::test_utils::TempDir;
+
+    fn frame(seconds: u64, socket_uj: u64, cpu_user: u64, process_utime: u64) -> TraceFrame {
+        TraceFrame {
+            timestamp: Duration::from_secs(seconds),
+            host_uj: None,
+            sockets: vec![SocketFrame {
+                id: 0,
+                uj: socket_uj,
+                domains: vec![DomainFrame {
+                    id: 0,
+                    name: String::from("core"),
+                    uj: socket_uj / 2,
+                }],
+            }],
+            components: vec![],
+            platforms: vec![],
+            stat: StatFrame {
+                user: cpu_user,
+                nice: 0,
+                system: 0,
+                idle: 1000,
+                iowait: Some(0),
+                irq: Some(0),
+                softirq: Some(0),
+                steal: Some(0),
+                guest: Some(0),
+                guest_nice: Some(0),
+                ctxt: 10,
+                processes: 2,
+                procs_running: Some(1),
+                procs_blocked: Some(0),
+            },
+            processes: vec![ProcessFrame {
+                pid: 42,
+                ppid: 1,
+                comm: String::from("stress-ng"),
+                state: 'R',
+                cmdline: vec![String::from("stress-ng"), String::from("--cpu")],
+                exe: Some(String::from("/usr/bin/stress-ng")),
+                utime: process_utime,
+                stime: 0,
+            }],
+        }
+    }
+
+    #[test]
+    fn replay_gives_deterministic_process_power() {
+        use std::os::unix::fs::PermissionsExt;
+        let root = TempDir::new("replay-test");
+        fs::create_dir_all(&root).unwrap();
+        let trace = root.join("trace.jsonl");
+        let lines: Vec<String> = [frame(100, 0, 0, 0), frame(102, 20_000_000, 200, 50)]
+            .iter()
+            .map(|f| serde_json::to_string(f).unwrap())
+            .collect();
+        fs::write(&trace, lines.join("\n")).unwrap();
+
+        let sensor = ReplaySensor::new(1, 1, trace.to_str().unwrap());
+        let mut topo = sensor.generate_topology().unwrap();
+        topo.refresh();
+        topo.refresh();
+        // 20J in 2 seconds, the process used a quarter of the CPU time
+        let socket_power = topo.sockets[0].get_records_diff_power_microwatts();
+        assert_eq!(socket_power.unwrap().value, "10000000");
+        let process_power = topo.get_process_power_consumption_microwatts(42).unwrap();
+        assert_eq!(process_power.value, "2500000");
+        assert_eq!(topo.proc_tracker.get_process_name(42), "stress-ng");
+        assert_eq!(
+            topo.proc_tracker.get_process_cmdline(42).unwrap(),
+            "stress-ng--cpu"
+        );
+        assert_eq!(topo.read_nb_context_switches_total_count(), Some(10));
+
+        // the recorded frame of a replayed topology is the replayed frame
+        let recorded = TraceRecorder::build_frame(&topo).unwrap();
+        assert_eq!(recorded, frame(102, 20_000_000, 200, 50));
+
+        // nothing changes once the end of the trace is reached
+        topo.refresh();
+        assert_eq!(topo.sockets[0].record_buffer.len(), 2);
+        set_replayed_time(None);
+        // each topology replays in its own private directory
+        let other = sensor.generate_topology().unwrap();
+        assert_ne!(other.procfs_root, topo.procfs_root);
+        let mode = fs::metadata(&topo.procfs_root)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+        let procfs_root = PathBuf::from(&topo.procfs_root);
+        drop(topo);
+        assert!(!procfs_root.exists());
+        assert!(Path::new(&other.procfs_root).exists());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
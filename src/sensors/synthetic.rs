@@ -0,0 +1,290 @@
+use crate::sensors::counters::{IntegratedPowerCounter, PowerMeter};
+use crate::sensors::{Sensor, Topology};
+use procfs::{CpuInfo, KernelStats};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::File;
+
+/// Default path of the procfs filesystem the CPU utilization is read from.
+pub const DEFAULT_PROCFS_PATH: &str = "/proc";
+
+/// Model of the power consumed by the CPUs of a host, depending on their
+/// utilization: idle_watts + (max_watts - idle_watts) * utilization ^ curve_exponent
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadModel {
+    /// Power consumed when the CPUs are idle, in watts
+    pub idle_watts: f64,
+    /// Power consumed when the CPUs are fully used, in watts
+    pub max_watts: f64,
+    /// Shape of the curve between idle and max power: 1 is linear, lower
+    /// values make the power grow faster at low utilization
+    pub curve_exponent: f64,
+}
+
+impl LoadModel {
+    /// Instantiates LoadModel and returns the instance.
+    pub fn new(idle_watts: f64, max_watts: f64, curve_exponent: f64) -> LoadModel {
+        LoadModel {
+            idle_watts,
+            max_watts,
+            curve_exponent,
+        }
+    }
+
+    /// Returns the power consumed at *utilization* (between 0 and 1), in watts,
+    /// and only the part depending on the load if *dynamic_only* is true.
+    pub fn power_watts(&self, utilization: f64, dynamic_only: bool) -> f64 {
+        let utilization = utilization.clamp(0.0, 1.0);
+        let dynamic =
+            (self.max_watts - self.idle_watts).max(0.0) * utilization.powf(self.curve_exponent);
+        if dynamic_only {
+            dynamic
+        } else {
+            self.idle_watts + dynamic
+        }
+    }
+}
+
+/// PowerMeter computing the power of a share of the CPUs from a LoadModel
+/// and the CPU utilization found in /proc/stat since the previous read.
+#[derive(Debug, Clone)]
+pub struct SyntheticPowerMeter {
+    pub model: LoadModel,
+    /// Path to the stat file of procfs
+    pub stat_path: String,
+    /// Part of the host CPUs power this meter measures
+    pub share: f64,
+    /// True to measure only the power depending on the load
+    pub dynamic_only: bool,
+    /// Busy and total jiffies at the previous read
+    last_times: Option<(u64, u64)>,
+}
+
+impl SyntheticPowerMeter {
+    /// Instantiates SyntheticPowerMeter and returns the instance.
+    pub fn new(
+        model: LoadModel,
+        stat_path: &str,
+        share: f64,
+        dynamic_only: bool,
+    ) -> SyntheticPowerMeter {
+        SyntheticPowerMeter {
+            model,
+            stat_path: String::from(stat_path),
+            share,
+            dynamic_only,
+            last_times: None,
+        }
+    }
+
+    /// Returns the CPU utilization, between 0 and 1, since the previous
+    /// call (since boot on the first call).
+    pub fn read_utilization(&mut self) -> Result<f64, Box<dyn Error>> {
+        let total = KernelStats::from_reader(File::open(&self.stat_path)?)?.total;
+        let idle = total.idle + total.iowait.unwrap_or_default();
+        let all = total.user
+            + total.nice
+            + total.system
+            + total.idle
+            + total.iowait.unwrap_or_default()
+            + total.irq.unwrap_or_default()
+            + total.softirq.unwrap_or_default()
+            + total.steal.unwrap_or_default();
+        let busy = all - idle;
+        let (busy_diff, all_diff) = match self.last_times {
+            Some((last_busy, last_all)) => {
+                (busy.saturating_sub(last_busy), all.saturating_sub(last_all))
+            }
+            None => (busy, all),
+        };
+        self.last_times = Some((busy, all));
+        if all_diff == 0 {
+            return Ok(0.0);
+        }
+        Ok(busy_diff as f64 / all_diff as f64)
+    }
+}
+
+impl PowerMeter for SyntheticPowerMeter {
+    fn read_uw(&mut self) -> Result<u64, Box<dyn Error>> {
+        let utilization = self.read_utilization()?;
+        let watts = self.model.power_watts(utilization, self.dynamic_only) * self.share;
+        Ok((watts * 1_000_000.0) as u64)
+    }
+}
+
+/// This is a Sensor type that generates plausible energy consumption
+/// counters from a LoadModel and the CPU utilization, for hosts without any
+/// energy measurement interface (CI runners, development VMs...).
+/// The modeled power is evenly split between the CPU sockets found in
+/// /proc/cpuinfo, each socket having a "core" domain measuring the part of
+/// its power depending on the load.
+pub struct SyntheticSensor {
+    buffer_per_socket_max_kbytes: u16,
+    buffer_per_domain_max_kbytes: u16,
+    model: LoadModel,
+    procfs_path: String,
+}
+
+impl SyntheticSensor {
+    /// Instantiates and returns an instance of SyntheticSensor.
+    pub fn new(
+        buffer_per_socket_max_kbytes: u16,
+        buffer_per_domain_max_kbytes: u16,
+        model: LoadModel,
+        procfs_path: &str,
+    ) -> SyntheticSensor {
+        SyntheticSensor {
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
+            model,
+            procfs_path: String::from(procfs_path),
+        }
+    }
+
+    /// Returns the ids of the CPU sockets found in cpuinfo, or a single
+    /// socket 0 if cpuinfo has no physical id (as in most virtual machines).
+    fn read_sockets_ids(&self) -> Result<Vec<u16>, Box<dyn Error>> {
+        let cpuinfo = CpuInfo::from_reader(File::open(format!("{}/cpuinfo", self.procfs_path))?)?;
+        let mut ids = BTreeSet::new();
+        for cpu in 0..cpuinfo.num_cores() {
+            if let Some(id) = cpuinfo.physical_id(cpu) {
+                ids.insert(id as u16);
+            }
+        }
+        if ids.is_empty() {
+            ids.insert(0);
+        }
+        Ok(ids.into_iter().collect())
+    }
+}
+
+impl Sensor for SyntheticSensor {
+    /// Creates a Topology instance with sockets and domains whose energy
+    /// consumption is modeled from the CPU utilization.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        if self.model.max_watts < self.model.idle_watts {
+            return Err(From::from(format!(
+                "Max power ({} W) is lower than idle power ({} W)",
+                self.model.max_watts, self.model.idle_watts
+            )));
+        }
+        let stat_path = format!("{}/stat", self.procfs_path);
+        // checks the utilization can be read before modeling anything
+        SyntheticPowerMeter::new(self.model.clone(), &stat_path, 1.0, false).read_utilization()?;
+        let sockets_ids = self.read_sockets_ids()?;
+        let share = 1.0 / sockets_ids.len() as f64;
+        debug!("Modeling {:?} for sockets {:?}", self.model, sockets_ids);
+
+        let mut topo = Topology::new();
+        for socket_id in sockets_ids {
+            topo.safe_add_socket_with_counter(
+                socket_id,
+                vec![],
+                vec![],
+                stat_path.clone(),
+                Box::new(IntegratedPowerCounter::new(SyntheticPowerMeter::new(
+                    self.model.clone(),
+                    &stat_path,
+                    share,
+                    false,
+                ))),
+                self.buffer_per_socket_max_kbytes,
+            );
+            topo.safe_add_domain_to_socket_with_counter(
+                socket_id,
+                0,
+                "core",
+                &stat_path,
+                Box::new(IntegratedPowerCounter::new(SyntheticPowerMeter::new(
+                    self.model.clone(),
+                    &stat_path,
+                    share,
+                    true,
+                ))),
+                self.buffer_per_domain_max_kbytes,
+            );
+        }
+        Ok(topo)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::test_utils::TempDir;
+    use std::fs;
+
+    fn write_stat(root: &std::path::Path, user: u64, idle: u64) {
+        fs::write(
+            root.join("stat"),
+            format!(
+                "cpu  {} 0 0 {} 0 0 0 0 0 0\nctxt 1\nbtime 0\nprocesses 1\n",
+                user, idle
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn load_model_follows_curve() {
+        let model = LoadModel::new(20.0, 120.0, 1.0);
+        assert_eq!(model.power_watts(0.0, false), 20.0);
+        assert_eq!(model.power_watts(0.5, false), 70.0);
+        assert_eq!(model.power_watts(2.0, false), 120.0);
+        assert_eq!(model.power_watts(0.5, true), 50.0);
+        let model = LoadModel::new(20.0, 120.0, 0.5);
+        assert_eq!(model.power_watts(0.25, false), 70.0);
+    }
+
+    #[test]
+    fn synthetic_sensor_models_utilization() {
+        let root = TempDir::new("synthetic");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("cpuinfo"),
+            "processor\t: 0\nphysical id\t: 0\n\nprocessor\t: 1\nphysical id\t: 1\n\n",
+        )
+        .unwrap();
+        write_stat(&root, 100, 900);
+        let procfs_path = root.to_str().unwrap();
+        let sensor = SyntheticSensor::new(1, 1, LoadModel::new(20.0, 120.0, 1.0), procfs_path);
+        let topo = sensor.generate_topology().unwrap();
+        assert_eq!(topo.sockets.len(), 2);
+        assert_eq!(topo.domains_names, Some(vec![String::from("core")]));
+
+        let stat_path = format!("{}/stat", procfs_path);
+        let mut meter =
+            SyntheticPowerMeter::new(LoadModel::new(20.0, 120.0, 1.0), &stat_path, 0.5, false);
+        // 10% since boot, then 50% since the previous read
+        assert_eq!(meter.read_uw().unwrap(), 15_000_000);
+        write_stat(&root, 200, 1000);
+        assert_eq!(meter.read_uw().unwrap(), 35_000_000);
+
+        let sensor = SyntheticSensor::new(1, 1, LoadModel::new(120.0, 20.0, 1.0), procfs_path);
+        assert!(sensor.generate_topology().is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,534 @@
+//! # Taskstats: CPU time of the processes that exited
+//!
+//! Processes that start and terminate between two refreshes of the topology
+//! never appear in /proc when scaphandre reads it, so the CPU time they used
+//! would be attributed to the processes that survived. The taskstats interface
+//! of the kernel (a generic netlink family) sends the accounting data of each
+//! task when it exits: [TaskstatsListener] registers to it from a dedicated
+//! thread and keeps the CPU time of each process that exited until the next
+//! refresh. Registering needs the CAP_NET_ADMIN capability.
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::sensors::utils::current_system_time_since_epoch;
+use crate::sensors::{Sensor, Topology};
+
+/// Generic netlink controller, giving the id of the taskstats family
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+const TASKSTATS_GENL_NAME: &str = "TASKSTATS";
+const TASKSTATS_GENL_VERSION: u8 = 1;
+const TASKSTATS_CMD_GET: u8 = 1;
+const TASKSTATS_CMD_ATTR_REGISTER_CPUMASK: u16 = 3;
+const TASKSTATS_TYPE_PID: u16 = 1;
+const TASKSTATS_TYPE_TGID: u16 = 2;
+const TASKSTATS_TYPE_STATS: u16 = 3;
+const TASKSTATS_TYPE_AGGR_PID: u16 = 4;
+const TASKSTATS_TYPE_AGGR_TGID: u16 = 5;
+/// Flag of ac_flag telling the task was the last one of its process
+const AGROUP: u8 = 0x20;
+/// Offsets of the fields read in struct taskstats (see linux/taskstats.h)
+const OFFSET_AC_FLAG: usize = 8;
+const OFFSET_CPU_RUN_REAL_TOTAL: usize = 64;
+const OFFSET_AC_COMM: usize = 80;
+const TS_COMM_LEN: usize = 32;
+const OFFSET_AC_UID: usize = 120;
+const OFFSET_AC_PPID: usize = 132;
+const OFFSET_AC_BTIME: usize = 136;
+const OFFSET_AC_UTIME: usize = 152;
+const OFFSET_AC_STIME: usize = 160;
+/// Tells if delay accounting, filling cpu_run_real_total, is enabled
+const TASK_DELAYACCT_PATH: &str = "/proc/sys/kernel/task_delayacct";
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const GENL_HDRLEN: usize = 4;
+const NLA_HDRLEN: usize = 4;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+/// Maximum number of exited processes kept between two refreshes
+const MAX_EXITED_PROCESSES: usize = 100000;
+
+/// A process that exited, as reported by taskstats.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExitedProcess {
+    pub pid: i32,
+    pub ppid: i32,
+    /// Real UID of the process
+    pub uid: u32,
+    /// Name of the process (of its last thread)
+    pub comm: String,
+    /// Start time of the process, in seconds since epoch
+    pub start_time_secs: u64,
+    /// CPU time consumed by the threads of the process, in microseconds
+    pub cpu_time_us: u64,
+    /// When the exit was received
+    pub timestamp: Duration,
+}
+
+impl ExitedProcess {
+    /// Returns the CPU time consumed by the process, in jiffies.
+    pub fn cpu_time_jiffies(&self, ticks_per_second: u64) -> u64 {
+        self.cpu_time_us * ticks_per_second / 1000000
+    }
+
+    /// Returns the start time of the process in jiffies since boot, as the
+    /// starttime field of /proc/PID/stat, given the boot time of the host.
+    pub fn start_time_jiffies(&self, boot_time_secs: u64, ticks_per_second: u64) -> u64 {
+        self.start_time_secs.saturating_sub(boot_time_secs) * ticks_per_second
+    }
+}
+
+/// Receives the exits of processes from taskstats, in a dedicated thread.
+/// Clones share the same exited processes.
+#[derive(Debug, Clone)]
+pub struct TaskstatsListener {
+    exited: Arc<Mutex<Vec<ExitedProcess>>>,
+}
+
+impl TaskstatsListener {
+    /// Registers to taskstats for all the CPUs of the host and starts the
+    /// thread receiving the exits. Fails if taskstats is not available or
+    /// scaphandre doesn't have the CAP_NET_ADMIN capability.
+    pub fn start() -> Result<TaskstatsListener, Box<dyn Error>> {
+        if let Ok(enabled) = fs::read_to_string(TASK_DELAYACCT_PATH) {
+            if enabled.trim() == "0" {
+                warn!(
+                    "Delay accounting is disabled (kernel.task_delayacct=0), the CPU time of \
+                    multi-threaded processes that exit only counts their last thread."
+                );
+            }
+        }
+        let socket = NetlinkSocket::open()?;
+        let family = socket.get_family_id(TASKSTATS_GENL_NAME)?;
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) }.max(1);
+        let mask = format!("0-{}\0", cpus - 1);
+        socket.request(
+            family,
+            TASKSTATS_CMD_GET,
+            TASKSTATS_GENL_VERSION,
+            TASKSTATS_CMD_ATTR_REGISTER_CPUMASK,
+            mask.as_bytes(),
+        )?;
+        let exited = Arc::new(Mutex::new(vec![]));
+        let listener = TaskstatsListener {
+            exited: exited.clone(),
+        };
+        thread::Builder::new()
+            .name(String::from("taskstats"))
+            .spawn(move || socket.listen(&exited))?;
+        Ok(listener)
+    }
+
+    /// Returns the processes that exited since the previous call.
+    pub fn drain(&self) -> Vec<ExitedProcess> {
+        mem::take(&mut *self.exited.lock().unwrap())
+    }
+}
+
+/// Generic netlink socket.
+struct NetlinkSocket {
+    fd: i32,
+}
+
+impl NetlinkSocket {
+    fn open() -> io::Result<NetlinkSocket> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_GENERIC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = NetlinkSocket { fd };
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as u16;
+        let bound = unsafe {
+            libc::bind(
+                fd,
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // bursts of exits (like a build) shouldn't overflow the socket buffer
+        let size: i32 = 4 * 1024 * 1024;
+        unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUF,
+                &size as *const i32 as *const libc::c_void,
+                mem::size_of::<i32>() as u32,
+            );
+        }
+        Ok(socket)
+    }
+
+    /// Sends a generic netlink request with a single attribute and returns
+    /// the reply, without its netlink header, once acknowledged (empty if the
+    /// request has no reply).
+    fn request(
+        &self,
+        family: u16,
+        command: u8,
+        version: u8,
+        attribute: u16,
+        value: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let message = encode_request(family, command, version, attribute, value);
+        let sent = unsafe {
+            libc::send(
+                self.fd,
+                message.as_ptr() as *const libc::c_void,
+                message.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buffer = vec![0u8; 8192];
+        let mut reply = vec![];
+        loop {
+            let len = self.recv(&mut buffer)?;
+            let message = buffer.get(..len).unwrap_or_default();
+            let kind = message.get(4..6).map(|b| u16::from_ne_bytes([b[0], b[1]]));
+            let payload = message.get(NLMSG_HDRLEN..).unwrap_or_default();
+            if kind == Some(NLMSG_ERROR) {
+                // the acknowledgement, with an error code of 0
+                let code = read_u32(payload, 0).unwrap_or_default() as i32;
+                if code != 0 {
+                    return Err(io::Error::from_raw_os_error(-code));
+                }
+                return Ok(reply);
+            }
+            reply = payload.to_vec();
+        }
+    }
+
+    /// Returns the id of the generic netlink family *name*.
+    fn get_family_id(&self, name: &str) -> io::Result<u16> {
+        let reply = self.request(
+            GENL_ID_CTRL,
+            CTRL_CMD_GETFAMILY,
+            1,
+            CTRL_ATTR_FAMILY_NAME,
+            format!("{}\0", name).as_bytes(),
+        )?;
+        attributes(reply.get(GENL_HDRLEN..).unwrap_or_default())
+            .into_iter()
+            .find(|(kind, _)| *kind == CTRL_ATTR_FAMILY_ID)
+            .and_then(|(_, value)| value.get(..2).map(|b| u16::from_ne_bytes([b[0], b[1]])))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, name))
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let len = unsafe {
+            libc::recv(
+                self.fd,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+
+    /// Receives the exits of processes and pushes them to *exited*, until
+    /// the socket fails.
+    fn listen(&self, exited: &Mutex<Vec<ExitedProcess>>) {
+        let mut buffer = vec![0u8; 65536];
+        loop {
+            let len = match self.recv(&mut buffer) {
+                Ok(len) => len,
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    warn!("Exits of processes were lost by taskstats: {}", e);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Couldn't receive exits of processes from taskstats: {}", e);
+                    return;
+                }
+            };
+            let mut offset = 0;
+            while let Some(message_len) = read_u32(&buffer[..len], offset) {
+                let message_len = message_len as usize;
+                if message_len < NLMSG_HDRLEN || offset + message_len > len {
+                    break;
+                }
+                let message = &buffer[offset + NLMSG_HDRLEN..offset + message_len];
+                if let Some(process) = parse_exit_message(message) {
+                    let mut exited = exited.lock().unwrap();
+                    if exited.len() < MAX_EXITED_PROCESSES {
+                        exited.push(process);
+                    }
+                }
+                offset += align(message_len);
+            }
+        }
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Returns *len* rounded up to the 4 bytes alignment of netlink.
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset + 8)?;
+    let mut value = [0u8; 8];
+    value.copy_from_slice(bytes);
+    Some(u64::from_ne_bytes(value))
+}
+
+/// Returns a generic netlink request message with a single attribute.
+fn encode_request(family: u16, command: u8, version: u8, attribute: u16, value: &[u8]) -> Vec<u8> {
+    let len = NLMSG_HDRLEN + GENL_HDRLEN + align(NLA_HDRLEN + value.len());
+    let mut message = Vec::with_capacity(len);
+    message.extend_from_slice(&(len as u32).to_ne_bytes());
+    message.extend_from_slice(&family.to_ne_bytes());
+    let flags = (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16;
+    message.extend_from_slice(&flags.to_ne_bytes());
+    message.extend_from_slice(&0u32.to_ne_bytes()); // sequence number
+    message.extend_from_slice(&0u32.to_ne_bytes()); // port id, set by the kernel
+    message.extend_from_slice(&[command, version, 0, 0]);
+    message.extend_from_slice(&((NLA_HDRLEN + value.len()) as u16).to_ne_bytes());
+    message.extend_from_slice(&attribute.to_ne_bytes());
+    message.extend_from_slice(value);
+    message.resize(len, 0);
+    message
+}
+
+/// Returns the type and value of the netlink attributes found in *data*.
+fn attributes(data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut result = vec![];
+    let mut offset = 0;
+    while let Some(header) = data.get(offset..offset + NLA_HDRLEN) {
+        let len = u16::from_ne_bytes([header[0], header[1]]) as usize;
+        let kind = u16::from_ne_bytes([header[2], header[3]]) & NLA_TYPE_MASK;
+        match data.get(offset + NLA_HDRLEN..offset + len) {
+            Some(value) if len >= NLA_HDRLEN => result.push((kind, value)),
+            _ => break,
+        }
+        offset += align(len);
+    }
+    result
+}
+
+/// Returns the pid (or tgid) and the struct taskstats of an aggregate attribute.
+fn parse_aggregate(data: &[u8]) -> Option<(u32, &[u8])> {
+    let (mut id, mut stats) = (None, None);
+    for (kind, value) in attributes(data) {
+        match kind {
+            TASKSTATS_TYPE_PID | TASKSTATS_TYPE_TGID => id = read_u32(value, 0),
+            TASKSTATS_TYPE_STATS => stats = Some(value),
+            _ => {}
+        }
+    }
+    Some((id?, stats?))
+}
+
+/// Returns the process that exited, from the generic netlink *message* (its
+/// netlink header excluded) sent by taskstats. Returns None when the message
+/// tells the exit of a thread while the other threads of its process still run,
+/// as its CPU time is already counted in the one of the process.
+pub fn parse_exit_message(message: &[u8]) -> Option<ExitedProcess> {
+    let (mut task, mut process) = (None, None);
+    for (kind, value) in attributes(message.get(GENL_HDRLEN..)?) {
+        match kind {
+            TASKSTATS_TYPE_AGGR_PID => task = parse_aggregate(value),
+            TASKSTATS_TYPE_AGGR_TGID => process = parse_aggregate(value),
+            _ => {}
+        }
+    }
+    let (pid, task_stats) = task?;
+    if task_stats.get(OFFSET_AC_FLAG)? & AGROUP == 0 {
+        return None;
+    }
+    // user and system times are only filled in the stats of a task, the ones
+    // of the thread group only sum the run time of all its threads with delay
+    // accounting (and are only sent for processes that had several threads)
+    let mut cpu_time_us =
+        read_u64(task_stats, OFFSET_AC_UTIME)? + read_u64(task_stats, OFFSET_AC_STIME)?;
+    let pid = match process {
+        Some((tgid, stats)) => {
+            cpu_time_us = cpu_time_us.max(read_u64(stats, OFFSET_CPU_RUN_REAL_TOTAL)? / 1000);
+            tgid
+        }
+        None => pid,
+    };
+    let comm = task_stats.get(OFFSET_AC_COMM..OFFSET_AC_COMM + TS_COMM_LEN)?;
+    let comm_len = comm.iter().position(|c| *c == 0).unwrap_or(TS_COMM_LEN);
+    Some(ExitedProcess {
+        pid: pid as i32,
+        ppid: read_u32(task_stats, OFFSET_AC_PPID)? as i32,
+        uid: read_u32(task_stats, OFFSET_AC_UID)?,
+        comm: String::from_utf8_lossy(&comm[..comm_len]).to_string(),
+        start_time_secs: read_u32(task_stats, OFFSET_AC_BTIME)? as u64,
+        cpu_time_us,
+        timestamp: current_system_time_since_epoch(),
+    })
+}
+
+/// Sensor wrapper attaching a TaskstatsListener to the topology of the wrapped
+/// sensor, so the processes that exited between two refreshes are accounted.
+pub struct TaskstatsSensor {
+    sensor: Box<dyn Sensor>,
+    listener: TaskstatsListener,
+}
+
+impl TaskstatsSensor {
+    /// Instantiates and returns an instance of TaskstatsSensor, attaching
+    /// *listener* to the topology of *sensor*.
+    pub fn new(sensor: Box<dyn Sensor>, listener: TaskstatsListener) -> TaskstatsSensor {
+        TaskstatsSensor { sensor, listener }
+    }
+}
+
+impl Sensor for TaskstatsSensor {
+    /// Creates a Topology instance from the wrapped sensor, with the listener attached.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut topo = self.sensor.generate_topology()?;
+        topo.exits_listener = Some(self.listener.clone());
+        Ok(topo)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let mut topology = *self.sensor.get_topology();
+        if let Some(topo) = topology.as_mut() {
+            topo.exits_listener = Some(self.listener.clone());
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute(kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut data = ((NLA_HDRLEN + value.len()) as u16).to_ne_bytes().to_vec();
+        data.extend_from_slice(&kind.to_ne_bytes());
+        data.extend_from_slice(value);
+        data.resize(align(data.len()), 0);
+        data
+    }
+
+    /// Stats of a task (*utime_us* and *stime_us* set) or of a thread group
+    /// (*run_real_ns* set when delay accounting is enabled).
+    fn aggregate(
+        kind: u16,
+        id_kind: u16,
+        id: u32,
+        flag: u8,
+        (utime_us, stime_us, run_real_ns): (u64, u64, u64),
+    ) -> Vec<u8> {
+        let mut stats = vec![0u8; 416];
+        stats[OFFSET_AC_FLAG] = flag;
+        stats[OFFSET_CPU_RUN_REAL_TOTAL..OFFSET_CPU_RUN_REAL_TOTAL + 8]
+            .copy_from_slice(&run_real_ns.to_ne_bytes());
+        stats[OFFSET_AC_UTIME..OFFSET_AC_UTIME + 8].copy_from_slice(&utime_us.to_ne_bytes());
+        stats[OFFSET_AC_STIME..OFFSET_AC_STIME + 8].copy_from_slice(&stime_us.to_ne_bytes());
+        stats[OFFSET_AC_COMM..OFFSET_AC_COMM + 2].copy_from_slice(b"cc");
+        stats[OFFSET_AC_UID..OFFSET_AC_UID + 4].copy_from_slice(&1000u32.to_ne_bytes());
+        stats[OFFSET_AC_PPID..OFFSET_AC_PPID + 4].copy_from_slice(&12u32.to_ne_bytes());
+        stats[OFFSET_AC_BTIME..OFFSET_AC_BTIME + 4].copy_from_slice(&1000u32.to_ne_bytes());
+        let mut value = attribute(id_kind, &id.to_ne_bytes());
+        value.extend(attribute(TASKSTATS_TYPE_STATS, &stats));
+        attribute(kind, &value)
+    }
+
+    #[test]
+    fn exits_are_parsed() {
+        let header = [2u8, 1, 0, 0];
+        // a thread exiting while its process runs is ignored
+        let mut message = header.to_vec();
+        message.extend(aggregate(
+            TASKSTATS_TYPE_AGGR_PID,
+            TASKSTATS_TYPE_PID,
+            51,
+            0,
+            (10, 0, 0),
+        ));
+        assert_eq!(parse_exit_message(&message), None);
+
+        // single threaded process
+        let mut message = header.to_vec();
+        message.extend(aggregate(
+            TASKSTATS_TYPE_AGGR_PID,
+            TASKSTATS_TYPE_PID,
+            50,
+            AGROUP,
+            (20000, 10000, 0),
+        ));
+        let process = parse_exit_message(&message).unwrap();
+        assert_eq!((process.pid, process.ppid, process.uid), (50, 12, 1000));
+        assert_eq!(process.comm, "cc");
+        assert_eq!(process.cpu_time_jiffies(100), 3);
+        assert_eq!(process.start_time_jiffies(900, 100), 10000);
+
+        // last thread of a multi-threaded process, the run time of the thread
+        // group is used when delay accounting fills it
+        let mut with_group = message.clone();
+        with_group.extend(aggregate(
+            TASKSTATS_TYPE_AGGR_TGID,
+            TASKSTATS_TYPE_TGID,
+            49,
+            0,
+            (0, 0, 90000000),
+        ));
+        let process = parse_exit_message(&with_group).unwrap();
+        assert_eq!((process.pid, process.cpu_time_us), (49, 90000));
+        message.extend(aggregate(
+            TASKSTATS_TYPE_AGGR_TGID,
+            TASKSTATS_TYPE_TGID,
+            49,
+            0,
+            (0, 0, 0),
+        ));
+        let process = parse_exit_message(&message).unwrap();
+        assert_eq!((process.pid, process.cpu_time_us), (49, 30000));
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
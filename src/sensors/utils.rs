@@ -1,10 +1,21 @@
+use crate::sensors::taskstats::ExitedProcess;
+use crate::sensors::Record;
 use docker_sync::container::Container;
 use k8s_sync::Pod;
-use procfs::process::Process;
+use procfs::process::{Process, Stat};
 use regex::Regex;
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::fs::{self, File};
 use std::time::{Duration, SystemTime};
 
+/// Path of the file user names are read from
+pub const PASSWD_PATH: &str = "/etc/passwd";
+
+/// Time the energy counters of the consumers that are gone are kept, waiting
+/// to be exported, before being dropped.
+pub const DEFAULT_ENERGY_COUNTERS_RETENTION: Duration = Duration::from_secs(300);
+
 #[derive(Debug, Clone)]
 /// Manages ProcessRecord instances.
 pub struct ProcessTracker {
@@ -16,6 +27,35 @@ pub struct ProcessTracker {
     pub regex_cgroup_docker: Regex,
     pub regex_cgroup_kubernetes: Regex,
     pub regex_cgroup_containerd: Regex,
+    /// Energy consumed by each process since it is tracked, by PID and start time
+    /// (so a PID reused by a new process gets a new counter).
+    pub processes_energy: HashMap<(i32, u64), ProcessEnergy>,
+    /// Tells if the energy counter of each process is exported, which creates
+    /// a series per process (the counters of groups are exported anyway)
+    pub processes_energy_metrics: bool,
+    /// Energy consumed by the processes of each container, by container id.
+    pub containers_energy: HashMap<String, EnergyCounter>,
+    /// Energy consumed by the processes of each kubernetes pod, by pod uid.
+    pub pods_energy: HashMap<String, EnergyCounter>,
+    /// Roots of the process trees power and energy are rolled up to, if enabled
+    pub tree_roots: Option<TreeRoots>,
+    /// Energy consumed by the processes of each process tree, by PID and
+    /// start time of its root.
+    pub trees_energy: HashMap<(i32, u64), TreeEnergy>,
+    /// Tells if processes are mapped to the systemd unit they run in
+    pub systemd_units: bool,
+    /// Energy consumed by the processes of each systemd unit
+    pub units_energy: HashMap<SystemdUnit, EnergyCounter>,
+    /// Energy consumed by the processes of each systemd slice, by path of the slice
+    pub slices_energy: HashMap<String, EnergyCounter>,
+    /// Tells if processes are mapped to the user running them
+    pub users: bool,
+    /// Energy consumed by the processes of each user, by real UID
+    pub users_energy: HashMap<u32, EnergyCounter>,
+    /// Names of the users, by UID, as found in PASSWD_PATH
+    pub usernames: HashMap<u32, String>,
+    /// Time the counters of consumers that are gone are kept if not exported
+    pub energy_counters_retention: Duration,
 }
 
 impl ProcessTracker {
@@ -38,6 +78,19 @@ impl ProcessTracker {
             regex_cgroup_docker,
             regex_cgroup_kubernetes,
             regex_cgroup_containerd,
+            processes_energy: HashMap::new(),
+            processes_energy_metrics: false,
+            containers_energy: HashMap::new(),
+            pods_energy: HashMap::new(),
+            tree_roots: None,
+            trees_energy: HashMap::new(),
+            systemd_units: false,
+            units_energy: HashMap::new(),
+            slices_energy: HashMap::new(),
+            users: false,
+            users_energy: HashMap::new(),
+            usernames: HashMap::new(),
+            energy_counters_retention: DEFAULT_ENERGY_COUNTERS_RETENTION,
         }
     }
 
@@ -57,12 +110,23 @@ impl ProcessTracker {
     /// }
     /// ```
     pub fn add_process_record(&mut self, process: Process) -> Result<String, String> {
+        self.add_process_record_with_threads(process, None)
+    }
+
+    /// Same as add_process_record, storing the records of the threads of
+    /// the process along with it.
+    pub fn add_process_record_with_threads(
+        &mut self,
+        process: Process,
+        threads: Option<Vec<ThreadRecord>>,
+    ) -> Result<String, String> {
         let iterator = self.procs.iter_mut();
         let pid = process.pid;
         // find the vector containing Process instances with the same pid
         let mut filtered = iterator.filter(|x| !x.is_empty() && x[0].process.pid == pid);
         let result = filtered.next();
-        let process_record = ProcessRecord::new(process);
+        let mut process_record = ProcessRecord::new(process);
+        process_record.threads = threads;
         if let Some(vector) = result {
             // if a vector of process records has been found
             // check if the previous records in the vector are from the same process
@@ -309,6 +373,466 @@ impl ProcessTracker {
         description
     }
 
+    /// Returns the container id and the kubernetes pod uid of a process, when
+    /// its cgroups show it runs in a docker container or a kubernetes pod.
+    fn get_process_container_ids(&self, process: &Process) -> (Option<String>, Option<String>) {
+        if let Ok(cgroups) = process.cgroups() {
+            for cg in &cgroups {
+                if self.regex_cgroup_docker.is_match(&cg.pathname) {
+                    let container_id = cg.pathname.rsplit('/').next();
+                    return (container_id.map(String::from), None);
+                } else if self.regex_cgroup_kubernetes.is_match(&cg.pathname) {
+                    let container_id = self
+                        .extract_pod_id_from_cgroup_path(cg.pathname.clone())
+                        .ok();
+                    // like /kubepods/burstable/pod<uid>/<container> or, with the
+                    // systemd cgroup driver, /kubepods.slice/kubepods-pod<uid>.slice/...
+                    let pod_uid = cg.pathname.split('/').find_map(|part| {
+                        part.strip_suffix(".slice")
+                            .unwrap_or(part)
+                            .rsplit_once("pod")
+                            .filter(|(_, uid)| !uid.is_empty())
+                            .map(|(_, uid)| uid.replace('_', "-"))
+                    });
+                    return (container_id, pod_uid);
+                }
+            }
+        }
+        (None, None)
+    }
+
+    /// Returns the systemd unit a process runs in, according to its cgroup in
+    /// the unified (v2) or the name=systemd hierarchy.
+    fn get_process_systemd_unit(&self, process: &Process) -> Option<SystemdUnit> {
+        let cgroups = process.cgroups().ok()?;
+        cgroups
+            .iter()
+            .find(|cg| cg.hierarchy == 0 || cg.controllers.iter().any(|c| c == "name=systemd"))
+            .and_then(|cg| SystemdUnit::from_cgroup_path(&cg.pathname))
+    }
+
+    /// Adds to the energy counters of each alive process (and of its container
+    /// and pod) the energy consumed since its previous record, from the power
+    /// it consumed in that time, given by PID in *processes_power*.
+    /// Counters of processes, containers and pods that are gone are kept, marked
+    /// as not alive, until drop_exported_energy_counters is called or for
+    /// energy_counters_retention at most.
+    pub fn add_energy(&mut self, processes_power: &HashMap<i32, Record>) {
+        for counter in self.processes_energy.values_mut() {
+            counter.energy.alive = false;
+        }
+        for counter in self
+            .containers_energy
+            .values_mut()
+            .chain(self.pods_energy.values_mut())
+        {
+            counter.alive = false;
+        }
+        for counter in self.trees_energy.values_mut() {
+            counter.energy.alive = false;
+        }
+        for counter in self
+            .units_energy
+            .values_mut()
+            .chain(self.slices_energy.values_mut())
+        {
+            counter.alive = false;
+        }
+        for counter in self.users_energy.values_mut() {
+            counter.alive = false;
+        }
+        let mut microjoules = vec![];
+        for records in self.get_alive_processes() {
+            let process = &records[0].process;
+            let key = (process.pid, process.stat.starttime);
+            let mut energy = 0.0;
+            if let (Some(previous), Some(power)) =
+                (records.get(1), processes_power.get(&process.pid))
+            {
+                let duration = records[0]
+                    .timestamp
+                    .saturating_sub(previous.timestamp)
+                    .as_secs_f64();
+                energy = power.value.parse::<f64>().unwrap_or_default() * duration;
+            }
+            microjoules.push((
+                key,
+                process.clone(),
+                records[0].total_time_jiffies(),
+                energy,
+            ));
+        }
+        let roots = self.get_tree_roots_keys();
+        for (key, process, cpu_jiffies, energy) in microjoules {
+            self.add_tree_energy(&roots, key.0, energy);
+            if !self.processes_energy.contains_key(&key) {
+                let (container_id, pod_uid) = self.get_process_container_ids(&process);
+                let systemd_unit = if self.systemd_units {
+                    self.get_process_systemd_unit(&process)
+                } else {
+                    None
+                };
+                let uid = if self.users {
+                    self.get_process_uid(&process)
+                } else {
+                    None
+                };
+                self.processes_energy.insert(
+                    key,
+                    ProcessEnergy {
+                        exe: process.stat.comm.clone(),
+                        container_id,
+                        pod_uid,
+                        systemd_unit,
+                        uid,
+                        cpu_jiffies,
+                        energy: EnergyCounter::default(),
+                    },
+                );
+            }
+            let counter = self.processes_energy.get_mut(&key).unwrap();
+            counter.cpu_jiffies = cpu_jiffies;
+            counter.energy.add(energy);
+            self.add_groups_energy(key, energy);
+        }
+        self.prune_energy_counters(current_system_time_since_epoch());
+    }
+
+    /// Returns the key (PID and start time) and the name of the root of the
+    /// process tree of each alive process, by PID.
+    fn get_tree_roots_keys(&self) -> HashMap<i32, ((i32, u64), String)> {
+        let roots = self.get_tree_roots();
+        if roots.is_empty() {
+            return HashMap::new();
+        }
+        let keys: HashMap<i32, ((i32, u64), String)> = self
+            .get_alive_processes()
+            .iter()
+            .map(|records| {
+                let stat = &records[0].process.stat;
+                (stat.pid, ((stat.pid, stat.starttime), stat.comm.clone()))
+            })
+            .collect();
+        roots
+            .iter()
+            .filter_map(|(pid, root)| keys.get(root).map(|key| (*pid, key.clone())))
+            .collect()
+    }
+
+    /// Adds *energy* to the counter of the process tree *pid* belongs to, given
+    /// the root of each process in *roots*.
+    fn add_tree_energy(
+        &mut self,
+        roots: &HashMap<i32, ((i32, u64), String)>,
+        pid: i32,
+        energy: f64,
+    ) {
+        if let Some((key, exe)) = roots.get(&pid) {
+            self.trees_energy
+                .entry(*key)
+                .or_insert_with(|| TreeEnergy {
+                    exe: exe.clone(),
+                    energy: EnergyCounter::default(),
+                })
+                .energy
+                .add(energy);
+        }
+    }
+
+    /// Returns the real UID of a process, resolving its user name if the
+    /// UID is not known yet.
+    fn get_process_uid(&mut self, process: &Process) -> Option<u32> {
+        let uid = process.status().ok()?.ruid;
+        self.resolve_username(uid);
+        Some(uid)
+    }
+
+    /// Reads PASSWD_PATH again if the name of the user *uid* is not known.
+    /// Users not found there (like the ones of a directory service) are
+    /// named after their UID.
+    fn resolve_username(&mut self, uid: u32) {
+        if !self.usernames.contains_key(&uid) {
+            self.usernames.extend(read_usernames(PASSWD_PATH));
+            self.usernames.entry(uid).or_insert_with(|| uid.to_string());
+        }
+    }
+
+    /// Adds *energy* to the counters of the container, pod, systemd unit and
+    /// user of the process *key*.
+    fn add_groups_energy(&mut self, key: (i32, u64), energy: f64) {
+        if let Some(counter) = self.processes_energy.get(&key) {
+            if let Some(uid) = counter.uid {
+                self.users_energy.entry(uid).or_default().add(energy);
+            }
+            if let Some(unit) = &counter.systemd_unit {
+                self.units_energy
+                    .entry(unit.clone())
+                    .or_default()
+                    .add(energy);
+                for slice in &unit.slices {
+                    self.slices_energy
+                        .entry(slice.clone())
+                        .or_default()
+                        .add(energy);
+                }
+            }
+            if let Some(container_id) = &counter.container_id {
+                self.containers_energy
+                    .entry(container_id.clone())
+                    .or_default()
+                    .add(energy);
+            }
+            if let Some(pod_uid) = &counter.pod_uid {
+                self.pods_energy
+                    .entry(pod_uid.clone())
+                    .or_default()
+                    .add(energy);
+            }
+        }
+    }
+
+    /// Returns the start times of the processes having an energy counter, by
+    /// PID, so that the processes that exited are found without walking all
+    /// the counters for each of them.
+    pub fn get_energy_start_times(&self) -> HashMap<i32, Vec<u64>> {
+        let mut start_times: HashMap<i32, Vec<u64>> = HashMap::new();
+        for (pid, start_time) in self.processes_energy.keys() {
+            start_times.entry(*pid).or_default().push(*start_time);
+        }
+        start_times
+    }
+
+    /// Returns the key and energy counter of a process that exited, if it was
+    /// tracked, given the start times of the tracked processes returned by
+    /// get_energy_start_times. The start time given by taskstats is in
+    /// seconds, hence the tolerance of one second.
+    pub fn find_exited_process_energy(
+        &self,
+        start_times: &HashMap<i32, Vec<u64>>,
+        exited: &ExitedProcess,
+        boot_time_secs: u64,
+        ticks_per_second: u64,
+    ) -> Option<((i32, u64), &ProcessEnergy)> {
+        let start_time = exited.start_time_jiffies(boot_time_secs, ticks_per_second);
+        let key = start_times
+            .get(&exited.pid)?
+            .iter()
+            .find(|process_start_time| process_start_time.abs_diff(start_time) <= ticks_per_second)
+            .map(|process_start_time| (exited.pid, *process_start_time))?;
+        self.processes_energy.get(&key).map(|energy| (key, energy))
+    }
+
+    /// Adds to the energy counters the energy consumed by the processes that
+    /// exited in the last *window*, from their power in *processes_power*.
+    /// Processes that were never tracked get a counter, exported once, grouped
+    /// in the container and pod of their parent.
+    pub fn add_exited_energy(
+        &mut self,
+        exited: &[ExitedProcess],
+        processes_power: &HashMap<i32, Record>,
+        window: Duration,
+    ) {
+        let ticks_per_second = procfs::ticks_per_second().unwrap_or(100) as u64;
+        let boot_time_secs = procfs::boot_time_secs().unwrap_or_default();
+        let roots = self.get_tree_roots_keys();
+        let mut start_times = self.get_energy_start_times();
+        for process in exited {
+            let energy = match processes_power.get(&process.pid) {
+                Some(power) => {
+                    power.value.parse::<f64>().unwrap_or_default() * window.as_secs_f64()
+                }
+                None => continue,
+            };
+            self.add_tree_energy(&roots, process.ppid, energy);
+            let key = match self.find_exited_process_energy(
+                &start_times,
+                process,
+                boot_time_secs,
+                ticks_per_second,
+            ) {
+                Some((key, _)) => key,
+                None => {
+                    // the parent is the last process started with its PID
+                    let parent = start_times
+                        .get(&process.ppid)
+                        .and_then(|parent_start_times| parent_start_times.iter().max())
+                        .and_then(|start_time| {
+                            self.processes_energy.get(&(process.ppid, *start_time))
+                        })
+                        .map(|parent| {
+                            (
+                                parent.container_id.clone(),
+                                parent.pod_uid.clone(),
+                                parent.systemd_unit.clone(),
+                            )
+                        });
+                    let (container_id, pod_uid, systemd_unit) = parent.unwrap_or_default();
+                    let key = (
+                        process.pid,
+                        process.start_time_jiffies(boot_time_secs, ticks_per_second),
+                    );
+                    let uid = if self.users {
+                        self.resolve_username(process.uid);
+                        Some(process.uid)
+                    } else {
+                        None
+                    };
+                    self.processes_energy.insert(
+                        key,
+                        ProcessEnergy {
+                            exe: process.comm.clone(),
+                            container_id,
+                            pod_uid,
+                            systemd_unit,
+                            uid,
+                            cpu_jiffies: process.cpu_time_jiffies(ticks_per_second),
+                            energy: EnergyCounter::default(),
+                        },
+                    );
+                    start_times.entry(key.0).or_default().push(key.1);
+                    key
+                }
+            };
+            let counter = self.processes_energy.get_mut(&key).unwrap();
+            counter.energy.microjoules += energy;
+            counter.energy.alive = false;
+            self.add_groups_energy(key, energy);
+        }
+    }
+
+    /// Forgets the energy counters of processes, containers and pods that are
+    /// gone. To be called once their final value has been exported.
+    pub fn drop_exported_energy_counters(&mut self) {
+        self.processes_energy
+            .retain(|_, counter| counter.energy.alive);
+        self.containers_energy.retain(|_, counter| counter.alive);
+        self.pods_energy.retain(|_, counter| counter.alive);
+        self.trees_energy.retain(|_, counter| counter.energy.alive);
+        self.units_energy.retain(|_, counter| counter.alive);
+        self.slices_energy.retain(|_, counter| counter.alive);
+        self.users_energy.retain(|_, counter| counter.alive);
+    }
+
+    /// Forgets the energy counters of processes, containers and pods that are
+    /// gone for more than energy_counters_retention at *now*, so they don't
+    /// pile up when the exporter doesn't export them.
+    pub fn prune_energy_counters(&mut self, now: Duration) {
+        let retention = self.energy_counters_retention;
+        self.processes_energy
+            .retain(|_, counter| counter.energy.retain(now, retention));
+        self.containers_energy
+            .retain(|_, counter| counter.retain(now, retention));
+        self.pods_energy
+            .retain(|_, counter| counter.retain(now, retention));
+        self.trees_energy
+            .retain(|_, counter| counter.energy.retain(now, retention));
+        self.units_energy
+            .retain(|_, counter| counter.retain(now, retention));
+        self.slices_energy
+            .retain(|_, counter| counter.retain(now, retention));
+        self.users_energy
+            .retain(|_, counter| counter.retain(now, retention));
+    }
+
+    /// Returns the real UID of each process, by PID, when processes are
+    /// mapped to users. Processes that exited are included until their
+    /// energy is exported.
+    pub fn get_processes_uids(&self) -> HashMap<i32, u32> {
+        let mut uids = HashMap::new();
+        // alive processes override the ones that exited with the same PID
+        let mut processes: Vec<_> = self.processes_energy.iter().collect();
+        processes.sort_by_key(|(_, process)| process.energy.alive);
+        for ((pid, _), process) in processes {
+            if let Some(uid) = process.uid {
+                uids.insert(*pid, uid);
+            }
+        }
+        uids
+    }
+
+    /// Returns the name of the user *uid*, or the UID itself if unknown.
+    pub fn get_username(&self, uid: u32) -> String {
+        self.usernames
+            .get(&uid)
+            .cloned()
+            .unwrap_or_else(|| uid.to_string())
+    }
+
+    /// Returns the systemd unit of each process, by PID, when processes are
+    /// mapped to systemd units. Processes that exited are included until
+    /// their energy is exported.
+    pub fn get_processes_systemd_units(&self) -> HashMap<i32, &SystemdUnit> {
+        let mut units = HashMap::new();
+        // alive processes override the ones that exited with the same PID
+        let mut processes: Vec<_> = self.processes_energy.iter().collect();
+        processes.sort_by_key(|(_, process)| process.energy.alive);
+        for ((pid, _), process) in processes {
+            if let Some(unit) = &process.systemd_unit {
+                units.insert(*pid, unit);
+            }
+        }
+        units
+    }
+
+    /// Returns the PIDs of the children of each alive process, by PID.
+    pub fn get_children(&self) -> HashMap<i32, Vec<i32>> {
+        let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+        for records in self.get_alive_processes() {
+            let stat = &records[0].process.stat;
+            children.entry(stat.ppid).or_default().push(stat.pid);
+        }
+        children
+    }
+
+    /// Returns the PID of the root of the process tree of each alive process,
+    /// by PID, when process trees are enabled. Without chosen roots, the root
+    /// of a process is its oldest ancestor (or itself) in its process group,
+    /// which is the group leader unless it exited: a shell puts each job in
+    /// its own group, so a `make -j` is not rolled up into the session
+    /// (sshd, login...) it was started from. Otherwise it is its oldest
+    /// ancestor (or itself) matching the chosen roots, below init (PID 1)
+    /// and kthreadd (PID 2), processes out of their trees having none.
+    pub fn get_tree_roots(&self) -> HashMap<i32, i32> {
+        let tree_roots = match &self.tree_roots {
+            Some(tree_roots) => tree_roots,
+            None => return HashMap::new(),
+        };
+        // parent, process group and name of each process, by PID
+        let processes: HashMap<i32, (i32, i32, &str)> = self
+            .get_alive_processes()
+            .iter()
+            .map(|records| {
+                let stat = &records[0].process.stat;
+                (stat.pid, (stat.ppid, stat.pgrp, stat.comm.as_str()))
+            })
+            .collect();
+        let mut roots = HashMap::new();
+        for (pid, (_, group, _)) in &processes {
+            let mut current = *pid;
+            let mut root = None;
+            // bounded, in case parents changed between the reads of /proc
+            for _ in 0..processes.len() {
+                let (parent, current_group, exe) = processes[&current];
+                if tree_roots.is_empty() {
+                    if current_group != *group {
+                        break;
+                    }
+                    root = Some(current);
+                } else if tree_roots.matches(current, exe) {
+                    root = Some(current);
+                }
+                if parent <= 2 || !processes.contains_key(&parent) {
+                    break;
+                }
+                current = parent;
+            }
+            if let Some(root) = root {
+                roots.insert(*pid, root);
+            }
+        }
+        roots
+    }
+
     /// Returns a vector containing pids of all running, sleeping or waiting current processes.
     pub fn get_alive_pids(&self) -> Vec<i32> {
         self.get_alive_processes()
@@ -495,11 +1019,134 @@ impl ProcessTracker {
     }
 }
 
+/// Energy consumed by a process, or by the processes of a container or a pod.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EnergyCounter {
+    /// Energy consumed since the consumer is tracked, in microjoules
+    pub microjoules: f64,
+    /// False once the consumer is gone, its counter then only waits to be exported
+    pub alive: bool,
+    /// Last time the consumer was seen alive by prune_energy_counters
+    pub last_seen: Option<Duration>,
+}
+
+impl EnergyCounter {
+    /// Adds *microjoules* to the counter and marks the consumer as alive.
+    fn add(&mut self, microjoules: f64) {
+        self.microjoules += microjoules;
+        self.alive = true;
+    }
+
+    /// Tells if the counter has to be kept at *now*: its consumer is alive or
+    /// has been gone for less than *retention*.
+    fn retain(&mut self, now: Duration, retention: Duration) -> bool {
+        if self.alive || self.last_seen.is_none() {
+            self.last_seen = Some(now);
+        }
+        now.saturating_sub(self.last_seen.unwrap()) <= retention
+    }
+}
+
+/// Energy counter of a process, with what it is grouped by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessEnergy {
+    pub exe: String,
+    /// Id of the container the process runs in, if any
+    pub container_id: Option<String>,
+    /// Uid of the kubernetes pod the process runs in, if any
+    pub pod_uid: Option<String>,
+    /// Systemd unit the process runs in, if known
+    pub systemd_unit: Option<SystemdUnit>,
+    /// Real UID of the process, if processes are mapped to users
+    pub uid: Option<u32>,
+    /// CPU time consumed by the process when it was last seen, in jiffies
+    pub cpu_jiffies: u64,
+    pub energy: EnergyCounter,
+}
+
+/// Processes chosen as roots of the process trees, by PID or executable name.
+/// When empty, each process is in the tree of the leader of its process group.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TreeRoots {
+    pub pids: Vec<i32>,
+    pub exes: Vec<String>,
+}
+
+impl TreeRoots {
+    /// Instantiates TreeRoots from *values*, each being a PID or an executable name.
+    pub fn new(values: &[&str]) -> TreeRoots {
+        let mut roots = TreeRoots::default();
+        for value in values {
+            match value.parse() {
+                Ok(pid) => roots.pids.push(pid),
+                Err(_) => roots.exes.push(value.to_string()),
+            }
+        }
+        roots
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pids.is_empty() && self.exes.is_empty()
+    }
+
+    /// Tells if the process *pid*, named *exe*, is a root.
+    pub fn matches(&self, pid: i32, exe: &str) -> bool {
+        self.pids.contains(&pid) || self.exes.iter().any(|e| e == exe)
+    }
+}
+
+/// A systemd unit processes run in (a service or a scope), with the slices
+/// containing it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SystemdUnit {
+    /// Name of the unit, like "ssh.service"
+    pub name: String,
+    /// Path of the slice the unit is in, "/" for the root slice
+    pub slice: String,
+    /// Paths of all the slices containing the unit, the outermost first
+    /// (the root slice excluded)
+    pub slices: Vec<String>,
+}
+
+impl SystemdUnit {
+    /// Returns the innermost unit found in a cgroup path, like
+    /// "/user.slice/user-1000.slice/session-2.scope", if any.
+    pub fn from_cgroup_path(pathname: &str) -> Option<SystemdUnit> {
+        let parts: Vec<&str> = pathname.split('/').filter(|p| !p.is_empty()).collect();
+        let index = parts
+            .iter()
+            .rposition(|p| p.ends_with(".service") || p.ends_with(".scope"))?;
+        let mut path = String::new();
+        let mut slices = vec![];
+        for part in &parts[..index] {
+            path.push('/');
+            path.push_str(part);
+            if part.ends_with(".slice") {
+                slices.push(path.clone());
+            }
+        }
+        Some(SystemdUnit {
+            name: parts[index].to_string(),
+            slice: slices.last().cloned().unwrap_or_else(|| String::from("/")),
+            slices,
+        })
+    }
+}
+
+/// Energy counter of a process tree, named after its root.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeEnergy {
+    pub exe: String,
+    pub energy: EnergyCounter,
+}
+
 /// Stores the information of a give process at a given timestamp
 #[derive(Debug, Clone)]
 pub struct ProcessRecord {
     pub process: Process,
     pub timestamp: Duration,
+    /// Threads of the process, if they are tracked
+    pub threads: Option<Vec<ThreadRecord>>,
 }
 
 impl ProcessRecord {
@@ -509,6 +1156,7 @@ impl ProcessRecord {
         ProcessRecord {
             process,
             timestamp: current_system_time_since_epoch(),
+            threads: None,
         }
     }
 
@@ -536,8 +1184,83 @@ impl ProcessRecord {
     }
 }
 
+/// Stores the information of a thread of a process, as found in
+/// /proc/PID/task/TID/stat.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadRecord {
+    pub tid: i32,
+    /// Name of the thread
+    pub comm: String,
+    /// CPU the thread last ran on
+    pub processor: Option<u16>,
+    /// CPU time spent in user mode, in jiffies
+    pub utime: u64,
+    /// CPU time spent in kernel mode, in jiffies
+    pub stime: u64,
+}
+
+impl ThreadRecord {
+    /// Returns the total CPU time consumed by this thread since its creation
+    pub fn total_time_jiffies(&self) -> u64 {
+        self.utime + self.stime
+    }
+}
+
+/// Reads the threads of the process *pid* in the procfs filesystem mounted
+/// on *procfs_root*. Threads terminating while being read are skipped.
+pub fn read_threads(procfs_root: &str, pid: i32) -> Vec<ThreadRecord> {
+    let mut threads = vec![];
+    if let Ok(entries) = fs::read_dir(format!("{}/{}/task", procfs_root, pid)) {
+        for entry in entries.flatten() {
+            if let Ok(file) = File::open(entry.path().join("stat")) {
+                if let Ok(stat) = Stat::from_reader(file) {
+                    threads.push(ThreadRecord {
+                        tid: stat.pid,
+                        comm: stat.comm,
+                        processor: stat.processor.map(|p| p as u16),
+                        utime: stat.utime,
+                        stime: stat.stime,
+                    });
+                }
+            }
+        }
+    }
+    threads.sort_by_key(|t| t.tid);
+    threads
+}
+
+/// Returns the name of each user found in the passwd file at *path*, by UID.
+pub fn read_usernames(path: &str) -> HashMap<u32, String> {
+    let mut usernames = HashMap::new();
+    if let Ok(content) = fs::read_to_string(path) {
+        for line in content.lines() {
+            let fields: Vec<&str> = line.split(':').collect();
+            if let (Some(name), Some(Ok(uid))) = (fields.first(), fields.get(2).map(|u| u.parse()))
+            {
+                usernames.insert(uid, name.to_string());
+            }
+        }
+    }
+    usernames
+}
+
+thread_local! {
+    /// Time returned by current_system_time_since_epoch() in this thread
+    /// instead of the system time, while a trace is replayed.
+    static REPLAYED_TIME: Cell<Option<Duration>> = const { Cell::new(None) };
+}
+
+/// Makes current_system_time_since_epoch() return *time* in the current
+/// thread, or the system time again if *time* is None.
+pub fn set_replayed_time(time: Option<Duration>) {
+    REPLAYED_TIME.with(|t| t.set(time));
+}
+
 /// Returns a Duration instance with the current timestamp
 pub fn current_system_time_since_epoch() -> Duration {
+    if let Some(time) = REPLAYED_TIME.with(|t| t.get()) {
+        return time;
+    }
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
@@ -546,6 +1269,7 @@ pub fn current_system_time_since_epoch() -> Duration {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sensors::test_utils::TempDir;
     #[test]
     fn process_records_added() {
         let proc = Process::myself().unwrap();
@@ -572,6 +1296,201 @@ mod tests {
         assert_eq!(tracker.procs.len(), 1);
         assert_eq!(tracker.procs[0].len(), 3);
     }
+
+    #[test]
+    fn exited_processes_energy_is_added() {
+        let mut tracker = ProcessTracker::new(3);
+        tracker.processes_energy.insert(
+            (10, 500),
+            ProcessEnergy {
+                exe: String::from("make"),
+                container_id: Some(String::from("c1")),
+                pod_uid: None,
+                systemd_unit: None,
+                uid: None,
+                cpu_jiffies: 0,
+                energy: EnergyCounter::default(),
+            },
+        );
+        let exited = ExitedProcess {
+            pid: 11,
+            ppid: 10,
+            uid: 1000,
+            comm: String::from("cc"),
+            start_time_secs: procfs::boot_time_secs().unwrap() + 20,
+            cpu_time_us: 10000,
+            timestamp: current_system_time_since_epoch(),
+        };
+        let mut powers = HashMap::new();
+        powers.insert(
+            11,
+            Record::new(
+                exited.timestamp,
+                String::from("1000000"),
+                crate::sensors::units::Unit::MicroWatt,
+            ),
+        );
+        // the same process reported twice gets a single counter
+        tracker.add_exited_energy(&[exited.clone(), exited], &powers, Duration::from_secs(2));
+        assert_eq!(tracker.processes_energy.len(), 2);
+        let start_times = tracker.get_energy_start_times();
+        let process = tracker
+            .processes_energy
+            .iter()
+            .find(|((pid, _), _)| *pid == 11)
+            .unwrap()
+            .1;
+        assert_eq!(start_times[&11].len(), 1);
+        assert_eq!(process.energy.microjoules, 4000000.0);
+        assert!(!process.energy.alive);
+        // grouped in the container of its parent
+        assert_eq!(tracker.containers_energy["c1"].microjoules, 4000000.0);
+    }
+
+    #[test]
+    fn process_energy_is_integrated() {
+        let proc = Process::myself().unwrap();
+        let mut tracker = ProcessTracker::new(3);
+        tracker.add_process_record(proc.clone()).unwrap();
+        tracker.add_process_record(proc.clone()).unwrap();
+        tracker.procs[0][1].timestamp = tracker.procs[0][0].timestamp - Duration::from_secs(2);
+        let mut powers = HashMap::new();
+        powers.insert(
+            proc.pid,
+            Record::new(
+                tracker.procs[0][0].timestamp,
+                String::from("1500000"),
+                crate::sensors::units::Unit::MicroWatt,
+            ),
+        );
+        tracker.add_energy(&powers);
+        tracker.add_energy(&powers);
+        let key = (proc.pid, proc.stat.starttime);
+        assert_eq!(tracker.processes_energy[&key].energy.microjoules, 6000000.0);
+        assert!(tracker.processes_energy[&key].energy.alive);
+
+        // the final value of a terminated process is kept until exported
+        tracker.procs.clear();
+        tracker.add_energy(&powers);
+        assert!(!tracker.processes_energy[&key].energy.alive);
+        assert_eq!(tracker.processes_energy[&key].energy.microjoules, 6000000.0);
+        tracker.drop_exported_energy_counters();
+        assert!(tracker.processes_energy.is_empty());
+
+        // counters that are never exported are dropped after the retention
+        tracker.add_process_record(proc.clone()).unwrap();
+        tracker.add_process_record(proc).unwrap();
+        tracker.procs[0][1].timestamp = tracker.procs[0][0].timestamp - Duration::from_secs(2);
+        let now = current_system_time_since_epoch();
+        tracker.add_energy(&powers);
+        tracker.procs.clear();
+        tracker.add_energy(&powers);
+        tracker.prune_energy_counters(now + DEFAULT_ENERGY_COUNTERS_RETENTION);
+        assert!(!tracker.processes_energy[&key].energy.alive);
+        tracker.prune_energy_counters(now + DEFAULT_ENERGY_COUNTERS_RETENTION * 2);
+        assert!(tracker.processes_energy.is_empty());
+    }
+
+    #[test]
+    fn process_trees_are_rolled_up() {
+        let proc = Process::myself().unwrap();
+        let parent = Process::new(proc.stat.ppid).unwrap();
+        let mut tracker = ProcessTracker::new(3);
+        tracker.add_process_record(proc.clone()).unwrap();
+        tracker.add_process_record(parent.clone()).unwrap();
+        assert!(tracker.get_tree_roots().is_empty());
+
+        // by default, processes are rolled up to their process group
+        tracker.tree_roots = Some(TreeRoots::new(&[]));
+        let roots = tracker.get_tree_roots();
+        if proc.stat.pgrp == parent.stat.pgrp && parent.pid > 2 {
+            assert_eq!(roots.get(&proc.pid), Some(&parent.pid));
+        } else {
+            assert_eq!(roots.get(&proc.pid), Some(&proc.pid));
+        }
+
+        tracker.tree_roots = Some(TreeRoots::new(&[&parent.pid.to_string()]));
+        let roots = tracker.get_tree_roots();
+        assert_eq!(roots.get(&proc.pid), Some(&parent.pid));
+        assert_eq!(roots.get(&parent.pid), Some(&parent.pid));
+
+        tracker.tree_roots = Some(TreeRoots::new(&[&proc.stat.comm]));
+        let roots = tracker.get_tree_roots();
+        assert_eq!(roots.get(&proc.pid), Some(&proc.pid));
+        assert_eq!(roots.get(&parent.pid), None);
+        assert_eq!(tracker.get_children()[&parent.pid], vec![proc.pid]);
+    }
+
+    #[test]
+    fn systemd_units_parsed_from_cgroups() {
+        let unit = SystemdUnit::from_cgroup_path("/system.slice/ssh.service").unwrap();
+        assert_eq!(unit.name, "ssh.service");
+        assert_eq!(unit.slice, "/system.slice");
+        let unit = SystemdUnit::from_cgroup_path(
+            "/user.slice/user-1000.slice/user@1000.service/app.slice/vte-spawn-1.scope",
+        )
+        .unwrap();
+        assert_eq!(unit.name, "vte-spawn-1.scope");
+        assert_eq!(
+            unit.slices,
+            vec![
+                "/user.slice",
+                "/user.slice/user-1000.slice",
+                "/user.slice/user-1000.slice/user@1000.service/app.slice"
+            ]
+        );
+        let unit = SystemdUnit::from_cgroup_path("/init.scope").unwrap();
+        assert_eq!((unit.slice.as_str(), unit.slices.len()), ("/", 0));
+        assert_eq!(SystemdUnit::from_cgroup_path("/"), None);
+    }
+
+    #[test]
+    fn usernames_read_from_passwd() {
+        let root = TempDir::new("passwd");
+        let path = root.join("passwd");
+        fs::write(
+            &path,
+            "root:x:0:0:root:/root:/bin/bash\n\
+             alice:x:1000:1000:Alice,,,:/home/alice:/bin/bash\n\
+             # comment\n\
+             +:::::: \n",
+        )
+        .unwrap();
+        let usernames = read_usernames(path.to_str().unwrap());
+        assert_eq!(usernames.len(), 2);
+        assert_eq!(usernames[&1000], "alice");
+
+        let mut tracker = ProcessTracker::new(3);
+        tracker.users = true;
+        let proc = Process::myself().unwrap();
+        let uid = tracker.get_process_uid(&proc).unwrap();
+        assert_eq!(uid, unsafe { libc::getuid() });
+        assert!(!tracker.get_username(uid).is_empty());
+    }
+
+    #[test]
+    fn threads_read_from_procfs() {
+        let root = TempDir::new("threads");
+        for (tid, comm, utime, processor) in [(42, "main", 120, 3), (43, "worker 1", 80, 0)] {
+            let task = root.join("42/task").join(tid.to_string());
+            fs::create_dir_all(&task).unwrap();
+            fs::write(
+                task.join("stat"),
+                format!(
+                    "{} ({}) S 1 42 42 0 -1 4194304 100 0 0 0 {} 7 0 0 20 0 2 0 100 1000000 100 \
+                     18446744073709551615 1 1 0 0 0 0 0 0 0 0 0 0 17 {} 0 0 0 0 0 0 0 0 0 0 0 0 0\n",
+                    tid, comm, utime, processor
+                ),
+            )
+            .unwrap();
+        }
+        let threads = read_threads(root.to_str().unwrap(), 42);
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[1].comm, "worker 1");
+        assert_eq!(threads[1].processor, Some(0));
+        assert_eq!(threads[0].total_time_jiffies(), 127);
+        assert!(read_threads("/nonexistent", 42).is_empty());
+    }
 }
 
 //  Copyright 2020 The scaphandre authors.